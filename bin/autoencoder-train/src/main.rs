@@ -0,0 +1,439 @@
+use anyhow::{bail, Context};
+use autoencoder::{
+    latent::{encode_images, reconstruction_errors},
+    AutoEncoder, AutoencoderConfig, AutoencoderProps,
+};
+use clap::{ArgEnum, Args, Parser, Subcommand};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tch::{
+    nn::{self, OptimizerConfig},
+    vision::image,
+    Device, Kind, Tensor,
+};
+use tch_utils::{
+    config::{build_model, load_config, load_model, save_config},
+    data::Datafolder,
+    init::Initialization,
+    tensor::{image_grid, interpolate2d},
+};
+use unet::encoder::{BasicCNN, BasicCNNConfig};
+
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+enum DatasetParam {
+    /// MNIST files in the dataset folder
+    Mnist,
+    /// Images of a folder of the dataset (see `--image-folder`)
+    Folder,
+}
+
+#[derive(Debug, Args)]
+struct DataArgs {
+    /// Path to the dataset
+    dataset_path: PathBuf,
+
+    #[clap(long, arg_enum, default_value_t = DatasetParam::Mnist)]
+    dataset: DatasetParam,
+
+    /// Folder of the images in the dataset path for the folder dataset
+    #[clap(long, default_value = "images")]
+    image_folder: String,
+
+    /// Uses the test set of MNIST instead of the training set
+    #[clap(long)]
+    test: bool,
+}
+
+#[derive(Debug, Parser)]
+#[clap(version, author, about)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Trains an autoencoder (or a VAE) on the images of the dataset
+    Train {
+        #[clap(flatten)]
+        data: DataArgs,
+
+        /// Path to the save location for the weight of the model, the config is saved next to them
+        #[clap(long)]
+        weight_path: Option<PathBuf>,
+
+        /// Trains a variational autoencoder
+        #[clap(long)]
+        variational: bool,
+
+        /// Weight of the KL divergence of the VAE
+        #[clap(long, default_value_t = 1.0)]
+        beta: f64,
+
+        /// Size of the latent vectors
+        #[clap(long, default_value_t = 16)]
+        latent_dim: i64,
+
+        /// Number of levels of the encoder and the decoder (1 to 4)
+        #[clap(long, default_value_t = 2)]
+        depth: usize,
+
+        /// Number of channels of the first level of the encoder
+        #[clap(long, default_value_t = 16)]
+        base_width: i64,
+
+        /// Size the images are resized to (a multiple of 2^depth)
+        #[clap(long, default_value_t = 32)]
+        size: i64,
+
+        #[clap(long, default_value_t = 64)]
+        batch_size: i64,
+
+        #[clap(long, default_value_t = 20)]
+        epochs: u32,
+
+        #[clap(long, default_value_t = 1e-3)]
+        lr: f64,
+
+        /// Seed of the initialization and of the shuffling
+        #[clap(long)]
+        seed: Option<i64>,
+    },
+    /// Writes the latent vector and the reconstruction error (anomaly score) of each image as csv
+    Encode {
+        /// Weights saved by the train command
+        weights: PathBuf,
+
+        #[clap(flatten)]
+        data: DataArgs,
+
+        #[clap(long, default_value = "latents.csv")]
+        output: PathBuf,
+
+        #[clap(long, default_value_t = 64)]
+        batch_size: i64,
+    },
+    /// Decodes the latent vectors of a csv written by the encode command into images
+    Decode {
+        weights: PathBuf,
+
+        latents: PathBuf,
+
+        /// Folder the images are written to
+        #[clap(long, default_value = "decoded")]
+        output_dir: PathBuf,
+    },
+    /// Decodes latent vectors drawn from the prior into a grid of images (meant for a VAE)
+    Sample {
+        weights: PathBuf,
+
+        #[clap(long, default_value_t = 64)]
+        count: i64,
+
+        #[clap(long, default_value = "samples.png")]
+        output: PathBuf,
+
+        #[clap(long)]
+        seed: Option<i64>,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    // Picking the device to use to the train of the model
+    let device = if tch::Cuda::is_available() {
+        let device_count = tch::Cuda::device_count() as f32;
+        let r: f32 = rand::random();
+        Device::Cuda(f32::floor(device_count * r) as usize)
+    } else {
+        Device::Cpu
+    };
+
+    match cli.command {
+        Command::Train {
+            data,
+            weight_path,
+            variational,
+            beta,
+            latent_dim,
+            depth,
+            base_width,
+            size,
+            batch_size,
+            epochs,
+            lr,
+            seed,
+        } => {
+            if let Some(seed) = seed {
+                tch::manual_seed(seed);
+            }
+            let (_, images) = load_images(&data, size)?;
+            let config = AutoencoderConfig {
+                encoder: BasicCNNConfig::new(images.size()[1] as u32)
+                    .base_width(base_width)
+                    .conv_config(nn::ConvConfig {
+                        padding: 1,
+                        ..Default::default()
+                    }),
+                depth,
+                props: AutoencoderProps {
+                    image_size: size,
+                    out_channels: images.size()[1],
+                    latent_dim,
+                    variational,
+                    ..Default::default()
+                },
+                init: Initialization {
+                    seed,
+                    ..Default::default()
+                },
+            };
+            let training = Training {
+                images,
+                batch_size,
+                epochs,
+                lr,
+                beta,
+                device,
+            };
+            let vs = match depth {
+                1 => train::<1>(&config, &training)?,
+                2 => train::<2>(&config, &training)?,
+                3 => train::<3>(&config, &training)?,
+                4 => train::<4>(&config, &training)?,
+                depth => bail!("Unsupported depth {depth}"),
+            };
+            if let Some(weight_path) = weight_path {
+                vs.save(&weight_path)?;
+                save_config(&config, &weight_path)?;
+            }
+        }
+        Command::Encode {
+            weights,
+            data,
+            output,
+            batch_size,
+        } => {
+            let config: AutoencoderConfig = load_config(&weights)?;
+            let (names, images) = load_images(&data, config.props.image_size)?;
+            let csv = match config.depth {
+                1 => encode::<1>(&weights, &names, &images, batch_size, device)?,
+                2 => encode::<2>(&weights, &names, &images, batch_size, device)?,
+                3 => encode::<3>(&weights, &names, &images, batch_size, device)?,
+                4 => encode::<4>(&weights, &names, &images, batch_size, device)?,
+                depth => bail!("Unsupported depth {depth}"),
+            };
+            fs::write(&output, csv).with_context(|| format!("Couldnt write {output:?}"))?;
+        }
+        Command::Decode {
+            weights,
+            latents,
+            output_dir,
+        } => {
+            let config: AutoencoderConfig = load_config(&weights)?;
+            let (names, z) = read_latents(&latents)?;
+            let images = match config.depth {
+                1 => load::<1>(&weights, device)?.decode(&z.to_device(device)),
+                2 => load::<2>(&weights, device)?.decode(&z.to_device(device)),
+                3 => load::<3>(&weights, device)?.decode(&z.to_device(device)),
+                4 => load::<4>(&weights, device)?.decode(&z.to_device(device)),
+                depth => bail!("Unsupported depth {depth}"),
+            };
+            fs::create_dir_all(&output_dir)?;
+            for (i, name) in names.iter().enumerate() {
+                save_image(
+                    &images.get(i as i64),
+                    &output_dir.join(format!("{name}.png")),
+                )?;
+            }
+        }
+        Command::Sample {
+            weights,
+            count,
+            output,
+            seed,
+        } => {
+            let config: AutoencoderConfig = load_config(&weights)?;
+            if !config.props.variational {
+                println!("The model is not variational : the samples may not look like the images");
+            }
+            if let Some(seed) = seed {
+                tch::manual_seed(seed);
+            }
+            let samples = match config.depth {
+                1 => load::<1>(&weights, device)?.sample(count, device),
+                2 => load::<2>(&weights, device)?.sample(count, device),
+                3 => load::<3>(&weights, device)?.sample(count, device),
+                4 => load::<4>(&weights, device)?.sample(count, device),
+                depth => bail!("Unsupported depth {depth}"),
+            };
+            let columns = (count as f64).sqrt().ceil() as i64;
+            save_image(&image_grid(&samples, columns, 2), &output)?;
+        }
+    }
+    Ok(())
+}
+
+/// Images of the dataset `[N,C,size,size]` in `[0, 1]` with their names (index or file stem)
+fn load_images(data: &DataArgs, size: i64) -> anyhow::Result<(Vec<String>, Tensor)> {
+    match data.dataset {
+        DatasetParam::Mnist => {
+            let m = tch::vision::mnist::load_dir(&data.dataset_path)?;
+            let images = if data.test {
+                m.test_images
+            } else {
+                m.train_images
+            };
+            let images = interpolate2d(&images.view([-1, 1, 28, 28]), &[size, size], true);
+            let names = (0..images.size()[0]).map(|i| i.to_string()).collect();
+            Ok((names, images))
+        }
+        DatasetParam::Folder => {
+            // The folder is given as both the input and the target of the dataset, the target is ignored
+            let load = |path: PathBuf| {
+                let name = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let xs = image::load(&path)
+                    .and_then(|xs| image::resize(&xs, size, size))
+                    .with_context(|| format!("Couldnt load {path:?}"))?;
+                Ok((name, xs.to_kind(Kind::Float) / 255.0))
+            };
+            let (names, images): (Vec<_>, Vec<_>) = Datafolder::from(
+                &data.dataset_path,
+                data.image_folder.clone(),
+                data.image_folder.clone(),
+                &load,
+                &|_| (),
+            )?
+            .map(|(x, _)| x)
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+            if images.is_empty() {
+                bail!(
+                    "No images in {:?}",
+                    data.dataset_path.join(&data.image_folder)
+                );
+            }
+            Ok((names, Tensor::stack(&images, 0)))
+        }
+    }
+}
+
+struct Training {
+    images: Tensor,
+    batch_size: i64,
+    epochs: u32,
+    lr: f64,
+    beta: f64,
+    device: Device,
+}
+
+fn train<const L: usize>(
+    config: &AutoencoderConfig,
+    training: &Training,
+) -> anyhow::Result<nn::VarStore> {
+    let vs = nn::VarStore::new(training.device);
+    let model = build_model::<AutoEncoder<BasicCNN<L>, L>>(&vs, config)?;
+    let mut opt = nn::Adam::default().build(&vs, training.lr)?;
+
+    let count = training.images.size()[0];
+    for epoch in 1..=training.epochs {
+        let mut steps = 0;
+        let mut avg_loss = 0.0;
+        let permutation = Tensor::randperm(count, (Kind::Int64, Device::Cpu));
+        for indices in permutation.split(training.batch_size, 0) {
+            let xs = training
+                .images
+                .index_select(0, &indices)
+                .to_device(training.device);
+            let output = model.forward_latent_t(&xs, true);
+            let loss = output.loss(&xs, training.beta);
+
+            // Gradient descent
+            opt.backward_step(&loss);
+
+            steps += 1;
+            avg_loss += (f64::from(&loss) - avg_loss) / steps as f64;
+        }
+        println!("epoch: {:4} train loss: {:10.5}", epoch, avg_loss);
+    }
+    Ok(vs)
+}
+
+fn load<const L: usize>(
+    weights: &Path,
+    device: Device,
+) -> anyhow::Result<AutoEncoder<BasicCNN<L>, L>> {
+    let (_, model) = load_model::<AutoEncoder<BasicCNN<L>, L>>(weights, device)?;
+    Ok(model)
+}
+
+/// Csv with the name, the reconstruction error and the latent vector of each image
+fn encode<const L: usize>(
+    weights: &Path,
+    names: &[String],
+    images: &Tensor,
+    batch_size: i64,
+    device: Device,
+) -> anyhow::Result<String> {
+    let model = load::<L>(weights, device)?;
+    let z = encode_images(&model, images, batch_size, device);
+    let errors = reconstruction_errors(&model, images, batch_size, device);
+
+    let latent_dim = model.latent_dim();
+    let mut csv = String::from("name,error");
+    for i in 0..latent_dim {
+        csv += &format!(",z{i}");
+    }
+    csv += "\n";
+    // The tensors are copied once instead of reading each value from the device
+    let errors = Vec::<f32>::from(&errors);
+    let z = Vec::<f32>::from(&z);
+    for ((name, error), z) in names.iter().zip(errors).zip(z.chunks(latent_dim as usize)) {
+        csv += &format!("{name},{error}");
+        for z in z {
+            csv += &format!(",{z}");
+        }
+        csv += "\n";
+    }
+    Ok(csv)
+}
+
+/// Reads the names and the latent vectors `[N, latent_dim]` of a csv written by [`encode`]
+fn read_latents(path: &Path) -> anyhow::Result<(Vec<String>, Tensor)> {
+    let content = fs::read_to_string(path).with_context(|| format!("Couldnt read {path:?}"))?;
+    let mut names = vec![];
+    let mut rows = vec![];
+    for (i, line) in content.lines().enumerate().skip(1) {
+        let mut columns = line.split(',');
+        let name = columns.next().unwrap_or_default().to_string();
+        let z = columns
+            .skip(1)
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid latent vector on line {}", i + 1))?;
+        names.push(name);
+        rows.push(Tensor::of_slice(&z));
+    }
+    if rows.is_empty() {
+        bail!("No latent vectors in {path:?}");
+    }
+    Ok((names, Tensor::stack(&rows, 0)))
+}
+
+/// Writes a `[C,H,W]` image in `[0, 1]`
+fn save_image(xs: &Tensor, path: &Path) -> anyhow::Result<()> {
+    let xs = if xs.size()[0] == 1 {
+        xs.repeat(&[3, 1, 1])
+    } else {
+        xs.shallow_clone()
+    };
+    let xs = (xs.clamp(0.0, 1.0) * 255.0).to_kind(Kind::Uint8);
+    image::save(&xs, path).with_context(|| format!("Couldnt write {path:?}"))
+}
@@ -0,0 +1,299 @@
+use anyhow::{bail, Context};
+use clap::{ArgEnum, Parser, Subcommand};
+use cnn::{Cnn, CnnConfig};
+use mlp::{MlpConfig, MLP};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tch::{Device, Kind, Tensor};
+use tch_utils::{
+    checkpoint::{self, CheckpointDiff},
+    config::{config_path, load_config, load_model},
+    import::NameMapping,
+    safetensors::{self, Metadata},
+    summary::Summary,
+};
+use unet::{load_unet, UnetConfig};
+use vit::{ViT, VitConfig};
+
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+enum ModelParam {
+    Mlp,
+    /// LeNet or ResNet classifier of the cnn crate
+    Cnn,
+    Unet,
+    /// Vision transformer classifier of the vit crate
+    Vit,
+}
+
+#[derive(Debug, Parser)]
+#[clap(version, author, about)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Prints the layers of a checkpoint with their parameters, output shapes and estimated cost
+    Summary {
+        /// Weights of the model (`.pt` or `.safetensors` without `--model`)
+        weights: PathBuf,
+
+        /// Architecture of the model, rebuilt from the config saved next to the weights.
+        /// Without it only the tensors of the checkpoint are listed.
+        #[clap(long, arg_enum)]
+        model: Option<ModelParam>,
+
+        /// Shape of the sample input, e.g. `1,3,256,256` (one sample of the size expected by the model by default)
+        #[clap(long, use_value_delimiter = true)]
+        input: Option<Vec<i64>>,
+    },
+    /// Converts a checkpoint between the `.pt` and `.safetensors` formats (chosen by the extension of the output).
+    /// The config saved next to a `.pt` goes in the safetensors header and the other way around.
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+
+        /// Commit of the code that trained the model, stored in the safetensors header
+        #[clap(long)]
+        commit: Option<String>,
+
+        /// Training data (file or folder) whose hash is stored in the safetensors header
+        #[clap(long)]
+        dataset: Option<PathBuf>,
+    },
+    /// Lists the tensors of a checkpoint with their shape, kind and statistics
+    List {
+        /// Weights (`.pt` or `.safetensors`)
+        weights: PathBuf,
+    },
+    /// Compares the tensors of two checkpoints : L2 norm of the differences, shape mismatches and missing keys.
+    /// Exits with an error if they differ.
+    Diff {
+        first: PathBuf,
+        second: PathBuf,
+
+        /// Differences below this L2 norm are ignored
+        #[clap(long, default_value_t = 0.0)]
+        tolerance: f64,
+    },
+    /// Renames the prefixes of the tensors of a checkpoint, e.g. `--rule =model` moves everything under `model`
+    /// and `--rule layer0=model.layer0` moves only `layer0`
+    Rename {
+        input: PathBuf,
+        output: PathBuf,
+
+        /// Rules `<from>=<to>` replacing the prefix `from` by `to`, an empty `from` matches every tensor
+        #[clap(long = "rule")]
+        rules: Vec<String>,
+
+        /// File with one `<from> <to>` rule per line
+        #[clap(long)]
+        mapping: Option<PathBuf>,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    match args.command {
+        Command::Summary {
+            weights,
+            model,
+            input,
+        } => summary(&weights, model, input),
+        Command::Convert {
+            input,
+            output,
+            commit,
+            dataset,
+        } => convert(&input, &output, commit, dataset),
+        Command::List { weights } => list(&weights),
+        Command::Diff {
+            first,
+            second,
+            tolerance,
+        } => diff(&first, &second, tolerance),
+        Command::Rename {
+            input,
+            output,
+            rules,
+            mapping,
+        } => rename(&input, &output, &rules, mapping),
+    }
+}
+
+/// Tensors of a checkpoint in either format
+fn load_tensors(path: &Path) -> anyhow::Result<(Vec<(String, Tensor)>, Metadata)> {
+    if safetensors::is_safetensors(path) {
+        safetensors::load_tensors(path)
+    } else {
+        Ok((Tensor::load_multi(path)?, Metadata::new()))
+    }
+}
+
+fn save_tensors(
+    path: &Path,
+    tensors: &[(String, Tensor)],
+    metadata: &Metadata,
+) -> anyhow::Result<()> {
+    if safetensors::is_safetensors(path) {
+        safetensors::save_tensors(path, tensors, metadata)
+    } else {
+        Ok(Tensor::save_multi(tensors, path)?)
+    }
+}
+
+fn list(weights: &Path) -> anyhow::Result<()> {
+    let (tensors, metadata) = load_tensors(weights)?;
+    let stats = checkpoint::stats(&tensors);
+    let width = stats
+        .iter()
+        .map(|stats| stats.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(6);
+    println!(
+        "{:width$}  {:>20}  {:>8}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "tensor", "shape", "kind", "norm", "mean", "std", "min", "max"
+    );
+    for stats in stats.iter() {
+        println!(
+            "{:width$}  {:>20}  {:>8}  {:>10.4}  {:>10.4}  {:>10.4}  {:>10.4}  {:>10.4}",
+            stats.name,
+            format!("{:?}", stats.shape),
+            format!("{:?}", stats.kind),
+            stats.norm,
+            stats.mean,
+            stats.std,
+            stats.min,
+            stats.max
+        );
+    }
+    let params: i64 = stats
+        .iter()
+        .map(|stats| stats.shape.iter().product::<i64>())
+        .sum();
+    println!("{} tensors, {params} parameters", stats.len());
+    for (key, value) in metadata.entries.iter() {
+        println!("{key}: {value}");
+    }
+    Ok(())
+}
+
+fn diff(first: &Path, second: &Path, tolerance: f64) -> anyhow::Result<()> {
+    let (first, _) = load_tensors(first)?;
+    let (second, _) = load_tensors(second)?;
+    let diff = CheckpointDiff::new(&first, &second);
+    print!("{diff}");
+    if !diff.is_same(tolerance) {
+        bail!("The checkpoints differ");
+    }
+    Ok(())
+}
+
+fn rename(
+    input: &Path,
+    output: &Path,
+    rules: &[String],
+    mapping: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let mut name_mapping = match mapping {
+        Some(path) => NameMapping::from_file(&path)?,
+        None => NameMapping::new(),
+    };
+    for rule in rules {
+        let (from, to) = rule
+            .split_once('=')
+            .with_context(|| format!("Invalid rule {rule} : expected `<from>=<to>`"))?;
+        name_mapping = name_mapping.rule(from, to);
+    }
+
+    let (tensors, mut metadata) = load_tensors(input)?;
+    let renamed = checkpoint::rename(tensors, &name_mapping);
+    // The architecture config doesn't match the new paths anymore
+    metadata.entries.remove("config");
+    save_tensors(output, &renamed, &metadata)?;
+    for (name, _) in renamed.iter() {
+        println!("{name}");
+    }
+    Ok(())
+}
+
+fn convert(
+    input: &Path,
+    output: &Path,
+    commit: Option<String>,
+    dataset: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let (tensors, mut metadata) = load_tensors(input)?;
+    if config_path(input).exists() {
+        let config = fs::read_to_string(config_path(input))?;
+        metadata = metadata.insert("config", &config);
+    }
+    if let Some(commit) = commit {
+        metadata = metadata.commit(&commit);
+    }
+    if let Some(dataset) = dataset {
+        metadata = metadata.dataset_hash(&safetensors::dataset_hash(&dataset)?);
+    }
+
+    save_tensors(output, &tensors, &metadata)?;
+    if let (false, Some(config)) = (safetensors::is_safetensors(output), metadata.get("config")) {
+        fs::write(config_path(output), config)?;
+    }
+    println!(
+        "converted {} tensors from {input:?} to {output:?}",
+        tensors.len()
+    );
+    for (key, value) in metadata.entries.iter().filter(|(key, _)| *key != "config") {
+        println!("{key}: {value}");
+    }
+    Ok(())
+}
+
+fn summary(
+    weights: &Path,
+    model: Option<ModelParam>,
+    input: Option<Vec<i64>>,
+) -> anyhow::Result<()> {
+    // The default shape is used unless one is given
+    let sample = |shape: Vec<i64>| {
+        Tensor::zeros(
+            input.as_deref().unwrap_or(&shape),
+            (Kind::Float, Device::Cpu),
+        )
+    };
+    let summary = match model {
+        None => Summary::of_tensors(&load_tensors(weights)?.0),
+        Some(ModelParam::Mlp) => {
+            let config: MlpConfig = load_config(weights)?;
+            let (vs, mlp) = load_model::<MLP>(weights, Device::Cpu)?;
+            Summary::new(&vs, &mlp, &sample(vec![1, config.in_features]))
+        }
+        Some(ModelParam::Cnn) => {
+            let config: CnnConfig = load_config(weights)?;
+            let (in_channels, size) = match &config {
+                CnnConfig::LeNet(config) => (config.in_channels, config.image_size),
+                CnnConfig::ResNet(config) => (config.in_channels, config.image_size),
+            };
+            let (vs, cnn) = load_model::<Cnn>(weights, Device::Cpu)?;
+            Summary::new(&vs, &cnn, &sample(vec![1, in_channels, size, size]))
+        }
+        Some(ModelParam::Vit) => {
+            let config: VitConfig = load_config(weights)?;
+            let (vs, vit) = load_model::<ViT>(weights, Device::Cpu)?;
+            let size = config.image_size;
+            Summary::new(&vs, &vit, &sample(vec![1, config.in_channels, size, size]))
+        }
+        Some(ModelParam::Unet) => {
+            let config: UnetConfig = load_config(weights)?;
+            let (vs, unet) = load_unet(weights, Device::Cpu)?;
+            let input = sample(vec![1, config.encoder.in_channels, 256, 256]);
+            Summary::new(&vs, &unet, &input)
+        }
+    };
+    print!("{summary}");
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use gan::{discriminator_loss, fixed_latents, generator_loss, Discriminator, GanConfig, Generator};
+use std::{fs, path::PathBuf};
+use tch::{
+    nn::{self, ModuleT, OptimizerConfig},
+    vision::image,
+    Device, Kind, Tensor,
+};
+use tch_utils::{
+    config::{build_model, save_config},
+    tensor::image_grid,
+};
+
+#[derive(Debug, Parser)]
+#[clap(version, author, about)]
+struct Args {
+    /// Path to the MNIST dataset
+    mnist_path: PathBuf,
+
+    /// Folder the sample grids (`epoch{i}.png`) and the weights are written to
+    #[clap(long, default_value = "gan")]
+    output_dir: PathBuf,
+
+    #[clap(long, default_value_t = 10)]
+    epochs: u32,
+
+    #[clap(long, default_value_t = 64)]
+    batch_size: i64,
+
+    /// Size of the latent vectors
+    #[clap(long, default_value_t = 64)]
+    latent_dim: i64,
+
+    /// Chanels of the convolutions of the discriminator (mirrored by the generator), e.g. `--widths 64,128`
+    #[clap(long, use_value_delimiter = true, default_value = "64,128")]
+    widths: Vec<i64>,
+
+    /// Learning rate of both optimizers
+    #[clap(long, default_value_t = 2e-4)]
+    lr: f64,
+
+    /// Steps of the discriminator for each step of the generator
+    #[clap(long, default_value_t = 1)]
+    discriminator_steps: u32,
+
+    /// Number of images of the sample grids
+    #[clap(long, default_value_t = 64)]
+    samples: i64,
+
+    /// Seed of the latent vectors of the sample grids, the same seed gives the same vectors across runs
+    #[clap(long, default_value_t = 0)]
+    sample_seed: u64,
+
+    /// Seed of the initialization and of the shuffling
+    #[clap(long)]
+    seed: Option<i64>,
+
+    /// Trains on the cpu even if a gpu is available
+    #[clap(long)]
+    cpu: bool,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Loading Dataset, the images are scaled to [-1, 1] like the outputs of the generator
+    let m = tch::vision::mnist::load_dir(&args.mnist_path)?;
+    let images = m.train_images.view([-1, 1, 28, 28]) * 2.0 - 1.0;
+
+    // Picking the device to use to the train of the model
+    let device = if tch::Cuda::is_available() && !args.cpu {
+        let device_count = tch::Cuda::device_count() as f32;
+        let r: f32 = rand::random();
+        Device::Cuda(f32::floor(device_count * r) as usize)
+    } else {
+        Device::Cpu
+    };
+
+    // The latent vectors of the grids are drawn from their own seed
+    let sample_z = fixed_latents(args.samples, args.latent_dim, args.sample_seed).to_device(device);
+    match args.seed {
+        Some(seed) => tch::manual_seed(seed),
+        None => tch::manual_seed(rand::random()),
+    }
+
+    // The generator and the discriminator have their own store so that each optimizer only updates its model
+    let mut config = GanConfig::new(1, 28)
+        .latent_dim(args.latent_dim)
+        .widths(args.widths.clone());
+    config.init.seed = args.seed;
+    let g_vs = nn::VarStore::new(device);
+    let generator = build_model::<Generator>(&g_vs, &config)?;
+    let d_vs = nn::VarStore::new(device);
+    let discriminator = build_model::<Discriminator>(&d_vs, &config)?;
+
+    // Adam with beta1 = 0.5 as in the DCGAN paper
+    let adam = nn::Adam {
+        beta1: 0.5,
+        ..Default::default()
+    };
+    let mut g_opt = adam.build(&g_vs, args.lr)?;
+    let mut d_opt = adam.build(&d_vs, args.lr)?;
+
+    fs::create_dir_all(&args.output_dir)
+        .with_context(|| format!("Couldnt create {:?}", args.output_dir))?;
+
+    let count = images.size()[0];
+    for epoch in 1..=args.epochs {
+        let mut steps = 0;
+        let (mut d_avg, mut g_avg) = (0.0, 0.0);
+        let permutation = Tensor::randperm(count, (Kind::Int64, Device::Cpu));
+        for indices in permutation.split(args.batch_size, 0) {
+            let real = images.index_select(0, &indices).to_device(device);
+            let batch_size = real.size()[0];
+
+            // Discriminator steps, the generated images are detached so that the generator is left untouched
+            let mut d_loss = Tensor::from(0.0);
+            for _ in 0..args.discriminator_steps {
+                let z = Tensor::randn(&[batch_size, args.latent_dim], (Kind::Float, device));
+                let fake = generator.forward_t(&z, true).detach();
+                d_loss = discriminator_loss(
+                    &discriminator.forward_t(&real, true),
+                    &discriminator.forward_t(&fake, true),
+                );
+                d_opt.backward_step(&d_loss);
+            }
+
+            // Generator step, the gradients reaching the discriminator are cleared by its next step
+            let z = Tensor::randn(&[batch_size, args.latent_dim], (Kind::Float, device));
+            let g_loss =
+                generator_loss(&discriminator.forward_t(&generator.forward_t(&z, true), true));
+            g_opt.backward_step(&g_loss);
+
+            steps += 1;
+            d_avg += (f64::from(&d_loss) - d_avg) / steps as f64;
+            g_avg += (f64::from(&g_loss) - g_avg) / steps as f64;
+        }
+        println!(
+            "epoch: {:4} discriminator loss: {:8.5} generator loss: {:8.5}",
+            epoch, d_avg, g_avg
+        );
+
+        // Sample grid of the epoch, generated from the same latent vectors every time
+        let samples = tch::no_grad(|| generator.forward_t(&sample_z, false));
+        let samples = ((samples + 1.0) / 2.0)
+            .clamp(0.0, 1.0)
+            .to_device(Device::Cpu);
+        let columns = (args.samples as f64).sqrt().ceil() as i64;
+        let grid =
+            (image_grid(&samples, columns, 2).repeat(&[3, 1, 1]) * 255.0).to_kind(Kind::Uint8);
+        let path = args.output_dir.join(format!("epoch{epoch:03}.png"));
+        image::save(&grid, &path).with_context(|| format!("Couldnt write {path:?}"))?;
+    }
+
+    // The config is saved next to the weights of the generator to rebuild it
+    let generator_path = args.output_dir.join("generator.pt");
+    g_vs.save(&generator_path)?;
+    save_config(&config, &generator_path)?;
+    d_vs.save(args.output_dir.join("discriminator.pt"))?;
+
+    Ok(())
+}
@@ -1,11 +1,21 @@
 use async_channel::{unbounded, Receiver};
 use bytes::Bytes;
 use clap::Parser;
+use cnn::{Cnn, CnnConfig};
+use mlp::{MlpConfig, MLP};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
 use tch::{
-    nn::{self, Module},
+    nn::{self, ModuleT},
     IndexOp, Kind, Tensor,
 };
+use tch_utils::{
+    config::{config_path, load_config, FromConfig},
+    safetensors,
+    tta::{Merge, TestTimeAugmentation, TtaParam},
+};
 use tokio::sync::oneshot::{channel, Sender};
+use vit::{ViT, VitConfig};
 use warp::{hyper::StatusCode, path, reply::with_status, Filter, Rejection, Reply};
 
 #[derive(Debug, Parser)]
@@ -17,6 +27,75 @@ struct Args {
     /// Port to listen on
     #[clap(short, long, default_value_t = 3030)]
     port: u16,
+    /// Test-time augmentation applied to the images
+    #[clap(long, arg_enum, default_value_t = TtaParam::None)]
+    tta: TtaParam,
+    /// Merging of the predictions of the augmented images
+    #[clap(long, arg_enum, default_value_t = Merge::Mean)]
+    merge: Merge,
+    /// Weights saved by mnist-train, the architecture is read from the config saved next to them (or in the safetensors header)
+    #[clap(long, default_value = "weights.pt")]
+    weights: PathBuf,
+}
+
+/// Config saved next to the weights by mnist-train
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ModelConfig {
+    Mlp(MlpConfig),
+    Cnn(CnnConfig),
+    Vit(VitConfig),
+}
+
+/// The augmentations are applied on the 28x28 images while the MLP expects flattened ones
+#[derive(Debug)]
+enum ImageClassifier {
+    Mlp(MLP),
+    Cnn(Cnn),
+    Vit(Box<ViT>),
+}
+
+impl ImageClassifier {
+    fn build(vs: &nn::Path, config: &ModelConfig) -> anyhow::Result<Self> {
+        Ok(match config {
+            ModelConfig::Mlp(config) => ImageClassifier::Mlp(MLP::from_config(vs, config)?),
+            ModelConfig::Cnn(config) => ImageClassifier::Cnn(Cnn::from_config(vs, config)?),
+            ModelConfig::Vit(config) => {
+                ImageClassifier::Vit(Box::new(ViT::from_config(vs, config)?))
+            }
+        })
+    }
+
+    /// Rebuilds the model from the config saved next to the weights (or in the safetensors header),
+    /// the weights saved without config use the former architecture
+    fn load(weights: &Path) -> anyhow::Result<(nn::VarStore, Self)> {
+        let mut vs = nn::VarStore::new(tch::Device::Cpu);
+        let model = if safetensors::is_safetensors(weights) {
+            let (_, metadata) = safetensors::load_tensors(weights)?;
+            let model = Self::build(&vs.root(), &metadata.parse_config()?)?;
+            safetensors::load_var_store(&mut vs, weights)?;
+            model
+        } else {
+            let model = if config_path(weights).exists() {
+                Self::build(&vs.root(), &load_config(weights)?)?
+            } else {
+                ImageClassifier::Mlp(MLP::new(&vs.root(), 784, 10, 128, 2))
+            };
+            vs.load(weights)?;
+            model
+        };
+        Ok((vs, model))
+    }
+}
+
+impl ModuleT for ImageClassifier {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        match self {
+            ImageClassifier::Mlp(mlp) => mlp.forward_t(&xs.reshape(&[-1, 784]), train),
+            ImageClassifier::Cnn(cnn) => cnn.forward_t(&xs.reshape(&[-1, 1, 28, 28]), train),
+            ImageClassifier::Vit(vit) => vit.forward_t(&xs.reshape(&[-1, 1, 28, 28]), train),
+        }
+    }
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -27,7 +106,8 @@ async fn main() {
     let (s, r) = unbounded::<(Tensor, Sender<Vec<f64>>)>();
     for _ in 0..args.workers {
         let r = r.clone();
-        tokio::spawn(async { inference_worker(r).await });
+        let (tta, merge, weights) = (args.tta, args.merge, args.weights.clone());
+        tokio::spawn(async move { inference_worker(r, tta, merge, weights).await });
     }
 
     // Setting up a route to do the inference
@@ -44,15 +124,24 @@ async fn main() {
         .await;
 }
 
-async fn inference_worker(tasks: Receiver<(Tensor, Sender<Vec<f64>>)>) {
+async fn inference_worker(
+    tasks: Receiver<(Tensor, Sender<Vec<f64>>)>,
+    tta: TtaParam,
+    merge: Merge,
+    weights: PathBuf,
+) {
     // Setting up an instance of the model for the worker
-    let mut vs = nn::VarStore::new(tch::Device::Cpu);
-    let mlp = mlp::MLP::new(&vs.root(), 784, 10, 128, 2);
-    vs.load("weights.pt").expect("unable to load weights");
+    let (_vs, classifier) = ImageClassifier::load(&weights).expect("unable to load the model");
+    let model = TestTimeAugmentation::classification(classifier)
+        .transforms(tta.transforms())
+        .merge(merge);
 
     // Recieve a inference request, compute the result and send it back with the given one shot chanel
     while let Ok((x, s)) = tasks.recv().await {
-        let y_hat = mlp.forward(&x.reshape(&[784])).sigmoid();
+        let y_hat = model
+            .forward_t(&x.unsqueeze(0), false)
+            .squeeze_dim(0)
+            .sigmoid();
         let res = (0..=9)
             .into_iter()
             .map(|i| f64::from(y_hat.i(i)))
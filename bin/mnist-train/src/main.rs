@@ -1,10 +1,40 @@
 use anyhow::Result;
 use clap::{ArgEnum, Parser};
-use mlp::MLP;
+use cnn::{Cnn, CnnConfig, LeNetConfig, ResNetConfig};
+use mlp::{MlpConfig, MLP};
+use serde::Serialize;
+use std::path::Path;
 use tch::{
-    nn::{self, Module, OptimizerConfig},
-    Device,
+    nn::{self, ModuleT, OptimizerConfig},
+    Device, Tensor,
 };
+use tch_utils::{
+    config::{build_model, save_config},
+    init::{InitParam, Initialization},
+    layers::{Activation, Normalization},
+    safetensors::{self, Metadata},
+};
+use vit::{ViT, VitConfig};
+
+/// Config saved next to the weights, mnist-api reads either of them
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum ModelConfig {
+    Mlp(MlpConfig),
+    Cnn(CnnConfig),
+    Vit(VitConfig),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+enum ModelParam {
+    Mlp,
+    /// LeNet-5 like convolutional network
+    Lenet,
+    /// Small residual network
+    Resnet,
+    /// Vision transformer
+    Vit,
+}
 
 #[derive(Debug, Parser)]
 #[clap(version, author, about)]
@@ -12,7 +42,11 @@ struct Args {
     /// Path to the dataset
     mnist_path: String,
 
-    /// Path to the save location for the weight of the model
+    /// Architecture of the model
+    #[clap(long, arg_enum, default_value_t = ModelParam::Mlp)]
+    model: ModelParam,
+
+    /// Path to the save location for the weight of the model (`.pt` or `.safetensors`)
     #[clap(long)]
     weight_path: Option<String>,
 
@@ -31,6 +65,63 @@ struct Args {
     /// Number of hidden layers
     #[clap(long, default_value_t = 2)]
     layer_count: u32,
+
+    /// Width of each hidden layer (replaces hidden-nodes and layer-count), e.g. `--widths 256,128`.
+    /// Also sets the fully connected layers of the lenet model.
+    #[clap(long, use_value_delimiter = true)]
+    widths: Option<Vec<i64>>,
+
+    /// Activation of the hidden layers
+    #[clap(long, arg_enum, default_value_t = ActivationParam::Relu)]
+    activation: ActivationParam,
+
+    /// Dropout probability of the hidden layers
+    #[clap(long, default_value_t = 0.0)]
+    dropout: f64,
+
+    /// Normalization of the hidden layers (and of the convolutions of the lenet model)
+    #[clap(long, arg_enum, default_value_t = HiddenNormParam::None)]
+    hidden_norm: HiddenNormParam,
+
+    /// Adds residual connections between the hidden layers of the same width
+    #[clap(long)]
+    residual: bool,
+
+    /// Initialization of the weights of the linear layers
+    #[clap(long, arg_enum, default_value_t = InitParam::Default)]
+    init: InitParam,
+
+    /// Seed of the initialization
+    #[clap(long)]
+    seed: Option<i64>,
+
+    /// Zero-initializes the residual layers (and the resnet and vit blocks) so that they start as identities
+    #[clap(long)]
+    zero_residual: bool,
+
+    /// Chanels of the convolution stages of the lenet and resnet models, e.g. `--channels 6,16`
+    #[clap(long, use_value_delimiter = true)]
+    channels: Option<Vec<i64>>,
+
+    /// Number of residual blocks per stage of the resnet model, or of transformer blocks of the vit model
+    #[clap(long, default_value_t = 2)]
+    blocks: usize,
+
+    /// Size of the patches of the vit model (a divisor of 28)
+    #[clap(long, default_value_t = 4)]
+    patch_size: i64,
+
+    /// Dimention of the tokens of the vit model
+    #[clap(long, default_value_t = 64)]
+    embed_dim: i64,
+
+    /// Number of attention heads of the vit model
+    #[clap(long, default_value_t = 4)]
+    heads: i64,
+
+    /// Size of the training batches, the whole training set is used at once by default
+    #[clap(long)]
+    batch_size: Option<i64>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
@@ -39,12 +130,27 @@ enum NormalizationParam {
     Sigmoid,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+enum ActivationParam {
+    Relu,
+    Gelu,
+    Silu,
+    Tanh,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+enum HiddenNormParam {
+    None,
+    Batch,
+    Layer,
+}
+
 fn main() -> Result<()> {
     // Parsing parameters
     let args = Args::parse();
 
     // Loading Dataset
-    let m = tch::vision::mnist::load_dir(args.mnist_path)?;
+    let m = tch::vision::mnist::load_dir(&args.mnist_path)?;
 
     // Picking the device to use to the train of the model
     let device = if tch::Cuda::is_available() {
@@ -57,42 +163,141 @@ fn main() -> Result<()> {
 
     // Creating the Model and the storage for the parameters
     let vs = nn::VarStore::new(device);
-    let net = MLP::new(&vs.root(), 784, 10, args.hidden_nodes, args.layer_count);
+    let activation = match args.activation {
+        ActivationParam::Relu => Activation::ReLU,
+        ActivationParam::Gelu => Activation::GELU,
+        ActivationParam::Silu => Activation::SiLU,
+        ActivationParam::Tanh => Activation::Tanh,
+    };
+    let normalization = match args.hidden_norm {
+        HiddenNormParam::None => Normalization::None,
+        HiddenNormParam::Batch => Normalization::Batch,
+        HiddenNormParam::Layer => Normalization::Layer,
+    };
+    let init = Initialization {
+        seed: args.seed,
+        ..Initialization::new()
+            .weights("*", args.init.into())
+            .zero_residual(args.zero_residual)
+    };
+    let (net, config): (Box<dyn ModuleT>, _) = match args.model {
+        ModelParam::Mlp => {
+            let widths = args
+                .widths
+                .clone()
+                .unwrap_or_else(|| vec![args.hidden_nodes as i64; args.layer_count as usize]);
+            let config = MlpConfig::new(784, 10)
+                .hidden(widths)
+                .activation(activation)
+                .dropout(args.dropout)
+                .normalization(normalization)
+                .residual(args.residual)
+                .init(init);
+            let net = build_model::<MLP>(&vs, &config)?;
+            (Box::new(net), ModelConfig::Mlp(config))
+        }
+        ModelParam::Lenet => {
+            let mut lenet = LeNetConfig::new(1, 10, 28)
+                .activation(activation)
+                .normalization(normalization)
+                .dropout(args.dropout)
+                .init(init);
+            if let Some(channels) = args.channels.clone() {
+                lenet = lenet.channels(channels);
+            }
+            if let Some(widths) = args.widths.clone() {
+                lenet = lenet.hidden(widths);
+            }
+            let config = CnnConfig::from(lenet);
+            let net = build_model::<Cnn>(&vs, &config)?;
+            (Box::new(net), ModelConfig::Cnn(config))
+        }
+        ModelParam::Resnet => {
+            let mut resnet = ResNetConfig::new(1, 10, 28)
+                .blocks(args.blocks)
+                .activation(activation)
+                .init(init);
+            if let Some(channels) = args.channels.clone() {
+                resnet = resnet.widths(channels);
+            }
+            let config = CnnConfig::from(resnet);
+            let net = build_model::<Cnn>(&vs, &config)?;
+            (Box::new(net), ModelConfig::Cnn(config))
+        }
+        ModelParam::Vit => {
+            let config = VitConfig::new(1, 10, 28)
+                .patch_size(args.patch_size)
+                .embed_dim(args.embed_dim)
+                .depth(args.blocks)
+                .heads(args.heads)
+                .dropout(args.dropout)
+                .init(init);
+            let net = build_model::<ViT>(&vs, &config)?;
+            (Box::new(net), ModelConfig::Vit(config))
+        }
+    };
 
     // Creating the optimizer
     let mut opt = nn::Adam::default().build(&vs, 1e-3)?;
 
-    println!("{}", m.test_images.requires_grad());
-
-    // Simple epoch loop
-    for epoch in 1..args.epoch {
-        // MNIST is small enought so that we do not have to split it in batch so we compute the loss directly on the whole dataset
-        let y_hat = net.forward(&m.train_images.to_device(device));
-
+    // Computing the loss of a batch
+    let batch_loss = |images: &Tensor, labels: &Tensor| {
+        let y_hat = net.forward_t(&images.to_device(device), true);
         let y_hat = match args.normalization {
             NormalizationParam::None => y_hat,
             NormalizationParam::Sigmoid => y_hat.sigmoid(),
         };
+        y_hat.cross_entropy_for_logits(&labels.to_device(device))
+    };
 
-        let loss = y_hat.cross_entropy_for_logits(&m.train_labels.to_device(device));
-
-        // Gradient descent
-        opt.backward_step(&loss);
+    // Simple epoch loop
+    for epoch in 1..args.epoch {
+        let train_loss = match args.batch_size {
+            // MNIST is small enought so that we do not have to split it in batch so we compute the loss directly on the whole dataset
+            None => {
+                let loss = batch_loss(&m.train_images, &m.train_labels);
+                opt.backward_step(&loss);
+                f64::from(&loss)
+            }
+            Some(batch_size) => {
+                let mut total = 0.0;
+                let mut batches = 0;
+                for (images, labels) in m.train_iter(batch_size).shuffle() {
+                    let loss = batch_loss(&images, &labels);
+                    opt.backward_step(&loss);
+                    total += f64::from(&loss);
+                    batches += 1;
+                }
+                total / batches as f64
+            }
+        };
 
         // Loggin the accuracy of the epoch
-        let test_accuracy = net
-            .forward(&m.test_images.to_device(device))
-            .accuracy_for_logits(&m.test_labels.to_device(device));
+        let test_accuracy =
+            net.batch_accuracy_for_logits(&m.test_images, &m.test_labels, device, 1000);
         println!(
             "epoch: {:4} train loss: {:8.5} test acc: {:5.2}%",
             epoch,
-            f64::from(&loss),
-            100. * f64::from(&test_accuracy),
+            train_loss,
+            100. * test_accuracy,
         );
     }
 
+    // The config is saved next to the weights (or in the safetensors header) to rebuild the model
     if let Some(save_path) = args.weight_path {
-        vs.save(save_path)?;
+        let save_path = Path::new(&save_path);
+        if safetensors::is_safetensors(save_path) {
+            let mut metadata = Metadata::new()
+                .config(&config)?
+                .dataset_hash(&safetensors::dataset_hash(Path::new(&args.mnist_path))?);
+            if let Some(commit) = safetensors::git_commit() {
+                metadata = metadata.commit(&commit);
+            }
+            safetensors::save_var_store(&vs, save_path, &metadata)?;
+        } else {
+            vs.save(save_path)?;
+            save_config(&config, save_path)?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,135 @@
+use clap::{ArgEnum, Parser};
+use std::path::PathBuf;
+use tch::{nn, vision::image, Device, Kind};
+use tch_utils::{
+    config::config_path,
+    data::load_image,
+    segmentation::{classes_path, LabelMap, PaddingParam, SegmentationMode},
+    tta::{Merge, TestTimeAugmentation, TtaParam},
+};
+use unet::{
+    encoder::BasicCNN,
+    inference::{Blending, SlidingWindow},
+    load_unet, UNet, UnetProps,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+enum BlendingParam {
+    Gaussian,
+    Linear,
+}
+
+#[derive(Debug, Parser)]
+#[clap(version, author, about)]
+struct Args {
+    /// Weights of the model saved by unet-train
+    weight_path: PathBuf,
+
+    /// Image to segment (tiff or any format supported by tch)
+    image: PathBuf,
+
+    /// Path of the predicted mask
+    output: PathBuf,
+
+    /// Padding of the convolutions used during the training (only for weights saved without config)
+    #[clap(long, arg_enum, default_value_t = PaddingParam::Same)]
+    padding: PaddingParam,
+
+    /// Size of the tiles given to the model
+    #[clap(long, default_value_t = 512)]
+    tile: i64,
+
+    /// Overlap between the predictions of neighbouring tiles
+    #[clap(long, default_value_t = 64)]
+    overlap: i64,
+
+    #[clap(long, default_value_t = 4)]
+    batch_size: usize,
+
+    /// Blending of the overlapping tiles
+    #[clap(long, arg_enum, default_value_t = BlendingParam::Gaussian)]
+    blending: BlendingParam,
+
+    /// Test-time augmentation applied to each tile
+    #[clap(long, arg_enum, default_value_t = TtaParam::None)]
+    tta: TtaParam,
+
+    /// Merging of the predictions of the augmented tiles
+    #[clap(long, arg_enum, default_value_t = Merge::Mean)]
+    merge: Merge,
+
+    /// Segmentation mode used during the training (multi-class if the model has several classes)
+    #[clap(long, arg_enum)]
+    mode: Option<SegmentationMode>,
+
+    /// Saves a binary mask instead of the probabilities (binary segmentation),
+    /// threshold of the classes in multi-label mode (0.5 by default)
+    #[clap(long)]
+    threshold: Option<f64>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let device = Device::cuda_if_available();
+
+    // Loading the classes saved next to the weights
+    let classes = classes_path(&args.weight_path);
+    let labels = if classes.exists() {
+        LabelMap::from_file(&classes)?
+    } else {
+        LabelMap::binary()
+    };
+    let mode = match args.mode {
+        Some(mode) => mode,
+        None if labels.len() > 1 => SegmentationMode::MultiClass,
+        None => SegmentationMode::MultiLabel,
+    };
+
+    // Rebuilding the model from the config saved by unet-train, the weights saved without config use the padding argument
+    let (_vs, unet) = if config_path(&args.weight_path).exists() {
+        load_unet(&args.weight_path, device)?
+    } else {
+        let mut vs = nn::VarStore::new(device);
+        let encoder = BasicCNN::new_conf(&(&vs.root() / "encoder"), 3, args.padding.conv_config());
+        let props = UnetProps {
+            same_padding: args.padding.is_same(),
+            ..Default::default()
+        };
+        let unet = UNet::new(&vs.root(), encoder, labels.len() as u32, props);
+        vs.load(&args.weight_path)?;
+        (vs, unet.into())
+    };
+
+    // Wrapping the model with the test-time augmentation
+    let model = TestTimeAugmentation::segmentation(unet)
+        .transforms(args.tta.transforms())
+        .merge(args.merge);
+
+    // Predicting the whole image tile by tile
+    let blending = match args.blending {
+        BlendingParam::Gaussian => Blending::Gaussian,
+        BlendingParam::Linear => Blending::Linear,
+    };
+    let window = SlidingWindow::new(args.tile)
+        .overlap(args.overlap)
+        .batch_size(args.batch_size)
+        .blending(blending)
+        .mode(mode);
+    let image = load_image(&args.image)?
+        .to_kind(Kind::Float)
+        .to_device(device);
+    let probabilities = window.predict(&model, &image);
+
+    let mask = match (mode, labels.len()) {
+        (SegmentationMode::MultiClass, _) => labels.colorize(&probabilities.argmax(0, false)),
+        (SegmentationMode::MultiLabel, 1) => match args.threshold {
+            Some(threshold) => probabilities.ge(threshold).to_kind(Kind::Uint8) * 255,
+            None => (probabilities * 255.0).to_kind(Kind::Uint8),
+        },
+        (SegmentationMode::MultiLabel, _) => {
+            labels.colorize_masks(&probabilities.ge(args.threshold.unwrap_or(0.5)))
+        }
+    };
+    image::save(&mask.to_device(Device::Cpu), &args.output)?;
+    Ok(())
+}
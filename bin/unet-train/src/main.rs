@@ -1,14 +1,22 @@
 use clap::{ArgEnum, Parser};
 use itertools::{multiunzip, Itertools};
-use std::{fs::File, path::PathBuf};
-use tch::{
-    nn::{self, Module, OptimizerConfig},
-    vision::image,
-    Device, Tensor,
+use std::path::{Path, PathBuf};
+use tch::{nn, vision::image, Device, Kind, Tensor};
+use tch_utils::{
+    config::{build_model, save_config},
+    data::{load_image, Datafolder},
+    finetune::{ParamGroups, WarmUp},
+    import::{import_weights, NameMapping},
+    init::{InitParam, Initialization},
+    metrics::ClassMetrics,
+    segmentation::{classes_path, LabelMap, MaskFormat, PaddingParam, SegmentationMode},
+    tensor::{center_crop_like, interpolate2d},
+};
+use unet::{
+    deep_supervision_loss, deep_supervision_weights,
+    encoder::{BasicCNN, BasicCNNConfig},
+    UNet, UnetConfig, UnetProps,
 };
-use tch_utils::{data::Datafolder, metrics::dice_score_1c};
-use tiff::decoder::Decoder;
-use unet::encoder;
 
 #[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
 enum SuportedEncoders {
@@ -30,6 +38,62 @@ struct Args {
     /// Encoder used in the U-Net
     #[clap(arg_enum)]
     encoder: SuportedEncoders,
+
+    /// Pretrained weights for the encoder (PyTorch state_dict saved with torch.save, exported as .npz or libtorch archive)
+    #[clap(long)]
+    pretrained: Option<PathBuf>,
+
+    /// Table mapping the names of the pretrained weights to the encoder's (one `<source> <target>` per line)
+    #[clap(long, requires = "pretrained")]
+    mapping: Option<PathBuf>,
+
+    /// Learning rate of the model
+    #[clap(long, default_value_t = 1e-3)]
+    lr: f64,
+
+    /// Weight decay of the model
+    #[clap(long, default_value_t = 0.0)]
+    weight_decay: f64,
+
+    /// Learning rate of the encoder (defaults to the learning rate of the model)
+    #[clap(long)]
+    encoder_lr: Option<f64>,
+
+    /// Weight decay of the encoder (defaults to the weight decay of the model)
+    #[clap(long)]
+    encoder_weight_decay: Option<f64>,
+
+    /// Number of epochs during which the encoder is frozen before being fine-tuned
+    #[clap(long, default_value_t = 0)]
+    freeze_encoder_epochs: u32,
+
+    /// Padding of the convolutions
+    #[clap(long, arg_enum, default_value_t = PaddingParam::Same)]
+    padding: PaddingParam,
+
+    /// Trains the deeper decoder levels with auxiliary segmentation heads
+    #[clap(long)]
+    deep_supervision: bool,
+
+    /// File with one `<name> <r> <g> <b>` class per line (a single foreground class by default)
+    #[clap(long)]
+    classes: Option<PathBuf>,
+
+    /// Segmentation mode (multi-class with a class file, multi-label otherwise)
+    #[clap(long, arg_enum)]
+    mode: Option<SegmentationMode>,
+
+    /// Format of the masks (palette in multi-class mode, channels in multi-label mode)
+    #[clap(long, arg_enum)]
+    mask_format: Option<MaskFormat>,
+
+    /// Initialization of the weights of the convolutions
+    #[clap(long, arg_enum, default_value_t = InitParam::Default)]
+    init: InitParam,
+
+    /// Seed of the initialization
+    #[clap(long)]
+    seed: Option<i64>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -46,69 +110,122 @@ fn main() -> anyhow::Result<()> {
         Device::Cpu
     };
 
+    // Classes of the model and decoding of the masks
+    let labels = match &args.classes {
+        Some(path) => LabelMap::from_file(path)?,
+        None => LabelMap::binary(),
+    };
+    let mode = match args.mode {
+        Some(mode) => mode,
+        None if args.classes.is_some() => SegmentationMode::MultiClass,
+        None => SegmentationMode::MultiLabel,
+    };
+    let mask_format = args
+        .mask_format
+        .unwrap_or_else(|| MaskFormat::default_for(mode));
+
     // Creating the Model and the storage for the parameters
     let vs = nn::VarStore::new(device);
-    let encoder = encoder::BasicCNN::new(&(&vs.root() / "encoder"), 3);
-    let unet = unet::UNet::new(&vs.root(), encoder, 1, Default::default());
+    let same_padding = args.padding.is_same();
+    let config = UnetConfig {
+        encoder: BasicCNNConfig::new(3).conv_config(args.padding.conv_config()),
+        depth: 4,
+        class_count: labels.len() as u32,
+        props: UnetProps {
+            same_padding,
+            deep_supervision: args.deep_supervision,
+            ..Default::default()
+        },
+        init: Initialization {
+            seed: args.seed,
+            ..Initialization::new().weights("*", args.init.into())
+        },
+    };
+    let unet = build_model::<UNet<BasicCNN<4>, 4>>(&vs, &config)?;
+
+    // Initializing the encoder with pretrained weights
+    if let Some(pretrained) = &args.pretrained {
+        let mapping = match &args.mapping {
+            Some(mapping) => NameMapping::from_file(mapping)?,
+            None => NameMapping::new(),
+        };
+        let report = import_weights(&vs, pretrained, &mapping, "encoder")?;
+        print!("{report}");
+    }
 
-    // Creating the optimizer
-    let mut opt = nn::Adam::default().build(&vs, 1e-3)?;
+    // Creating the optimizer with its own learning rate for the encoder
+    let groups = ParamGroups::new(args.lr, args.weight_decay).group(
+        "encoder",
+        args.encoder_lr.unwrap_or(args.lr),
+        args.encoder_weight_decay.unwrap_or(args.weight_decay),
+    );
+    let mut opt = groups.build(&vs, nn::Adam::default())?;
+    let warm_up = WarmUp::new("encoder", args.freeze_encoder_epochs);
 
     // Simple epoch loop
     for epoch in 1..500 {
+        if warm_up.step(&vs, epoch) {
+            let state = if warm_up.is_frozen(epoch) {
+                "frozen"
+            } else {
+                "unfrozen"
+            };
+            println!("epoch: {:4} encoder {state}", epoch);
+        }
         let mut steps = 0;
         let mut avg_loss = 0.0;
+        let mut metrics = ClassMetrics::new(labels.len());
         let train_ds = Datafolder::from(
             &train_path,
             "images".to_string(),
             "mask".to_string(),
-            &load_image,
+            &|path| load_image(&path).unwrap(),
             &|path| tch::vision::image::load(path).unwrap(),
         )?
         .map(|x| {
             println!("loaded");
-            (
-                image::resize(&x.0, 565, 565).unwrap(),
-                image::resize(&x.0, 565, 565).unwrap(),
-            )
+            // The masks are decoded before being resized so that the classes are not blended
+            let y = mask_format.decode(&labels, &x.1).to_kind(Kind::Float);
+            let y = interpolate2d(&y.unsqueeze(0), &[565, 565], false).squeeze_dim(0);
+            (image::resize(&x.0, 565, 565).unwrap(), y)
         });
         for batch in train_ds.chunks(args.batch_size).into_iter() {
             let (x, y): (Vec<_>, Vec<_>) = multiunzip(batch);
             let x = Tensor::stack(x.as_slice(), 0)
                 .to_kind(tch::Kind::Float)
                 .to_device(device);
-            let y = Tensor::stack(y.as_slice(), 0)
-                .to_kind(tch::Kind::Float)
-                .to_device(device);
+            let y = Tensor::stack(y.as_slice(), 0).to_device(device);
+            let y = match mode {
+                SegmentationMode::MultiClass => y.to_kind(Kind::Int64),
+                SegmentationMode::MultiLabel => y,
+            };
             // Making the prediction
-            let y_hat = unet.forward(&x);
-            let loss = 1.0_f32 - dice_score_1c(&y_hat, &y);
+            let outputs = unet.forward_deep_t(&x, true);
+            let y = center_crop_like(&y, &outputs[0], 2);
+            let weights = deep_supervision_weights(outputs.len());
+            let loss = deep_supervision_loss(&outputs, &weights, |y_hat| mode.loss(y_hat, &y));
 
             // Gradient descent
             opt.backward_step(&loss);
 
             steps += args.batch_size;
             avg_loss += (f64::from(&loss) - avg_loss) / (steps + args.batch_size) as f64;
+            let (y_hat, y) = tch::no_grad(|| mode.masks(&outputs[0], &y));
+            metrics.update(&y_hat, &y);
         }
 
-        // Loggin the loss of the epoch
+        // Loggin the loss and the metrics of the epoch
         println!("epoch: {:4} train loss: {:8.5}", epoch, avg_loss);
+        for ((class, iou), dice) in labels.classes.iter().zip(metrics.iou()).zip(metrics.dice()) {
+            println!("    {:>16} iou: {:5.3} dice: {:5.3}", class.name, iou, dice);
+        }
     }
-    Ok(())
-}
 
-fn load_image(path: PathBuf) -> Tensor {
-    let file = File::open(path).unwrap();
-    let mut decoder = Decoder::new(file).unwrap();
-    let (x, y) = decoder.dimensions().unwrap();
-    let image = decoder.read_image().unwrap();
-
-    let img = match image {
-        tiff::decoder::DecodingResult::U8(i) => i,
-        _ => panic!(),
-    };
-    Tensor::from(img.as_slice())
-        .reshape(&[y as i64, x as i64, 3])
-        .swapaxes(0, 2)
-        .swapaxes(1, 2)
+    // The classes and the config are saved next to the weights
+    if let Some(save_path) = args.weight_path {
+        vs.save(&save_path)?;
+        labels.save(&classes_path(Path::new(&save_path)))?;
+        save_config(&config, Path::new(&save_path))?;
+    }
+    Ok(())
 }
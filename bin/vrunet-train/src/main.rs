@@ -0,0 +1,323 @@
+use anyhow::bail;
+use clap::{ArgEnum, Parser};
+use itertools::{multiunzip, Itertools};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+use tch::{nn, nn::OptimizerConfig, vision::image, Device, Kind, Tensor};
+use tch_utils::{
+    data::{load_image, TripletFolder},
+    layers::Normalization,
+    metrics::ClassMetrics,
+    segmentation::{classes_path, LabelMap, MaskFormat, SegmentationMode},
+    tensor::interpolate2d,
+};
+use tiff::decoder::{Decoder, DecodingResult};
+use unet::encoder::BasicCNNConfig;
+use vru_net::{VruNet, VruNetProps};
+
+#[derive(Debug, Copy, Clone, PartialEq, ArgEnum)]
+enum NormalizationParam {
+    None,
+    Batch,
+    Instance,
+}
+
+#[derive(Debug, Parser)]
+#[clap(version, author, about)]
+struct Args {
+    /// Path to the dataset expect the folder to contain two folder training & validation
+    /// with each an images, aux & masks folder (the files of the three folders are matched by name order)
+    dataset_path: PathBuf,
+
+    /// Path to the save location for the weight of the model
+    #[clap(long)]
+    weight_path: Option<PathBuf>,
+
+    #[clap(long, default_value_t = 8)]
+    batch_size: usize,
+
+    #[clap(long, default_value_t = 100)]
+    epochs: u32,
+
+    /// Learning rate of the model
+    #[clap(long, default_value_t = 1e-3)]
+    lr: f64,
+
+    /// Resolution the images and the masks are resized to
+    #[clap(long, default_value_t = 256)]
+    size: i64,
+
+    /// Number of levels of the encoder
+    #[clap(long, default_value_t = 4)]
+    depth: usize,
+
+    /// Number of channels of the first level of the encoder
+    #[clap(long, default_value_t = 64)]
+    base_width: i64,
+
+    /// Factor applied to the number of channels at each level of the encoder
+    #[clap(long, default_value_t = 2.0)]
+    width_multiplier: f64,
+
+    /// Normalization of the encoder
+    #[clap(long, arg_enum, default_value_t = NormalizationParam::None)]
+    normalization: NormalizationParam,
+
+    /// Channels of the auxiliary rasters
+    #[clap(long, default_value_t = 4)]
+    aux_channels: i64,
+
+    /// File with one `<name> <r> <g> <b>` class per line (a single foreground class by default)
+    #[clap(long)]
+    classes: Option<PathBuf>,
+
+    /// Segmentation mode (multi-class with a class file, multi-label otherwise)
+    #[clap(long, arg_enum)]
+    mode: Option<SegmentationMode>,
+
+    /// Format of the masks (palette in multi-class mode, channels in multi-label mode)
+    #[clap(long, arg_enum)]
+    mask_format: Option<MaskFormat>,
+
+    /// Folder of the checkpoints, the weights with the best validation IoU are saved as `best.pt`
+    #[clap(long)]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// Number of epochs between two checkpoints
+    #[clap(long, default_value_t = 10)]
+    checkpoint_every: u32,
+
+    /// Weights to resume the training from
+    #[clap(long)]
+    resume: Option<PathBuf>,
+}
+
+/// Settings of the data shared by the training and the validation
+struct Data {
+    labels: LabelMap,
+    mode: SegmentationMode,
+    mask_format: MaskFormat,
+    size: i64,
+    aux_size: i64,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    match args.depth {
+        2 => train::<2>(&args),
+        3 => train::<3>(&args),
+        4 => train::<4>(&args),
+        5 => train::<5>(&args),
+        depth => bail!("Unsupported depth {depth}, expected a depth between 2 and 5"),
+    }
+}
+
+fn train<const L: usize>(args: &Args) -> anyhow::Result<()> {
+    // Picking the device to use to the train of the model
+    let device = if tch::Cuda::is_available() {
+        let device_count = tch::Cuda::device_count() as f32;
+        let r: f32 = rand::random();
+        Device::Cuda(f32::floor(device_count * r) as usize)
+    } else {
+        Device::Cpu
+    };
+
+    // Classes of the model and decoding of the masks
+    let labels = match &args.classes {
+        Some(path) => LabelMap::from_file(path)?,
+        None => LabelMap::binary(),
+    };
+    let mode = match args.mode {
+        Some(mode) => mode,
+        None if args.classes.is_some() => SegmentationMode::MultiClass,
+        None => SegmentationMode::MultiLabel,
+    };
+    let mask_format = args
+        .mask_format
+        .unwrap_or_else(|| MaskFormat::default_for(mode));
+    let scale = 2_i64.pow(L as u32 - 1);
+    if args.size % scale != 0 {
+        bail!(
+            "The size ({}) should be a multiple of {} for a depth of {}",
+            args.size,
+            scale,
+            L
+        );
+    }
+    let data = Data {
+        labels,
+        mode,
+        mask_format,
+        size: args.size,
+        // The auxiliary rasters are resized to the resolution of the bottleneck
+        aux_size: args.size / scale,
+    };
+
+    // Creating the Model and the storage for the parameters
+    let mut vs = nn::VarStore::new(device);
+    let normalization = match args.normalization {
+        NormalizationParam::None => Normalization::None,
+        NormalizationParam::Batch => Normalization::Batch,
+        NormalizationParam::Instance => Normalization::Instance,
+    };
+    let encoder = BasicCNNConfig::new(3)
+        .base_width(args.base_width)
+        .width_multiplier(args.width_multiplier)
+        .normalization(normalization)
+        .conv_config(nn::ConvConfig {
+            padding: 1,
+            ..Default::default()
+        })
+        .build::<L>(&(&vs.root() / "encoder"));
+    let props = VruNetProps {
+        aux_channels: args.aux_channels,
+        class_count: Some(data.labels.len() as u32),
+        ..Default::default()
+    };
+    let vrunet = VruNet::new_props(&vs.root(), encoder, props);
+    if let Some(resume) = &args.resume {
+        vs.load(resume)?;
+    }
+    if let Some(checkpoint_dir) = &args.checkpoint_dir {
+        fs::create_dir_all(checkpoint_dir)?;
+    }
+
+    let mut opt = nn::Adam::default().build(&vs, args.lr)?;
+
+    let train_path = args.dataset_path.join("training");
+    let validation_path = args.dataset_path.join("validation");
+    let mut best_iou = f64::NEG_INFINITY;
+    for epoch in 1..=args.epochs {
+        let mut steps = 0;
+        let mut avg_loss = 0.0;
+        for batch in load_triplets(&train_path, &data)?
+            .chunks(args.batch_size)
+            .into_iter()
+        {
+            let (x, aux, y) = stack_batch(batch, &data, device);
+            let y_hat = vrunet.forward_t(&x, &aux, true);
+            let loss = mode.loss(&y_hat, &y);
+
+            // Gradient descent
+            opt.backward_step(&loss);
+
+            steps += 1;
+            avg_loss += (f64::from(&loss) - avg_loss) / steps as f64;
+        }
+        println!("epoch: {:4} train loss: {:8.5}", epoch, avg_loss);
+
+        // Validation metrics
+        let mut metrics = ClassMetrics::new(data.labels.len());
+        if validation_path.exists() {
+            tch::no_grad(|| -> anyhow::Result<()> {
+                for batch in load_triplets(&validation_path, &data)?
+                    .chunks(args.batch_size)
+                    .into_iter()
+                {
+                    let (x, aux, y) = stack_batch(batch, &data, device);
+                    let y_hat = vrunet.forward_t(&x, &aux, false);
+                    let (y_hat, y) = mode.masks(&y_hat, &y);
+                    metrics.update(&y_hat, &y);
+                }
+                Ok(())
+            })?;
+            let ious = metrics.iou();
+            for ((class, iou), dice) in data.labels.classes.iter().zip(&ious).zip(metrics.dice()) {
+                println!("    {:>16} iou: {:5.3} dice: {:5.3}", class.name, iou, dice);
+            }
+
+            // Keeping the weights with the best mean IoU
+            let present: Vec<_> = ious.into_iter().filter(|iou| !iou.is_nan()).collect();
+            let mean_iou = present.iter().sum::<f64>() / present.len().max(1) as f64;
+            if mean_iou > best_iou {
+                best_iou = mean_iou;
+                if let Some(checkpoint_dir) = &args.checkpoint_dir {
+                    // The classes are saved next to the weights
+                    let best_path = checkpoint_dir.join("best.pt");
+                    vs.save(&best_path)?;
+                    data.labels.save(&classes_path(&best_path))?;
+                }
+            }
+        }
+
+        if let Some(checkpoint_dir) = &args.checkpoint_dir {
+            if epoch % args.checkpoint_every.max(1) == 0 {
+                vs.save(checkpoint_dir.join(format!("epoch{epoch}.pt")))?;
+            }
+        }
+    }
+
+    // The classes are saved next to the weights
+    if let Some(save_path) = &args.weight_path {
+        vs.save(save_path)?;
+        data.labels.save(&classes_path(save_path))?;
+    }
+    Ok(())
+}
+
+/// Loads the `(image, aux, mask)` triplets of a folder resized for the model
+fn load_triplets<'a>(
+    path: &Path,
+    data: &'a Data,
+) -> anyhow::Result<impl Iterator<Item = (Tensor, Tensor, Tensor)> + 'a> {
+    Ok(TripletFolder::from(
+        path,
+        ["images", "aux", "masks"],
+        &|path| load_image(&path).unwrap(),
+        &load_raster,
+        &load_mask,
+    )?
+    .map(|((x, aux), y)| {
+        // The masks are decoded before being resized so that the classes are not blended
+        let y = data
+            .mask_format
+            .decode(&data.labels, &y)
+            .to_kind(Kind::Float);
+        let y = interpolate2d(&y.unsqueeze(0), &[data.size, data.size], false).squeeze_dim(0);
+        let x = image::resize(&x, data.size, data.size).unwrap();
+        let aux = interpolate2d(&aux, &[data.aux_size, data.aux_size], true);
+        (x, aux, y)
+    }))
+}
+
+/// Stacks the triplets of a batch on the device
+fn stack_batch(
+    batch: impl Iterator<Item = (Tensor, Tensor, Tensor)>,
+    data: &Data,
+    device: Device,
+) -> (Tensor, Tensor, Tensor) {
+    let (x, aux, y): (Vec<_>, Vec<_>, Vec<_>) = multiunzip(batch);
+    let x = Tensor::stack(&x, 0).to_kind(Kind::Float).to_device(device);
+    let aux = Tensor::stack(&aux, 0).to_device(device);
+    let y = Tensor::stack(&y, 0).to_device(device);
+    let y = match data.mode {
+        SegmentationMode::MultiClass => y.to_kind(Kind::Int64),
+        SegmentationMode::MultiLabel => y,
+    };
+    (x, aux, y)
+}
+
+fn load_mask(path: PathBuf) -> Tensor {
+    image::load(path).unwrap()
+}
+
+/// Loads a tiff raster with any number of channels as a `[C,H,W]` float tensor
+fn load_raster(path: PathBuf) -> Tensor {
+    let file = File::open(path).unwrap();
+    let mut decoder = Decoder::new(file).unwrap();
+    let (x, y) = decoder.dimensions().unwrap();
+    let raster = match decoder.read_image().unwrap() {
+        DecodingResult::U8(r) => Tensor::from(r.as_slice()),
+        DecodingResult::U16(r) => {
+            Tensor::from(r.iter().map(|v| *v as f32).collect::<Vec<_>>().as_slice())
+        }
+        DecodingResult::F32(r) => Tensor::from(r.as_slice()),
+        _ => panic!("Unsupported raster type"),
+    };
+    raster
+        .reshape(&[y as i64, x as i64, -1])
+        .permute(&[2, 0, 1])
+        .to_kind(Kind::Float)
+}
@@ -0,0 +1,115 @@
+use tch::{Device, Kind, Tensor};
+use tch_utils::types::FeatureExtractor;
+
+use crate::AutoEncoder;
+
+/// Latent vectors (`[N, latent_dim]`, on the cpu) of `[N,C,H,W]` images processed by batches of `batch_size` on `device`
+pub fn encode_images<E, const L: usize>(
+    model: &AutoEncoder<E, L>,
+    images: &Tensor,
+    batch_size: i64,
+    device: Device,
+) -> Tensor
+where
+    E: FeatureExtractor<L>,
+{
+    let _guard = tch::no_grad_guard();
+    let batches: Vec<_> = images
+        .split(batch_size, 0)
+        .iter()
+        .map(|batch| {
+            model
+                .encode(&batch.to_device(device))
+                .to_device(Device::Cpu)
+        })
+        .collect();
+    Tensor::cat(&batches, 0)
+}
+
+/// Mean squared error of the reconstruction of each image (`[N]`, on the cpu), usable as an anomaly score
+pub fn reconstruction_errors<E, const L: usize>(
+    model: &AutoEncoder<E, L>,
+    images: &Tensor,
+    batch_size: i64,
+    device: Device,
+) -> Tensor
+where
+    E: FeatureExtractor<L>,
+{
+    let _guard = tch::no_grad_guard();
+    let errors: Vec<_> = images
+        .split(batch_size, 0)
+        .iter()
+        .map(|batch| {
+            let batch = batch.to_device(device);
+            let reconstruction = model.decode(&model.encode(&batch));
+            (reconstruction - batch)
+                .square()
+                .flatten(1, -1)
+                .mean_dim(&[-1], false, Kind::Float)
+                .to_device(Device::Cpu)
+        })
+        .collect();
+    Tensor::cat(&errors, 0)
+}
+
+/// Decodes `steps` latent vectors linearly interpolated between the ones of the `[C,H,W]` images `a` and `b`
+pub fn interpolate<E, const L: usize>(
+    model: &AutoEncoder<E, L>,
+    a: &Tensor,
+    b: &Tensor,
+    steps: i64,
+) -> Tensor
+where
+    E: FeatureExtractor<L>,
+{
+    let _guard = tch::no_grad_guard();
+    let z = model.encode(&Tensor::stack(&[a, b], 0));
+    let t = Tensor::linspace(0.0, 1.0, steps, (Kind::Float, z.device())).unsqueeze(1);
+    let (za, zb) = (z.get(0).unsqueeze(0), z.get(1).unsqueeze(0));
+    model.decode(&(&za + (zb - &za) * t))
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{ConvConfig, VarStore},
+        Device, Kind, Tensor,
+    };
+    use unet::encoder::BasicCNNConfig;
+
+    use super::{encode_images, interpolate, reconstruction_errors};
+    use crate::{AutoEncoder, AutoencoderProps};
+
+    #[test]
+    fn latents() {
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(1)
+            .base_width(4)
+            .conv_config(ConvConfig {
+                padding: 1,
+                ..Default::default()
+            })
+            .build::<2>(&(&vs.root() / "encoder"));
+        let ae = AutoEncoder::new(
+            &vs.root(),
+            encoder,
+            AutoencoderProps {
+                image_size: 8,
+                latent_dim: 5,
+                ..Default::default()
+            },
+        );
+
+        let images = Tensor::rand(&[7, 1, 8, 8], (Kind::Float, Device::Cpu));
+        let z = encode_images(&ae, &images, 3, Device::Cpu);
+        assert_eq!(z.size(), vec![7, 5]);
+        assert!(z.allclose(&ae.encode(&images), 1e-5, 1e-5, false));
+        assert_eq!(
+            reconstruction_errors(&ae, &images, 3, Device::Cpu).size(),
+            vec![7]
+        );
+        let steps = interpolate(&ae, &images.get(0), &images.get(1), 4);
+        assert_eq!(steps.size(), vec![4, 1, 8, 8]);
+    }
+}
@@ -0,0 +1,447 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, Conv2D, Module, ModuleT, Path},
+    Device, Kind, Tensor,
+};
+use tch_utils::{
+    config::FromConfig,
+    init::Initialization,
+    layers::Activation,
+    summary::{module_name, trace},
+    types::{input, FeatureExtractor, MultiModuleT, NamedTensors},
+};
+use thiserror::Error;
+use unet::{
+    block::{BlockProps, ConvBlock, UpSample, Upsampling},
+    encoder::{BasicCNN, BasicCNNConfig},
+};
+
+pub mod latent;
+
+#[derive(Debug, Error)]
+enum ConfigError {
+    #[error("The config describes an autoencoder of depth {0} while a depth of {1} was expected")]
+    Depth(usize, usize),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoencoderProps {
+    /// Size of the (square) input images, it should be a multiple of 2^L.
+    /// The encoder should use padded convolutions (e.g. [`BasicCNNConfig::conv_config`]).
+    pub image_size: i64,
+    /// Chanels of the reconstructed images
+    pub out_channels: i64,
+    /// Size of the latent vectors
+    pub latent_dim: i64,
+    /// Encodes a distribution (mean and log-variance) of the latent vectors instead of the vectors themselves
+    pub variational: bool,
+    pub decoder_block_convolutions: u32,
+    /// Normalization, activation, dropout and residual connections of the decoder blocks
+    pub block: BlockProps,
+    pub upsampling: Upsampling,
+    /// Activation of the reconstruction (e.g. sigmoid for images in `[0, 1]`)
+    pub output_activation: Activation,
+}
+
+impl Default for AutoencoderProps {
+    fn default() -> Self {
+        Self {
+            image_size: 32,
+            out_channels: 1,
+            latent_dim: 16,
+            variational: false,
+            decoder_block_convolutions: 2,
+            block: Default::default(),
+            upsampling: Upsampling::Transpose,
+            output_activation: Activation::Sigmoid,
+        }
+    }
+}
+
+/// Serializable description of an autoencoder with a [`BasicCNN`] encoder, see [`FromConfig`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoencoderConfig {
+    pub encoder: BasicCNNConfig,
+    /// Number of levels of the encoder
+    pub depth: usize,
+    pub props: AutoencoderProps,
+    /// Initialization of the bottleneck and the decoder, relative to the autoencoder (e.g. `decoder.*`).
+    /// The encoder uses its own initialization.
+    #[serde(default)]
+    pub init: Initialization,
+}
+
+/// Projection of the encoded features to the latent space
+#[derive(Debug)]
+enum Bottleneck {
+    Deterministic(nn::Linear),
+    Variational { mu: nn::Linear, logvar: nn::Linear },
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    up: UpSample,
+    block: ConvBlock,
+}
+
+/// Outputs of [`AutoEncoder::forward_latent_t`]
+#[derive(Debug)]
+pub struct AutoencoderOutput {
+    pub reconstruction: Tensor,
+    /// Latent vectors used for the reconstruction (sampled with [`reparameterize`] by a training forward of a VAE)
+    pub z: Tensor,
+    /// Mean of the latent distribution, the latent vectors for a deterministic autoencoder
+    pub mu: Tensor,
+    /// Log-variance of the latent distribution, `None` for a deterministic autoencoder
+    pub logvar: Option<Tensor>,
+}
+
+impl AutoencoderOutput {
+    /// Reconstruction loss plus `beta` times the KL divergence for a VAE (beta-VAE, Higgins et al. 2017)
+    pub fn loss(&self, xs: &Tensor, beta: f64) -> Tensor {
+        let loss = reconstruction_loss(&self.reconstruction, xs);
+        match &self.logvar {
+            Some(logvar) => loss + kl_loss(&self.mu, logvar) * beta,
+            None => loss,
+        }
+    }
+}
+
+/// Convolutional autoencoder on top of a [`FeatureExtractor`], optionally variational (Kingma & Welling 2013).
+///
+/// The output of the encoder is flattened and projected to the latent space (`latent` or `mu` and `logvar`),
+/// the decoder projects the latent vectors back (`decoder.input`) and up-samples them through `L` levels
+/// (`decoder.layer{i}`) before a 1x1 convolution (`decoder.output`).
+#[derive(Debug)]
+pub struct AutoEncoder<E, const L: usize>
+where
+    E: FeatureExtractor<L>,
+{
+    encoder: E,
+    bottleneck: Bottleneck,
+    latent_name: String,
+    decoder_input: nn::Linear,
+    decoder: Vec<DecoderLayer>,
+    output: Conv2D,
+    output_name: String,
+    /// Shape `[C, H, W]` of the output of the encoder
+    encoded_shape: [i64; 3],
+    props: AutoencoderProps,
+}
+
+impl<E, const L: usize> AutoEncoder<E, L>
+where
+    E: FeatureExtractor<L>,
+{
+    pub fn new(vs: &Path, encoder: E, props: AutoencoderProps) -> Self {
+        let scale = 2_i64.pow(L as u32);
+        assert!(
+            props.image_size % scale == 0,
+            "The image size ({}) should be a multiple of {scale}",
+            props.image_size
+        );
+        assert!(props.latent_dim > 0, "latent_dim should be above 0");
+
+        let chanels = encoder.chanels_count();
+        let size = props.image_size / scale;
+        let encoded_shape = [chanels[L - 1], size, size];
+        let features = chanels[L - 1] * size * size;
+
+        let linear = |name: &str, in_dim: i64, out_dim: i64| {
+            nn::linear(vs / name, in_dim, out_dim, Default::default())
+        };
+        let bottleneck = if props.variational {
+            Bottleneck::Variational {
+                mu: linear("mu", features, props.latent_dim),
+                logvar: linear("logvar", features, props.latent_dim),
+            }
+        } else {
+            Bottleneck::Deterministic(linear("latent", features, props.latent_dim))
+        };
+        let latent_name = module_name(&(vs / if props.variational { "mu" } else { "latent" }));
+
+        let conv_conf = nn::ConvConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        let decoder_vs = vs / "decoder";
+        let decoder_input = nn::linear(
+            &decoder_vs / "input",
+            props.latent_dim,
+            features,
+            Default::default(),
+        );
+        let decoder = (0..L)
+            .rev()
+            .map(|layer| {
+                let vs = &decoder_vs / format!("layer{layer}");
+                let in_channels = if layer == L - 1 {
+                    chanels[layer]
+                } else {
+                    chanels[layer + 1]
+                };
+                DecoderLayer {
+                    up: UpSample::new(&vs, in_channels, chanels[layer], props.upsampling),
+                    block: ConvBlock::new(
+                        &vs,
+                        chanels[layer],
+                        chanels[layer],
+                        props.decoder_block_convolutions,
+                        conv_conf,
+                        props.block,
+                    ),
+                }
+            })
+            .collect();
+        let output = nn::conv2d(
+            &decoder_vs / "output",
+            chanels[0],
+            props.out_channels,
+            1,
+            Default::default(),
+        );
+
+        Self {
+            encoder,
+            bottleneck,
+            latent_name,
+            decoder_input,
+            decoder,
+            output,
+            output_name: module_name(&(&decoder_vs / "output")),
+            encoded_shape,
+            props,
+        }
+    }
+
+    pub fn latent_dim(&self) -> i64 {
+        self.props.latent_dim
+    }
+
+    pub fn is_variational(&self) -> bool {
+        self.props.variational
+    }
+
+    /// Mean and log-variance (`None` for a deterministic autoencoder) of the latent distribution of `[B,C,H,W]` images
+    pub fn encode_t(&self, xs: &Tensor, train: bool) -> (Tensor, Option<Tensor>) {
+        let (_, features) = self.encoder.forward_extracts_t(xs, train);
+        let features = features.flatten(1, -1);
+        let (mu, logvar) = match &self.bottleneck {
+            Bottleneck::Deterministic(latent) => (latent.forward(&features), None),
+            Bottleneck::Variational { mu, logvar } => {
+                (mu.forward(&features), Some(logvar.forward(&features)))
+            }
+        };
+        trace(&self.latent_name, &mu);
+        (mu, logvar)
+    }
+
+    /// Latent vectors of `[B,C,H,W]` images, the mean of the distribution for a VAE
+    pub fn encode(&self, xs: &Tensor) -> Tensor {
+        self.encode_t(xs, false).0
+    }
+
+    /// Reconstructs the images of `[B, latent_dim]` latent vectors
+    pub fn decode_t(&self, z: &Tensor, train: bool) -> Tensor {
+        let [c, h, w] = self.encoded_shape;
+        let xs = self.decoder_input.forward(z).view([-1, c, h, w]);
+        let xs = self.props.block.activation.apply(&xs);
+        let xs = self.decoder.iter().fold(xs, |xs, layer| {
+            layer.block.forward_t(&layer.up.forward(&xs), train)
+        });
+        let ys = self
+            .props
+            .output_activation
+            .apply(&self.output.forward(&xs));
+        trace(&self.output_name, &ys);
+        ys
+    }
+
+    pub fn decode(&self, z: &Tensor) -> Tensor {
+        self.decode_t(z, false)
+    }
+
+    /// Encodes and reconstructs the images, the latent vectors of a VAE are sampled during the training
+    pub fn forward_latent_t(&self, xs: &Tensor, train: bool) -> AutoencoderOutput {
+        let (mu, logvar) = self.encode_t(xs, train);
+        let z = match (&logvar, train) {
+            (Some(logvar), true) => reparameterize(&mu, logvar),
+            _ => mu.shallow_clone(),
+        };
+        AutoencoderOutput {
+            reconstruction: self.decode_t(&z, train),
+            z,
+            mu,
+            logvar,
+        }
+    }
+
+    /// Decodes `count` latent vectors drawn from the prior `N(0, I)`, meaningful for a VAE
+    pub fn sample(&self, count: i64, device: Device) -> Tensor {
+        let z = Tensor::randn(&[count, self.props.latent_dim], (Kind::Float, device));
+        self.decode(&z)
+    }
+}
+
+/// Latent vectors `mu + sigma * eps` with `eps ~ N(0, I)` so that the sampling is differentiable
+pub fn reparameterize(mu: &Tensor, logvar: &Tensor) -> Tensor {
+    let std = (logvar * 0.5).exp();
+    mu + std.randn_like() * std
+}
+
+/// KL divergence between `N(mu, exp(logvar))` and `N(0, I)`, summed over the latent dimensions and averaged over the batch
+pub fn kl_loss(mu: &Tensor, logvar: &Tensor) -> Tensor {
+    let kl = (logvar + 1.0 - mu.pow_tensor_scalar(2) - logvar.exp()).sum_dim_intlist(
+        &[-1],
+        false,
+        Kind::Float,
+    );
+    kl.mean(Kind::Float) * -0.5
+}
+
+/// Squared error summed over the pixels and averaged over the batch
+pub fn reconstruction_loss(ys: &Tensor, xs: &Tensor) -> Tensor {
+    (ys - xs)
+        .square()
+        .flatten(1, -1)
+        .sum_dim_intlist(&[-1], false, Kind::Float)
+        .mean(Kind::Float)
+}
+
+impl<E, const L: usize> nn::ModuleT for AutoEncoder<E, L>
+where
+    E: FeatureExtractor<L>,
+{
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        self.forward_latent_t(xs, train).reconstruction
+    }
+}
+
+impl<E, const L: usize> MultiModuleT for AutoEncoder<E, L>
+where
+    E: FeatureExtractor<L>,
+{
+    fn input_names(&self) -> Vec<&str> {
+        vec!["image"]
+    }
+
+    fn output_names(&self) -> Vec<&str> {
+        vec!["reconstruction", "latent"]
+    }
+
+    fn forward_multi_t(&self, inputs: &NamedTensors, train: bool) -> anyhow::Result<NamedTensors> {
+        let output = self.forward_latent_t(input(inputs, "image")?, train);
+        Ok(NamedTensors::from([
+            ("reconstruction".to_string(), output.reconstruction),
+            ("latent".to_string(), output.mu),
+        ]))
+    }
+}
+
+impl<const L: usize> FromConfig for AutoEncoder<BasicCNN<L>, L> {
+    type Config = AutoencoderConfig;
+
+    fn from_config(vs: &Path, config: &AutoencoderConfig) -> anyhow::Result<Self> {
+        if config.depth != L {
+            bail!(ConfigError::Depth(config.depth, L));
+        }
+        let encoder = BasicCNN::from_config(&(vs / "encoder"), &config.encoder)?;
+        Ok(AutoEncoder::new(vs, encoder, config.props.clone()))
+    }
+
+    fn initialization(config: &AutoencoderConfig) -> Initialization {
+        let init = config
+            .encoder
+            .init
+            .prefixed("encoder")
+            .extend(config.init.clone());
+        if !init.zero_residual || !config.props.block.residual {
+            return init;
+        }
+        // The blocks without convolution are rejected by their constructor
+        match config.props.decoder_block_convolutions.checked_sub(1) {
+            Some(last) => init.zeros(&format!("decoder.layer*.conv{last}")),
+            None => init,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{ConvConfig, ModuleT, VarStore},
+        Device, Kind, Tensor,
+    };
+    use tch_utils::config::{build_model, checkpoint_round_trip, FromConfig};
+    use unet::encoder::{BasicCNN, BasicCNNConfig};
+
+    use crate::{kl_loss, AutoEncoder, AutoencoderConfig, AutoencoderProps};
+
+    fn config(variational: bool) -> AutoencoderConfig {
+        AutoencoderConfig {
+            encoder: BasicCNNConfig::new(1)
+                .base_width(4)
+                .conv_config(ConvConfig {
+                    padding: 1,
+                    ..Default::default()
+                }),
+            depth: 2,
+            props: AutoencoderProps {
+                image_size: 16,
+                latent_dim: 3,
+                variational,
+                ..Default::default()
+            },
+            init: Default::default(),
+        }
+    }
+
+    #[test]
+    fn autoencoder() {
+        let vs = VarStore::new(Device::Cpu);
+        let ae = build_model::<AutoEncoder<BasicCNN<2>, 2>>(&vs, &config(false)).unwrap();
+        let x = Tensor::rand(&[2, 1, 16, 16], (Kind::Float, Device::Cpu));
+
+        let output = ae.forward_latent_t(&x, true);
+        assert_eq!(output.reconstruction.size(), vec![2, 1, 16, 16]);
+        assert_eq!(output.mu.size(), vec![2, 3]);
+        assert!(output.logvar.is_none());
+        // The deterministic autoencoder doesn't sample
+        assert!(ae.encode(&x).equal(&ae.encode(&x)));
+        assert_eq!(ae.sample(4, Device::Cpu).size(), vec![4, 1, 16, 16]);
+    }
+
+    #[test]
+    fn vae() {
+        let vs = VarStore::new(Device::Cpu);
+        let config = config(true);
+        let vae = build_model::<AutoEncoder<BasicCNN<2>, 2>>(&vs, &config).unwrap();
+        let x = Tensor::rand(&[2, 1, 16, 16], (Kind::Float, Device::Cpu));
+
+        let output = vae.forward_latent_t(&x, true);
+        assert!(output.logvar.is_some());
+        assert!(!output.z.equal(&output.mu));
+        assert!(f64::from(output.loss(&x, 1.0)) > 0.0);
+        // The evaluation uses the mean of the distribution
+        assert!(vae.forward_t(&x, false).equal(&vae.forward_t(&x, false)));
+
+        let loaded = checkpoint_round_trip(&vs, &vae, &config, &x);
+        assert!(loaded.is_variational());
+        assert!(loaded
+            .encode(&x)
+            .allclose(&vae.encode(&x), 1e-6, 1e-6, false));
+        // The depth of the config should match the one of the model
+        let vs = VarStore::new(Device::Cpu);
+        assert!(AutoEncoder::<BasicCNN<3>, 3>::from_config(&vs.root(), &config).is_err());
+    }
+
+    #[test]
+    fn kl() {
+        // The KL divergence of the prior with itself is null
+        let zeros = Tensor::zeros(&[4, 3], (Kind::Float, Device::Cpu));
+        assert_eq!(f64::from(kl_loss(&zeros, &zeros)), 0.0);
+        let ones = Tensor::ones(&[4, 3], (Kind::Float, Device::Cpu));
+        assert!((f64::from(kl_loss(&ones, &zeros)) - 1.5).abs() < 1e-6);
+    }
+}
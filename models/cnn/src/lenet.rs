@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, ConvConfig, Module, ModuleT},
+    Tensor,
+};
+use tch_utils::{
+    config::FromConfig,
+    init::Initialization,
+    layers::{Activation, Norm, Normalization},
+    summary::{module_name, trace},
+    tensor::images,
+};
+
+/// Configuration of a [`LeNet`] : stages of `conv -> norm -> activation -> 2x2 max pooling`
+/// followed by fully connected layers.
+///
+/// The variables are named `conv{i}`, `norm{i}`, `fc{i}` and `classifier`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeNetConfig {
+    pub in_channels: i64,
+    pub classes: i64,
+    /// Size of the (square) input images, used to unflatten the inputs and size the first fully connected layer
+    pub image_size: i64,
+    /// Chanels of the convolution of each stage
+    pub channels: Vec<i64>,
+    /// Size of the kernels, the convolutions are padded to keep the resolution
+    pub kernel_size: i64,
+    /// Width of the hidden fully connected layers
+    pub hidden: Vec<i64>,
+    pub activation: Activation,
+    pub normalization: Normalization,
+    /// Dropout probability applied after the hidden fully connected layers during the training
+    pub dropout: f64,
+    #[serde(default)]
+    pub init: Initialization,
+}
+
+impl LeNetConfig {
+    /// LeNet-5 like configuration (6 and 16 chanels, 120 and 84 hidden nodes)
+    pub fn new(in_channels: i64, classes: i64, image_size: i64) -> Self {
+        Self {
+            in_channels,
+            classes,
+            image_size,
+            channels: vec![6, 16],
+            kernel_size: 5,
+            hidden: vec![120, 84],
+            activation: Activation::ReLU,
+            normalization: Normalization::None,
+            dropout: 0.0,
+            init: Initialization::default(),
+        }
+    }
+
+    pub fn channels(mut self, channels: Vec<i64>) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    pub fn kernel_size(mut self, kernel_size: i64) -> Self {
+        self.kernel_size = kernel_size;
+        self
+    }
+
+    pub fn hidden(mut self, hidden: Vec<i64>) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    pub fn activation(mut self, activation: Activation) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    /// Normalization applied after each convolution
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    pub fn dropout(mut self, dropout: f64) -> Self {
+        self.dropout = dropout;
+        self
+    }
+
+    pub fn init(mut self, init: Initialization) -> Self {
+        self.init = init;
+        self
+    }
+
+    pub fn build(&self, vs: &nn::Path) -> LeNet {
+        assert!(!self.channels.is_empty(), "Expected at least one stage");
+        assert!(
+            self.kernel_size % 2 == 1,
+            "The kernel size should be odd to keep the resolution"
+        );
+        assert!(
+            (0.0..1.0).contains(&self.dropout),
+            "dropout should be in [0, 1)"
+        );
+        let size = self.image_size >> self.channels.len();
+        assert!(
+            size > 0,
+            "The images of size {} are too small for {} stages",
+            self.image_size,
+            self.channels.len()
+        );
+
+        let mut previous = self.in_channels;
+        let convs = self
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(i, channels)| {
+                let conv_vs = vs / format!("conv{i}");
+                let conv = nn::conv2d(
+                    &conv_vs,
+                    previous,
+                    *channels,
+                    self.kernel_size,
+                    ConvConfig {
+                        padding: self.kernel_size / 2,
+                        ..Default::default()
+                    },
+                );
+                let norm = self
+                    .normalization
+                    .build(&(vs / format!("norm{i}")), *channels, 2);
+                previous = *channels;
+                (conv, norm, module_name(&conv_vs))
+            })
+            .collect();
+
+        let mut previous = previous * size * size;
+        let hidden = self
+            .hidden
+            .iter()
+            .enumerate()
+            .map(|(i, width)| {
+                let fc_vs = vs / format!("fc{i}");
+                let fc = nn::linear(&fc_vs, previous, *width, Default::default());
+                previous = *width;
+                (fc, module_name(&fc_vs))
+            })
+            .collect();
+        let classifier = nn::linear(
+            vs / "classifier",
+            previous,
+            self.classes,
+            Default::default(),
+        );
+
+        LeNet {
+            convs,
+            hidden,
+            classifier,
+            classifier_name: module_name(&(vs / "classifier")),
+            config: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LeNet {
+    convs: Vec<(nn::Conv2D, Option<Norm>, String)>,
+    hidden: Vec<(nn::Linear, String)>,
+    classifier: nn::Linear,
+    classifier_name: String,
+    config: LeNetConfig,
+}
+
+impl FromConfig for LeNet {
+    type Config = LeNetConfig;
+
+    fn from_config(vs: &nn::Path, config: &LeNetConfig) -> anyhow::Result<Self> {
+        Ok(config.build(vs))
+    }
+
+    fn initialization(config: &LeNetConfig) -> Initialization {
+        config.init.clone()
+    }
+}
+
+impl ModuleT for LeNet {
+    /// Expects `[N, C, H, W]` images or `[N, C*H*W]` flattened ones
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let config = &self.config;
+        let xs = images(xs, config.in_channels, config.image_size);
+        let xs = self.convs.iter().fold(xs, |xs, (conv, norm, name)| {
+            let ys = conv.forward(&xs);
+            let ys = match norm {
+                Some(norm) => norm.forward_t(&ys, train),
+                None => ys,
+            };
+            let ys = config.activation.apply(&ys).max_pool2d_default(2);
+            trace(name, &ys);
+            ys
+        });
+        let xs = self
+            .hidden
+            .iter()
+            .fold(xs.flatten(1, -1), |xs, (fc, name)| {
+                let ys = config
+                    .activation
+                    .apply(&fc.forward(&xs))
+                    .dropout(config.dropout, train);
+                trace(name, &ys);
+                ys
+            });
+        let ys = self.classifier.forward(&xs);
+        trace(&self.classifier_name, &ys);
+        ys
+    }
+}
@@ -0,0 +1,133 @@
+mod lenet;
+mod resnet;
+
+pub use lenet::{LeNet, LeNetConfig};
+pub use resnet::{ResNet, ResNetConfig};
+
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, ModuleT},
+    Tensor,
+};
+use tch_utils::{config::FromConfig, init::Initialization};
+
+/// Configuration of any of the classifiers of the crate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CnnConfig {
+    LeNet(LeNetConfig),
+    ResNet(ResNetConfig),
+}
+
+impl From<LeNetConfig> for CnnConfig {
+    fn from(config: LeNetConfig) -> Self {
+        CnnConfig::LeNet(config)
+    }
+}
+
+impl From<ResNetConfig> for CnnConfig {
+    fn from(config: ResNetConfig) -> Self {
+        CnnConfig::ResNet(config)
+    }
+}
+
+/// Classifier whose architecture is chosen by its config
+#[derive(Debug)]
+pub enum Cnn {
+    LeNet(Box<LeNet>),
+    ResNet(Box<ResNet>),
+}
+
+impl FromConfig for Cnn {
+    type Config = CnnConfig;
+
+    fn from_config(vs: &nn::Path, config: &CnnConfig) -> anyhow::Result<Self> {
+        Ok(match config {
+            CnnConfig::LeNet(config) => Cnn::LeNet(Box::new(LeNet::from_config(vs, config)?)),
+            CnnConfig::ResNet(config) => Cnn::ResNet(Box::new(ResNet::from_config(vs, config)?)),
+        })
+    }
+
+    fn initialization(config: &CnnConfig) -> Initialization {
+        match config {
+            CnnConfig::LeNet(config) => LeNet::initialization(config),
+            CnnConfig::ResNet(config) => ResNet::initialization(config),
+        }
+    }
+}
+
+impl ModuleT for Cnn {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        match self {
+            Cnn::LeNet(lenet) => lenet.forward_t(xs, train),
+            Cnn::ResNet(resnet) => resnet.forward_t(xs, train),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{self, ModuleT},
+        Device, Kind, Tensor,
+    };
+    use tch_utils::{
+        config::{build_model, checkpoint_round_trip},
+        init::Initialization,
+        layers::Normalization,
+    };
+
+    use crate::{Cnn, CnnConfig, LeNetConfig, ResNetConfig};
+
+    #[test]
+    fn lenet() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let lenet = LeNetConfig::new(1, 10, 28)
+            .normalization(Normalization::Batch)
+            .dropout(0.5)
+            .build(&vs.root());
+
+        let x = Tensor::rand(&[2, 784], (Kind::Float, Device::Cpu));
+        assert_eq!(lenet.forward_t(&x, true).size(), vec![2, 10]);
+        let x = Tensor::rand(&[3, 1, 28, 28], (Kind::Float, Device::Cpu));
+        assert_eq!(lenet.forward_t(&x, false).size(), vec![3, 10]);
+
+        // 28 -> 14 -> 7 after the two stages
+        let variables = vs.variables();
+        assert_eq!(variables["fc0.weight"].size(), vec![120, 16 * 7 * 7]);
+        assert_eq!(variables["classifier.weight"].size(), vec![10, 84]);
+    }
+
+    #[test]
+    fn resnet() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = ResNetConfig::new(1, 10, 28)
+            .widths(vec![8, 16])
+            .init(Initialization::new().zero_residual(true));
+        let resnet = build_model::<Cnn>(&vs, &config.into()).unwrap();
+
+        let x = Tensor::rand(&[2, 784], (Kind::Float, Device::Cpu));
+        assert_eq!(resnet.forward_t(&x, true).size(), vec![2, 10]);
+
+        let variables = vs.variables();
+        assert!(variables.contains_key("stage1.block0.skip.weight"));
+        assert!(!variables.contains_key("stage1.block1.skip.weight"));
+        assert_eq!(
+            f64::from(
+                variables["stage0.block1.conv1.weight"]
+                    .abs()
+                    .sum(Kind::Float)
+            ),
+            0.0
+        );
+    }
+
+    #[test]
+    fn checkpoint() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = CnnConfig::from(LeNetConfig::new(1, 10, 28).channels(vec![4, 8, 8]));
+        let lenet = build_model::<Cnn>(&vs, &config).unwrap();
+        let x = Tensor::rand(&[2, 1, 28, 28], (Kind::Float, Device::Cpu));
+        let loaded = checkpoint_round_trip(&vs, &lenet, &config, &x);
+        assert!(matches!(loaded, Cnn::LeNet(_)));
+    }
+}
@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, Conv2D, ConvConfig, Module, ModuleT},
+    Tensor,
+};
+use tch_utils::{
+    config::FromConfig,
+    init::Initialization,
+    layers::{normalize, Activation, Norm, Normalization},
+    summary::{module_name, trace},
+    tensor::images,
+};
+
+/// Configuration of a small [`ResNet`] (He et al. 2015) : a 3x3 stem followed by stages of basic residual blocks,
+/// a global average pooling and a linear classifier.
+///
+/// The variables are named `stem`, `stem_norm`, `stage{s}.block{b}` and `classifier`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResNetConfig {
+    pub in_channels: i64,
+    pub classes: i64,
+    /// Size of the (square) input images, used to unflatten the inputs
+    pub image_size: i64,
+    /// Chanels of each stage, the resolution is halved at the start of every stage but the first one
+    pub widths: Vec<i64>,
+    /// Number of residual blocks per stage
+    pub blocks: usize,
+    pub activation: Activation,
+    pub normalization: Normalization,
+    #[serde(default)]
+    pub init: Initialization,
+}
+
+impl ResNetConfig {
+    /// Three stages of 16, 32 and 64 chanels with two blocks each and batch normalization
+    pub fn new(in_channels: i64, classes: i64, image_size: i64) -> Self {
+        Self {
+            in_channels,
+            classes,
+            image_size,
+            widths: vec![16, 32, 64],
+            blocks: 2,
+            activation: Activation::ReLU,
+            normalization: Normalization::Batch,
+            init: Initialization::default(),
+        }
+    }
+
+    pub fn widths(mut self, widths: Vec<i64>) -> Self {
+        self.widths = widths;
+        self
+    }
+
+    pub fn blocks(mut self, blocks: usize) -> Self {
+        self.blocks = blocks;
+        self
+    }
+
+    pub fn activation(mut self, activation: Activation) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    pub fn init(mut self, init: Initialization) -> Self {
+        self.init = init;
+        self
+    }
+
+    pub fn build(&self, vs: &nn::Path) -> ResNet {
+        assert!(!self.widths.is_empty(), "Expected at least one stage");
+        assert!(self.blocks > 0, "Expected at least one block per stage");
+
+        let stem = conv3x3(&(vs / "stem"), self.in_channels, self.widths[0], 1, self);
+        let stem_norm = self
+            .normalization
+            .build(&(vs / "stem_norm"), self.widths[0], 2);
+
+        let mut previous = self.widths[0];
+        let mut blocks = vec![];
+        for (s, width) in self.widths.iter().enumerate() {
+            let stage = vs / format!("stage{s}");
+            for b in 0..self.blocks {
+                let stride = if s > 0 && b == 0 { 2 } else { 1 };
+                blocks.push(BasicBlock::new(
+                    &(&stage / format!("block{b}")),
+                    previous,
+                    *width,
+                    stride,
+                    self,
+                ));
+                previous = *width;
+            }
+        }
+        let classifier = nn::linear(
+            vs / "classifier",
+            previous,
+            self.classes,
+            Default::default(),
+        );
+
+        ResNet {
+            stem,
+            stem_norm,
+            stem_name: module_name(&(vs / "stem")),
+            blocks,
+            classifier,
+            classifier_name: module_name(&(vs / "classifier")),
+            config: self.clone(),
+        }
+    }
+}
+
+/// The biases are redundant with the normalization
+fn conv3x3(
+    vs: &nn::Path,
+    in_channels: i64,
+    out_channels: i64,
+    stride: i64,
+    config: &ResNetConfig,
+) -> Conv2D {
+    nn::conv2d(
+        vs,
+        in_channels,
+        out_channels,
+        3,
+        ConvConfig {
+            stride,
+            padding: 1,
+            bias: config.normalization == Normalization::None,
+            ..Default::default()
+        },
+    )
+}
+
+/// Two 3x3 convolutions (`conv0`, `conv1`) with a shortcut, projected by a strided 1x1 convolution (`skip`)
+/// when the resolution or the number of chanels changes
+#[derive(Debug)]
+struct BasicBlock {
+    conv0: Conv2D,
+    norm0: Option<Norm>,
+    conv1: Conv2D,
+    norm1: Option<Norm>,
+    skip: Option<(Conv2D, Option<Norm>)>,
+    activation: Activation,
+    name: String,
+}
+
+impl BasicBlock {
+    fn new(
+        vs: &nn::Path,
+        in_channels: i64,
+        out_channels: i64,
+        stride: i64,
+        config: &ResNetConfig,
+    ) -> Self {
+        let skip = if stride != 1 || in_channels != out_channels {
+            let conv = nn::conv2d(
+                vs / "skip",
+                in_channels,
+                out_channels,
+                1,
+                ConvConfig {
+                    stride,
+                    bias: config.normalization == Normalization::None,
+                    ..Default::default()
+                },
+            );
+            let norm = config
+                .normalization
+                .build(&(vs / "skip_norm"), out_channels, 2);
+            Some((conv, norm))
+        } else {
+            None
+        };
+        Self {
+            conv0: conv3x3(&(vs / "conv0"), in_channels, out_channels, stride, config),
+            norm0: config.normalization.build(&(vs / "norm0"), out_channels, 2),
+            conv1: conv3x3(&(vs / "conv1"), out_channels, out_channels, 1, config),
+            norm1: config.normalization.build(&(vs / "norm1"), out_channels, 2),
+            skip,
+            activation: config.activation,
+            name: module_name(vs),
+        }
+    }
+}
+
+impl ModuleT for BasicBlock {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let ys = normalize(&self.norm0, self.conv0.forward(xs), train);
+        let ys = normalize(
+            &self.norm1,
+            self.conv1.forward(&self.activation.apply(&ys)),
+            train,
+        );
+        let shortcut = match &self.skip {
+            Some((conv, norm)) => normalize(norm, conv.forward(xs), train),
+            None => xs.shallow_clone(),
+        };
+        let ys = self.activation.apply(&(ys + shortcut));
+        trace(&self.name, &ys);
+        ys
+    }
+}
+
+#[derive(Debug)]
+pub struct ResNet {
+    stem: Conv2D,
+    stem_norm: Option<Norm>,
+    stem_name: String,
+    blocks: Vec<BasicBlock>,
+    classifier: nn::Linear,
+    classifier_name: String,
+    config: ResNetConfig,
+}
+
+impl FromConfig for ResNet {
+    type Config = ResNetConfig;
+
+    fn from_config(vs: &nn::Path, config: &ResNetConfig) -> anyhow::Result<Self> {
+        Ok(config.build(vs))
+    }
+
+    /// The last convolution of the blocks is zeroed with `zero_residual` so that they start as identities
+    fn initialization(config: &ResNetConfig) -> Initialization {
+        let init = config.init.clone();
+        if init.zero_residual {
+            init.zeros("stage*.block*.conv1")
+        } else {
+            init
+        }
+    }
+}
+
+impl ModuleT for ResNet {
+    /// Expects `[N, C, H, W]` images or `[N, C*H*W]` flattened ones
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let config = &self.config;
+        let xs = images(xs, config.in_channels, config.image_size);
+        let xs = normalize(&self.stem_norm, self.stem.forward(&xs), train);
+        let xs = config.activation.apply(&xs);
+        trace(&self.stem_name, &xs);
+        let xs = self
+            .blocks
+            .iter()
+            .fold(xs, |xs, block| block.forward_t(&xs, train));
+        let ys = self
+            .classifier
+            .forward(&xs.adaptive_avg_pool2d(&[1, 1]).flatten(1, -1));
+        trace(&self.classifier_name, &ys);
+        ys
+    }
+}
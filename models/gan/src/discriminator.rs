@@ -0,0 +1,98 @@
+use tch::{
+    nn::{self, Conv2D, ConvConfig, Module, ModuleT},
+    Tensor,
+};
+use tch_utils::{
+    config::FromConfig,
+    init::Initialization,
+    layers::{normalize, Norm, Normalization},
+    summary::{module_name, trace},
+    tensor::images,
+};
+
+use crate::GanConfig;
+
+/// Discriminator of a DCGAN : strided convolutions (`layer{i}`, normalized by `norm{i}` but the first one)
+/// with leaky ReLUs followed by a linear layer (`output`) giving the logit of the image being real.
+#[derive(Debug)]
+pub struct Discriminator {
+    layers: Vec<(Conv2D, Option<Norm>, String)>,
+    output: nn::Linear,
+    output_name: String,
+    config: GanConfig,
+}
+
+impl Discriminator {
+    pub fn new(vs: &nn::Path, config: &GanConfig) -> anyhow::Result<Self> {
+        let size = config.resolution()?;
+        let mut previous = config.channels;
+        let layers = config
+            .widths
+            .iter()
+            .enumerate()
+            .map(|(i, width)| {
+                let first = i == 0;
+                let layer_vs = vs / format!("layer{i}");
+                let conv = nn::conv2d(
+                    &layer_vs,
+                    previous,
+                    *width,
+                    4,
+                    ConvConfig {
+                        stride: 2,
+                        padding: 1,
+                        bias: first || config.normalization == Normalization::None,
+                        ..Default::default()
+                    },
+                );
+                let norm = if first {
+                    None
+                } else {
+                    config
+                        .normalization
+                        .build(&(vs / format!("norm{i}")), *width, 2)
+                };
+                previous = *width;
+                (conv, norm, module_name(&layer_vs))
+            })
+            .collect();
+        let output = nn::linear(vs / "output", previous * size * size, 1, Default::default());
+
+        Ok(Self {
+            layers,
+            output,
+            output_name: module_name(&(vs / "output")),
+            config: config.clone(),
+        })
+    }
+}
+
+impl FromConfig for Discriminator {
+    type Config = GanConfig;
+
+    fn from_config(vs: &nn::Path, config: &GanConfig) -> anyhow::Result<Self> {
+        Discriminator::new(vs, config)
+    }
+
+    fn initialization(config: &GanConfig) -> Initialization {
+        config.init.clone()
+    }
+}
+
+impl ModuleT for Discriminator {
+    /// Logits `[N]` of `[N, C, H, W]` images (or `[N, C*H*W]` flattened ones)
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let config = &self.config;
+        let xs = images(xs, config.channels, config.image_size);
+        let xs = self.layers.iter().fold(xs, |xs, (conv, norm, name)| {
+            let ys = conv.forward(&xs);
+            let ys = normalize(norm, ys, train);
+            let ys = ys.maximum(&(&ys * config.negative_slope));
+            trace(name, &ys);
+            ys
+        });
+        let ys = self.output.forward(&xs.flatten(1, -1)).squeeze_dim(-1);
+        trace(&self.output_name, &ys);
+        ys
+    }
+}
@@ -0,0 +1,128 @@
+use tch::{
+    nn::{self, ConvTranspose2D, ConvTransposeConfig, Module, ModuleT},
+    Device, Kind, Tensor,
+};
+use tch_utils::{
+    config::FromConfig,
+    init::Initialization,
+    layers::{normalize, Norm, Normalization},
+    summary::{module_name, trace},
+};
+
+use crate::GanConfig;
+
+/// Generator of a DCGAN : the latent vectors are projected (`input`) to the lowest resolution
+/// and up-sampled by transposed convolutions (`layer{i}`, normalized by `norm{i}`) to `[-1, 1]` images.
+#[derive(Debug)]
+pub struct Generator {
+    input: nn::Linear,
+    input_norm: Option<Norm>,
+    input_name: String,
+    layers: Vec<(ConvTranspose2D, Option<Norm>, String)>,
+    /// Resolution of the projected latent vectors
+    size: i64,
+    config: GanConfig,
+}
+
+impl Generator {
+    pub fn new(vs: &nn::Path, config: &GanConfig) -> anyhow::Result<Self> {
+        let size = config.resolution()?;
+        let widths = &config.widths;
+        let deepest = widths[widths.len() - 1];
+
+        let input = nn::linear(
+            vs / "input",
+            config.latent_dim,
+            deepest * size * size,
+            Default::default(),
+        );
+        let input_norm = config.normalization.build(&(vs / "input_norm"), deepest, 2);
+
+        let layers = (0..widths.len())
+            .rev()
+            .enumerate()
+            .map(|(layer, i)| {
+                let last = i == 0;
+                let out_channels = if last { config.channels } else { widths[i - 1] };
+                let layer_vs = vs / format!("layer{layer}");
+                let conv = nn::conv_transpose2d(
+                    &layer_vs,
+                    widths[i],
+                    out_channels,
+                    4,
+                    ConvTransposeConfig {
+                        stride: 2,
+                        padding: 1,
+                        bias: last || config.normalization == Normalization::None,
+                        ..Default::default()
+                    },
+                );
+                let norm = if last {
+                    None
+                } else {
+                    config
+                        .normalization
+                        .build(&(vs / format!("norm{layer}")), out_channels, 2)
+                };
+                (conv, norm, module_name(&layer_vs))
+            })
+            .collect();
+
+        Ok(Self {
+            input,
+            input_norm,
+            input_name: module_name(&(vs / "input")),
+            layers,
+            size,
+            config: config.clone(),
+        })
+    }
+
+    pub fn latent_dim(&self) -> i64 {
+        self.config.latent_dim
+    }
+
+    /// Generates `count` images from latent vectors drawn from `N(0, I)`
+    pub fn sample(&self, count: i64, device: Device) -> Tensor {
+        let z = Tensor::randn(&[count, self.config.latent_dim], (Kind::Float, device));
+        self.forward_t(&z, false)
+    }
+}
+
+impl FromConfig for Generator {
+    type Config = GanConfig;
+
+    fn from_config(vs: &nn::Path, config: &GanConfig) -> anyhow::Result<Self> {
+        Generator::new(vs, config)
+    }
+
+    fn initialization(config: &GanConfig) -> Initialization {
+        config.init.clone()
+    }
+}
+
+impl ModuleT for Generator {
+    /// Images `[N, C, H, W]` in `[-1, 1]` of `[N, latent_dim]` latent vectors
+    fn forward_t(&self, z: &Tensor, train: bool) -> Tensor {
+        let size = self.size;
+        let deepest = self.config.widths[self.config.widths.len() - 1];
+        let xs = self.input.forward(z).view([-1, deepest, size, size]);
+        let xs = normalize(&self.input_norm, xs, train).relu();
+        trace(&self.input_name, &xs);
+
+        let last = self.layers.len() - 1;
+        self.layers
+            .iter()
+            .enumerate()
+            .fold(xs, |xs, (i, (conv, norm, name))| {
+                let ys = conv.forward(&xs);
+                let ys = if i == last {
+                    ys.tanh()
+                } else {
+                    normalize(norm, ys, train).relu()
+                };
+                trace(name, &ys);
+                ys
+            })
+    }
+}
@@ -0,0 +1,211 @@
+mod discriminator;
+mod generator;
+
+pub use discriminator::Discriminator;
+pub use generator::Generator;
+
+use anyhow::bail;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use tch::{Reduction, Tensor};
+use tch_utils::{
+    init::{InitScheme, Initialization},
+    layers::Normalization,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+enum ConfigError {
+    #[error("Expected at least one layer")]
+    NoLayer,
+    #[error("The image size ({0}) should be a multiple of {1}")]
+    ImageSize(i64, i64),
+}
+
+/// Configuration shared by the [`Generator`] and the [`Discriminator`] of a DCGAN (Radford et al. 2015).
+///
+/// The discriminator halves the resolution with a strided 4x4 convolution for each width,
+/// the generator mirrors it with transposed convolutions.
+/// Both are built from the same config but in their own `VarStore` so that they get their own optimizer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GanConfig {
+    /// Size of the latent vectors
+    pub latent_dim: i64,
+    /// Chanels of the images
+    pub channels: i64,
+    /// Size of the (square) images, it should be a multiple of 2^widths.len()
+    pub image_size: i64,
+    /// Chanels of the convolutions of the discriminator, from the images to the lowest resolution
+    pub widths: Vec<i64>,
+    /// Normalization of the hidden layers, the first layer of the discriminator and the output of the generator are not normalized
+    pub normalization: Normalization,
+    /// Slope of the leaky ReLU of the discriminator
+    pub negative_slope: f64,
+    #[serde(default)]
+    pub init: Initialization,
+}
+
+impl GanConfig {
+    /// DCGAN like configuration : latent vectors of size 64, 64 and 128 chanels, batch normalization
+    /// and weights drawn from `N(0, 0.02^2)`
+    pub fn new(channels: i64, image_size: i64) -> Self {
+        Self {
+            latent_dim: 64,
+            channels,
+            image_size,
+            widths: vec![64, 128],
+            normalization: Normalization::Batch,
+            negative_slope: 0.2,
+            init: Initialization::new()
+                .weights("*", InitScheme::Normal(0.02))
+                .bias("*", 0.0),
+        }
+    }
+
+    pub fn latent_dim(mut self, latent_dim: i64) -> Self {
+        self.latent_dim = latent_dim;
+        self
+    }
+
+    pub fn widths(mut self, widths: Vec<i64>) -> Self {
+        self.widths = widths;
+        self
+    }
+
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    pub fn init(mut self, init: Initialization) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// Size of the feature maps at the lowest resolution
+    fn resolution(&self) -> anyhow::Result<i64> {
+        if self.widths.is_empty() {
+            bail!(ConfigError::NoLayer);
+        }
+        let scale = 1 << self.widths.len();
+        if self.image_size % scale != 0 {
+            bail!(ConfigError::ImageSize(self.image_size, scale));
+        }
+        Ok(self.image_size / scale)
+    }
+}
+
+/// Latent vectors `[count, latent_dim]` drawn from `N(0, I)` on the cpu with the given seed,
+/// so that the samples of different epochs or runs can be compared.
+/// The values are drawn from their own generator (Box-Muller), the one of torch is left untouched.
+pub fn fixed_latents(count: i64, latent_dim: i64, seed: u64) -> Tensor {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let z: Vec<f32> = (0..count * latent_dim)
+        .map(|_| {
+            // 1 - u is in ]0, 1] so that the log is finite
+            let u: f32 = 1.0 - rng.gen::<f32>();
+            let v: f32 = rng.gen();
+            (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+        })
+        .collect();
+    Tensor::of_slice(&z).view([count, latent_dim])
+}
+
+fn binary_cross_entropy(logits: &Tensor, target: f64) -> Tensor {
+    logits.binary_cross_entropy_with_logits::<Tensor>(
+        &logits.full_like(target),
+        None,
+        None,
+        Reduction::Mean,
+    )
+}
+
+/// Loss of the discriminator for the logits of real and generated images, labeled 1 and 0
+pub fn discriminator_loss(real: &Tensor, fake: &Tensor) -> Tensor {
+    binary_cross_entropy(real, 1.0) + binary_cross_entropy(fake, 0.0)
+}
+
+/// Non-saturating loss of the generator : the generated images should be classified as real
+pub fn generator_loss(fake: &Tensor) -> Tensor {
+    binary_cross_entropy(fake, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{self, ModuleT},
+        Device, Kind, Tensor,
+    };
+    use tch_utils::config::{build_model, checkpoint_round_trip};
+
+    use crate::{
+        discriminator_loss, fixed_latents, generator_loss, Discriminator, GanConfig, Generator,
+    };
+
+    #[test]
+    fn dcgan() {
+        let config = GanConfig::new(1, 28).latent_dim(8).widths(vec![4, 8]);
+        let g_vs = nn::VarStore::new(Device::Cpu);
+        let generator = build_model::<Generator>(&g_vs, &config).unwrap();
+        let d_vs = nn::VarStore::new(Device::Cpu);
+        let discriminator = build_model::<Discriminator>(&d_vs, &config).unwrap();
+
+        let z = Tensor::randn(&[3, 8], (Kind::Float, Device::Cpu));
+        let images = generator.forward_t(&z, true);
+        assert_eq!(images.size(), vec![3, 1, 28, 28]);
+        assert!(f64::from(images.abs().max()) <= 1.0);
+        assert_eq!(discriminator.forward_t(&images, true).size(), vec![3]);
+        // The flattened MNIST images are accepted
+        let x = Tensor::rand(&[2, 784], (Kind::Float, Device::Cpu));
+        assert_eq!(discriminator.forward_t(&x, false).size(), vec![2]);
+
+        // 28 -> 14 -> 7
+        let variables = d_vs.variables();
+        assert_eq!(variables["output.weight"].size(), vec![1, 8 * 7 * 7]);
+        assert!(!variables.contains_key("norm0.weight"));
+        assert!(f64::from(g_vs.variables()["layer0.weight"].std(true)) < 0.03);
+    }
+
+    #[test]
+    fn losses() {
+        let confident = Tensor::full(&[4], 20.0, (Kind::Float, Device::Cpu));
+        assert!(f64::from(discriminator_loss(&confident, &-&confident)) < 1e-6);
+        assert!(f64::from(generator_loss(&-&confident)) > 10.0);
+        let unsure = Tensor::zeros(&[4], (Kind::Float, Device::Cpu));
+        let loss = f64::from(discriminator_loss(&unsure, &unsure));
+        assert!((loss - 2.0 * 2f64.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn latents() {
+        assert!(fixed_latents(4, 8, 1).equal(&fixed_latents(4, 8, 1)));
+        assert!(!fixed_latents(4, 8, 1).equal(&fixed_latents(4, 8, 2)));
+        // The random generator of torch is not reseeded
+        tch::manual_seed(0);
+        let x = Tensor::randn(&[4], (Kind::Float, Device::Cpu));
+        tch::manual_seed(0);
+        let _ = fixed_latents(4, 8, 1);
+        assert!(x.equal(&Tensor::randn(&[4], (Kind::Float, Device::Cpu))));
+
+        let z = fixed_latents(100, 100, 3);
+        assert!(f64::from(z.mean(Kind::Float)).abs() < 0.05);
+        assert!((f64::from(z.std(true)) - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn image_size() {
+        let config = GanConfig::new(1, 30).widths(vec![4, 8]);
+        let vs = nn::VarStore::new(Device::Cpu);
+        assert!(build_model::<Generator>(&vs, &config).is_err());
+        assert!(build_model::<Discriminator>(&vs, &config.widths(vec![])).is_err());
+    }
+
+    #[test]
+    fn checkpoint() {
+        let config = GanConfig::new(1, 16).latent_dim(4).widths(vec![4, 8]);
+        let vs = nn::VarStore::new(Device::Cpu);
+        let generator = build_model::<Generator>(&vs, &config).unwrap();
+        checkpoint_round_trip(&vs, &generator, &config, &fixed_latents(2, 4, 0));
+    }
+}
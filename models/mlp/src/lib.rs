@@ -1,11 +1,175 @@
 mod tests;
 
-use tch::{nn, Tensor};
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, Module, ModuleT},
+    Tensor,
+};
+use tch_utils::{
+    config::FromConfig,
+    init::Initialization,
+    layers::{Activation, Norm, Normalization},
+    summary::{module_name, trace},
+    types::{input, MultiModuleT, NamedTensors},
+};
+
+/// Configuration of a [`MLP`].
+///
+/// The hidden layer `i` is stored under `layer{i}` and the output layer under `layer{n}`
+/// (`n` being the number of hidden layers) so that the weights of [`MLP::new`] can still be loaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MlpConfig {
+    pub in_features: i64,
+    pub out_features: i64,
+    pub hidden: Vec<i64>,
+    pub activation: Activation,
+    pub dropout: f64,
+    pub normalization: Normalization,
+    pub residual: bool,
+    /// Initialization of the layers, relative to the MLP (e.g. `layer0`)
+    #[serde(default)]
+    pub init: Initialization,
+}
+
+impl MlpConfig {
+    pub fn new(in_features: u32, out_features: u32) -> Self {
+        Self {
+            in_features: in_features as i64,
+            out_features: out_features as i64,
+            hidden: vec![128],
+            activation: Activation::ReLU,
+            dropout: 0.0,
+            normalization: Normalization::None,
+            residual: false,
+            init: Initialization::default(),
+        }
+    }
+
+    /// Width of each hidden layer
+    pub fn hidden(mut self, hidden: Vec<i64>) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    pub fn activation(mut self, activation: Activation) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    /// Dropout probability applied after each hidden layer during the training
+    pub fn dropout(mut self, dropout: f64) -> Self {
+        self.dropout = dropout;
+        self
+    }
+
+    /// Normalization applied before the activation of each hidden layer (e.g. batch or layer normalization)
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Adds the input of the hidden layers to their output when they have the same width
+    pub fn residual(mut self, residual: bool) -> Self {
+        self.residual = residual;
+        self
+    }
+
+    pub fn init(mut self, init: Initialization) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// Indices of the hidden layers with a residual connection
+    fn residual_layers(&self) -> Vec<usize> {
+        if !self.residual {
+            return vec![];
+        }
+        let inputs = std::iter::once(self.in_features).chain(self.hidden.iter().copied());
+        inputs
+            .zip(self.hidden.iter())
+            .enumerate()
+            .filter(|(_, (input, width))| input == *width)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn build(&self, vs: &nn::Path) -> MLP {
+        assert!(self.in_features > 0, "in_features should be above 0");
+        assert!(self.out_features > 0, "out_features should be above 0");
+        assert!(
+            !self.hidden.is_empty(),
+            "Expected at least one hidden layer"
+        );
+        assert!(
+            self.hidden.iter().all(|width| *width > 0),
+            "The hidden widths should be above 0"
+        );
+        assert!(
+            (0.0..1.0).contains(&self.dropout),
+            "dropout should be in [0, 1)"
+        );
+        assert!(
+            self.normalization != Normalization::Instance,
+            "Instance normalization needs spatial dimentions, the MLP has none"
+        );
+
+        let residual_layers = self.residual_layers();
+        let mut previous = self.in_features;
+        let layers = self
+            .hidden
+            .iter()
+            .enumerate()
+            .map(|(i, width)| {
+                let linear = nn::linear(
+                    vs / format!("layer{i}"),
+                    previous,
+                    *width,
+                    Default::default(),
+                );
+                let norm = self
+                    .normalization
+                    .build(&(vs / format!("norm{i}")), *width, 0);
+                previous = *width;
+                HiddenLayer {
+                    linear,
+                    norm,
+                    residual: residual_layers.contains(&i),
+                    name: module_name(&(vs / format!("layer{i}"))),
+                }
+            })
+            .collect();
+
+        let output_vs = vs / format!("layer{}", self.hidden.len());
+        let output = nn::linear(&output_vs, previous, self.out_features, Default::default());
+        MLP {
+            layers,
+            output,
+            output_name: module_name(&output_vs),
+            activation: self.activation,
+            dropout: self.dropout,
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct MLP(nn::Sequential);
+struct HiddenLayer {
+    linear: nn::Linear,
+    norm: Option<Norm>,
+    residual: bool,
+    name: String,
+}
+
+#[derive(Debug)]
+pub struct MLP {
+    layers: Vec<HiddenLayer>,
+    output: nn::Linear,
+    output_name: String,
+    activation: Activation,
+    dropout: f64,
+}
 
 impl MLP {
+    /// MLP with `layer_count` ReLU hidden layers of `hidden_nodes` nodes
     pub fn new(
         vs: &nn::Path,
         in_feature: u32,
@@ -13,45 +177,63 @@ impl MLP {
         hidden_nodes: u32,
         layer_count: u32,
     ) -> Self {
-        let seq = nn::seq();
-        let in_layer = nn::linear(
-            vs / "layer0",
-            in_feature as i64,
-            hidden_nodes as i64,
-            Default::default(),
-        );
-
-        assert!(in_feature > 0, "in_features should be above 0");
-        assert!(out_feature > 0, "out_features should be above 0");
         assert!(hidden_nodes > 0, "hidden_nodes should be above 0");
         assert!(layer_count > 0, "layer_count should be above 0");
+        MlpConfig::new(in_feature, out_feature)
+            .hidden(vec![hidden_nodes as i64; layer_count as usize])
+            .build(vs)
+    }
+}
 
-        let seq = seq.add(in_layer);
-        let seq = seq.add_fn(Tensor::relu);
+impl FromConfig for MLP {
+    type Config = MlpConfig;
 
-        let inter_layers = (1..layer_count).into_iter().map(|i| {
-            nn::linear(
-                vs / format!("layer{}", i),
-                hidden_nodes as i64,
-                hidden_nodes as i64,
-                Default::default(),
-            )
-        });
+    fn from_config(vs: &nn::Path, config: &MlpConfig) -> anyhow::Result<Self> {
+        Ok(config.build(vs))
+    }
 
-        let seq = inter_layers.fold(seq, |seq, lin| seq.add(lin).add_fn(Tensor::relu));
+    fn initialization(config: &MlpConfig) -> Initialization {
+        let init = config.init.clone();
+        if !init.zero_residual {
+            return init;
+        }
+        config
+            .residual_layers()
+            .into_iter()
+            .fold(init, |init, i| init.zeros(&format!("layer{i}")))
+    }
+}
 
-        let out_layer = nn::linear(
-            vs / format!("layer{}", layer_count),
-            hidden_nodes as i64,
-            out_feature as i64,
-            Default::default(),
-        );
-        MLP(seq.add(out_layer))
+impl nn::ModuleT for MLP {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let xs = self.layers.iter().fold(xs.shallow_clone(), |xs, layer| {
+            let ys = layer.linear.forward(&xs);
+            let ys = match &layer.norm {
+                Some(norm) => norm.forward_t(&ys, train),
+                None => ys,
+            };
+            let ys = self.activation.apply(&ys).dropout(self.dropout, train);
+            let ys = if layer.residual { ys + xs } else { ys };
+            trace(&layer.name, &ys);
+            ys
+        });
+        let ys = self.output.forward(&xs);
+        trace(&self.output_name, &ys);
+        ys
     }
 }
 
-impl nn::Module for MLP {
-    fn forward(&self, xs: &Tensor) -> Tensor {
-        self.0.forward(xs)
+impl MultiModuleT for MLP {
+    fn input_names(&self) -> Vec<&str> {
+        vec!["input"]
+    }
+
+    fn output_names(&self) -> Vec<&str> {
+        vec!["logits"]
+    }
+
+    fn forward_multi_t(&self, inputs: &NamedTensors, train: bool) -> anyhow::Result<NamedTensors> {
+        let ys = self.forward_t(input(inputs, "input")?, train);
+        Ok(NamedTensors::from([("logits".to_string(), ys)]))
     }
 }
@@ -1,10 +1,16 @@
 #[cfg(test)]
 mod tests {
-    use crate::MLP;
+    use crate::{MlpConfig, MLP};
     use tch::{
-        nn::{self, Module},
+        nn::{self, ModuleT},
         Device, Kind, Tensor,
     };
+    use tch_utils::{
+        config::{build_model, checkpoint_round_trip},
+        init::{InitScheme, Initialization},
+        layers::{Activation, Normalization},
+        types::{MultiModuleT, NamedTensors},
+    };
 
     #[test]
     fn create() {
@@ -29,11 +35,115 @@ mod tests {
         let mlp = MLP::new(&(&vs.root() / "model"), 4, 3, 5, 1);
 
         let x = Tensor::rand(&[2, 4], (Kind::Float, Device::Cpu));
-        let y = mlp.forward(&x);
+        let y = mlp.forward_t(&x, false);
 
         assert_eq!(y.size(), vec![2, 3], "expected size [2,3] ");
     }
 
+    #[test]
+    fn config() {
+        // The names of the variables are the ones of `MLP::new`
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _ = MLP::new(&vs.root(), 4, 3, 5, 2);
+        let mut names: Vec<_> = vs.variables().into_keys().collect();
+        names.sort();
+        let config = nn::VarStore::new(Device::Cpu);
+        let _ = MlpConfig::new(4, 3)
+            .hidden(vec![5, 5])
+            .build(&config.root());
+        let mut config_names: Vec<_> = config.variables().into_keys().collect();
+        config_names.sort();
+        assert_eq!(names, config_names);
+
+        let vs = nn::VarStore::new(Device::Cpu);
+        let mlp = MlpConfig::new(4, 3)
+            .hidden(vec![8, 8, 6])
+            .activation(Activation::GELU)
+            .normalization(Normalization::Batch)
+            .dropout(0.5)
+            .residual(true)
+            .build(&vs.root());
+        assert!(vs.variables().contains_key("norm1.running_mean"));
+        assert!(vs.variables().contains_key("layer3.weight"));
+
+        let x = Tensor::rand(&[16, 4], (Kind::Float, Device::Cpu));
+        assert_eq!(mlp.forward_t(&x, true).size(), vec![16, 3]);
+    }
+
+    #[test]
+    fn dropout() {
+        let config = MlpConfig::new(4, 3).hidden(vec![32, 32]);
+        let vs = nn::VarStore::new(Device::Cpu);
+        let mlp = config.clone().dropout(0.5).build(&vs.root());
+        let mut reference_vs = nn::VarStore::new(Device::Cpu);
+        let reference = config.build(&reference_vs.root());
+        reference_vs.copy(&vs).unwrap();
+
+        let x = Tensor::rand(&[16, 4], (Kind::Float, Device::Cpu));
+        // The dropout draws new masks for each training pass and is disabled in evaluation mode
+        assert!(!mlp.forward_t(&x, true).equal(&mlp.forward_t(&x, true)));
+        assert!(mlp
+            .forward_t(&x, false)
+            .equal(&reference.forward_t(&x, false)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Instance normalization")]
+    fn instance_normalization() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _ = MlpConfig::new(4, 3)
+            .normalization(Normalization::Instance)
+            .build(&vs.root());
+    }
+
+    #[test]
+    fn initialization() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = MlpConfig::new(4, 3)
+            .hidden(vec![6, 6, 6])
+            .residual(true)
+            .init(
+                Initialization::new()
+                    .weights("*", InitScheme::XavierUniform)
+                    .zero_residual(true),
+            );
+        let _ = build_model::<MLP>(&vs, &config).unwrap();
+
+        // The residual layers start as identities
+        let variables = vs.variables();
+        for i in [1, 2] {
+            let ws = &variables[&format!("layer{i}.weight")];
+            assert_eq!(f64::from(ws.abs().sum(Kind::Float)), 0.0);
+        }
+        assert_ne!(
+            f64::from(variables["layer0.weight"].abs().sum(Kind::Float)),
+            0.0
+        );
+    }
+
+    #[test]
+    fn checkpoint() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = MlpConfig::new(4, 3)
+            .hidden(vec![7, 5])
+            .activation(Activation::Tanh);
+        let mlp = config.build(&vs.root());
+        let x = Tensor::rand(&[2, 4], (Kind::Float, Device::Cpu));
+        checkpoint_round_trip(&vs, &mlp, &config, &x);
+    }
+
+    #[test]
+    fn multi_input() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let mlp = MLP::new(&(&vs.root() / "model"), 4, 3, 5, 1);
+
+        let x = Tensor::rand(&[2, 4], (Kind::Float, Device::Cpu));
+        let inputs = NamedTensors::from([("input".to_string(), x.shallow_clone())]);
+        let outputs = mlp.forward_multi(&inputs).unwrap();
+
+        assert!(outputs["logits"].equal(&mlp.forward_t(&x, false)));
+    }
+
     #[test]
     fn forwarding_cuda() {
         if !tch::Cuda::is_available() {
@@ -45,7 +155,7 @@ mod tests {
         let mlp = MLP::new(&(&vs.root() / "model"), 4, 3, 5, 1);
 
         let x = Tensor::rand(&[2, 4], (Kind::Float, Device::Cuda(0)));
-        let y = mlp.forward(&x);
+        let y = mlp.forward_t(&x, false);
 
         assert_eq!(y.size(), vec![2, 3], "expected dim [2,3]");
     }
@@ -0,0 +1,353 @@
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, Conv2D, Module, ModuleT, Path},
+    Tensor,
+};
+use tch_utils::{
+    tensor::{center_crop, center_crop_like, pad_to_multiple},
+    types::FeatureExtractor,
+};
+use unet::block::{BlockProps, ConvBlock, UpSample, Upsampling};
+
+/// Configuration of a [`UNetPlusPlus`], mirrors [`unet::UnetProps`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnetPlusPlusProps {
+    pub node_block_convolutions: u32,
+    pub center_block_convolutions: u32,
+    /// Uses padded convolutions in the center and the nodes so that the output has the resolution of the input.
+    /// The input is padded to a multiple of 2^L and the output cropped back to the input size.
+    pub same_padding: bool,
+    /// Normalization, activation, dropout and residual connections of the center and the nodes
+    pub block: BlockProps,
+    /// Up-sampling of the nested skip pathways
+    pub upsampling: Upsampling,
+    /// Adds a segmentation head on every node of the first level, see [`UNetPlusPlus::forward_deep_t`]
+    pub deep_supervision: bool,
+}
+
+impl Default for UnetPlusPlusProps {
+    fn default() -> Self {
+        Self {
+            node_block_convolutions: 2,
+            center_block_convolutions: 2,
+            same_padding: false,
+            block: Default::default(),
+            upsampling: Upsampling::Transpose,
+            deep_supervision: false,
+        }
+    }
+}
+
+/// Node `X(i,j)` of the nested skip pathways
+#[derive(Debug)]
+struct Node {
+    up: UpSample,
+    block: ConvBlock,
+}
+
+/// UNet++ (Zhou et al. 2018) : a UNet whose skip connections are replaced by dense nested convolution blocks.
+///
+/// The node `X(i,j)` is at the level `i` and takes the outputs of `X(i,0..j)` and the up-sampled output of `X(i+1,j-1)`,
+/// `X(i,0)` being the feature maps of the encoder and `X(L,0)` the center.
+/// Its variables are named `decoder/node{i}_{j}`.
+#[derive(Debug)]
+pub struct UNetPlusPlus<E, const L: usize>
+where
+    E: FeatureExtractor<L>,
+{
+    encoder: E,
+    center: ConvBlock,
+    /// `nodes[i][j - 1]` is the node `X(i,j)`
+    nodes: Vec<Vec<Node>>,
+    /// Heads of the nodes `X(0,j)`, the last one is the classifier
+    heads: Vec<(usize, Conv2D)>,
+    same_padding: bool,
+    depth: usize,
+}
+
+impl<E, const L: usize> UNetPlusPlus<E, L>
+where
+    E: FeatureExtractor<L>,
+{
+    pub fn new(vs: &Path, encoder: E, class_count: u32, props: UnetPlusPlusProps) -> Self {
+        let conv_conf = nn::ConvConfig {
+            padding: if props.same_padding { 1 } else { 0 },
+            ..Default::default()
+        };
+
+        // Channels of the levels, the last one being the center
+        let mut chanels = encoder.chanels_count().to_vec();
+        chanels.push(chanels[L - 1] * 2);
+
+        // Creating the center convolutions
+        let center = ConvBlock::new(
+            &(vs / "center"),
+            chanels[L - 1],
+            chanels[L],
+            props.center_block_convolutions,
+            conv_conf,
+            props.block,
+        );
+
+        // Creating the nodes of the nested skip pathways
+        let decoder_vs = &(vs / "decoder");
+        let nodes = (0..L)
+            .map(|i| {
+                (1..=L - i)
+                    .map(|j| {
+                        let vs = decoder_vs / format!("node{i}_{j}");
+                        let up = UpSample::new(&vs, chanels[i + 1], chanels[i], props.upsampling);
+                        let block = ConvBlock::new(
+                            &vs,
+                            chanels[i] * (j as i64 + 1),
+                            chanels[i],
+                            props.node_block_convolutions,
+                            conv_conf,
+                            props.block,
+                        );
+                        Node { up, block }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // Creating the heads, the deepest node of the first level is classified by the classifier
+        let heads = (1..=L)
+            .filter(|j| props.deep_supervision || *j == L)
+            .map(|j| {
+                let name = if j == L {
+                    "classifier".to_string()
+                } else {
+                    format!("head{j}")
+                };
+                let head = nn::conv2d(
+                    &(vs / name),
+                    chanels[0],
+                    class_count as i64,
+                    1,
+                    Default::default(),
+                );
+                (j, head)
+            })
+            .collect();
+
+        Self {
+            encoder,
+            center,
+            nodes,
+            heads,
+            same_padding: props.same_padding,
+            depth: L,
+        }
+    }
+
+    /// Prunes the network at inference : only the nodes up to `X(0,depth)` are computed and
+    /// the output of its head is returned by `forward_t`.
+    /// Needs [`UnetPlusPlusProps::deep_supervision`] for a depth below L.
+    pub fn prune(&mut self, depth: usize) {
+        assert!(
+            (1..=L).contains(&depth),
+            "The depth should be in [1, {}] got {} instead",
+            L,
+            depth
+        );
+        assert!(
+            self.heads.iter().any(|(j, _)| *j == depth),
+            "The network was created without deep supervision and can't be pruned"
+        );
+        self.depth = depth;
+    }
+
+    /// Depth used by `forward_t`
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Computes the nodes `X(0,1..=depth)` of the first level
+    fn forward_nodes_t(&self, xs: &Tensor, train: bool, depth: usize) -> Vec<Tensor> {
+        assert!(
+            xs.dim() == 3 || xs.dim() == 4,
+            "Expected [C,W,H]/[B,C,W,H] shaped tensor got {:?} instead",
+            xs.size()
+        );
+
+        // Extracting features with the encoder
+        let (feature_maps, xs) = self.encoder.forward_extracts_t(xs, train);
+
+        // grid[i][j] is the output of the node X(i,j)
+        let mut grid: Vec<Vec<Tensor>> = feature_maps.into_iter().map(|fm| vec![fm]).collect();
+        if depth == L {
+            grid.push(vec![self.center.forward_t(&xs, train)]);
+        }
+
+        for j in 1..=depth {
+            for i in 0..=depth - j {
+                let node = &self.nodes[i][j - 1];
+
+                // Upsampling the node below and cropping the dense skip connections
+                let xt = node.up.forward(&grid[i + 1][j - 1]);
+                let mut inputs: Vec<_> = grid[i]
+                    .iter()
+                    .map(|xs| center_crop_like(xs, &xt, 2))
+                    .collect();
+                inputs.push(xt);
+                let stacked = Tensor::cat(&inputs, -3);
+
+                let ys = node.block.forward_t(&stacked, train);
+                grid[i].push(ys);
+            }
+        }
+        grid.swap_remove(0).split_off(1)
+    }
+
+    /// Runs the heads of the nodes `X(0,j)` for `j` in `depths`, the outputs are in the same order
+    fn forward_heads_t(&self, xs: &Tensor, train: bool, depths: &[usize]) -> Vec<Tensor> {
+        // Padding the input so that every level of the encoder halves the resolution exactly
+        let size = xs.size();
+        let input = if self.same_padding {
+            pad_to_multiple(xs, 2_i64.pow(L as u32), 2)
+        } else {
+            xs.shallow_clone()
+        };
+
+        let max_depth = *depths.iter().max().expect("Expected at least one depth");
+        let nodes = self.forward_nodes_t(&input, train, max_depth);
+
+        let outputs: Vec<_> = depths
+            .iter()
+            .map(|depth| {
+                let (_, head) = self
+                    .heads
+                    .iter()
+                    .find(|(j, _)| j == depth)
+                    .expect("Expected a head for the depth");
+                head.forward(&nodes[depth - 1])
+            })
+            .collect();
+
+        // The deeper nodes are smaller with unpadded convolutions
+        let smallest = outputs
+            .iter()
+            .min_by_key(|ys| ys.size()[ys.dim() - 1])
+            .unwrap()
+            .shallow_clone();
+        outputs
+            .iter()
+            .map(|ys| {
+                if self.same_padding {
+                    center_crop(ys, &size[size.len() - 2..])
+                } else {
+                    center_crop_like(ys, &smallest, 2)
+                }
+            })
+            .collect()
+    }
+
+    /// Training forward of the deep supervision.
+    ///
+    /// Returns the outputs of the heads from the deepest node `X(0,L)` to the shallowest `X(0,1)`,
+    /// so that it can be used with [`unet::deep_supervision_loss`].
+    /// Without [`UnetPlusPlusProps::deep_supervision`] only the output of the classifier is returned.
+    pub fn forward_deep_t(&self, xs: &Tensor, train: bool) -> Vec<Tensor> {
+        let depths: Vec<_> = self.heads.iter().rev().map(|(j, _)| *j).collect();
+        self.forward_heads_t(xs, train, &depths)
+    }
+}
+
+impl<E, const L: usize> nn::ModuleT for UNetPlusPlus<E, L>
+where
+    E: FeatureExtractor<L>,
+{
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        self.forward_heads_t(xs, train, &[self.depth])
+            .pop()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{ConvConfig, ModuleT, VarStore},
+        Device, Kind, Tensor,
+    };
+    use unet::{deep_supervision_loss, deep_supervision_weights, encoder::BasicCNNConfig};
+
+    use crate::{UNetPlusPlus, UnetPlusPlusProps};
+
+    fn encoder_config() -> BasicCNNConfig {
+        BasicCNNConfig::new(3)
+            .base_width(4)
+            .conv_config(ConvConfig {
+                padding: 1,
+                ..Default::default()
+            })
+    }
+
+    #[test]
+    fn depths() {
+        let x = Tensor::rand(&[2, 3, 48, 40], (Kind::Float, Device::Cpu));
+        let props = || UnetPlusPlusProps {
+            same_padding: true,
+            ..Default::default()
+        };
+
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = encoder_config().build::<2>(&(&vs.root() / "encoder"));
+        let net = UNetPlusPlus::new(&vs.root(), encoder, 2, props());
+        assert_eq!(net.forward_t(&x, false).size(), vec![2, 2, 48, 40]);
+
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = encoder_config().build::<3>(&(&vs.root() / "encoder"));
+        let net = UNetPlusPlus::new(&vs.root(), encoder, 2, props());
+        assert_eq!(net.forward_t(&x, true).size(), vec![2, 2, 48, 40]);
+        assert!(vs.variables().contains_key("decoder.node0_3.conv0.weight"));
+        assert!(!vs.variables().contains_key("decoder.node1_3.conv0.weight"));
+
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = encoder_config().build::<4>(&(&vs.root() / "encoder"));
+        let net = UNetPlusPlus::new(&vs.root(), encoder, 1, props());
+        assert_eq!(net.forward_t(&x, false).size(), vec![2, 1, 48, 40]);
+    }
+
+    #[test]
+    fn unpadded() {
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(4)
+            .build::<2>(&(&vs.root() / "encoder"));
+        let net = UNetPlusPlus::new(&vs.root(), encoder, 2, Default::default());
+
+        // Each block of two unpadded convolutions removes 4 pixels : X(0,0) 88, X(1,0) 40, center 16,
+        // X(1,1) 2 * 16 - 4 = 28 and X(0,2) 2 * 28 - 4 = 52
+        let x = Tensor::rand(&[1, 3, 92, 92], (Kind::Float, Device::Cpu));
+        let y = net.forward_t(&x, false);
+        assert_eq!(y.size(), vec![1, 2, 52, 52]);
+    }
+
+    #[test]
+    fn deep_supervision_pruning() {
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = encoder_config().build::<3>(&(&vs.root() / "encoder"));
+        let props = UnetPlusPlusProps {
+            same_padding: true,
+            deep_supervision: true,
+            ..Default::default()
+        };
+        let mut net = UNetPlusPlus::new(&vs.root(), encoder, 2, props);
+
+        let x = Tensor::rand(&[2, 3, 32, 32], (Kind::Float, Device::Cpu));
+        let outputs = net.forward_deep_t(&x, true);
+        assert_eq!(outputs.len(), 3);
+        for output in outputs.iter() {
+            assert_eq!(output.size(), vec![2, 2, 32, 32]);
+        }
+        let weights = deep_supervision_weights(outputs.len());
+        deep_supervision_loss(&outputs, &weights, |y| y.mean(Kind::Float)).backward();
+
+        for depth in 1..=3 {
+            net.prune(depth);
+            assert_eq!(net.forward_t(&x, false).size(), vec![2, 2, 32, 32]);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, Conv2D, ConvConfig, ConvTranspose2D, ConvTransposeConfig, Module, ModuleT, Path},
+    Tensor,
+};
+use tch_utils::{
+    layers::{Activation, ConvDims, Norm, Normalization},
+    summary::{module_name, trace},
+    tensor::{center_crop_like, interpolate2d},
+};
+
+/// Options of the convolution blocks of the center and the decoder of the UNet
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BlockProps {
+    pub activation: Activation,
+    pub normalization: Normalization,
+    /// Dropout probability applied at the end of the block
+    pub dropout: f64,
+    /// Adds the input of the block (projected with a 1x1 convolution if needed) to its output
+    pub residual: bool,
+}
+
+impl Default for BlockProps {
+    fn default() -> Self {
+        Self {
+            activation: Activation::ReLU,
+            normalization: Normalization::None,
+            dropout: 0.0,
+            residual: false,
+        }
+    }
+}
+
+/// Stack of `conv -> norm -> activation` with an optional residual connection.
+///
+/// The variables are named `conv{i}`, `norm{i}` and `skip` for the projection of the residual connection,
+/// so a block without normalization nor residual connection has the same variables as a plain stack of convolutions.
+/// The convolutions are 2D by default, `ConvBlock<[i64; 3]>` works on volumes.
+#[derive(Debug)]
+pub struct ConvBlock<D: ConvDims = [i64; 2]> {
+    convs: Vec<(D::Conv, Option<Norm>)>,
+    skip: Option<D::Conv>,
+    props: BlockProps,
+    name: String,
+}
+
+impl<D: ConvDims> ConvBlock<D> {
+    pub fn new(
+        vs: &Path,
+        in_channels: i64,
+        out_channels: i64,
+        convolutions: u32,
+        conv_config: ConvConfig,
+        props: BlockProps,
+    ) -> Self {
+        assert!(convolutions > 0, "A block needs at least one convolution");
+        let convs = (0..convolutions)
+            .map(|i| {
+                let in_channels = if i == 0 { in_channels } else { out_channels };
+                let conv = D::conv(
+                    &(vs / format!("conv{i}")),
+                    in_channels,
+                    out_channels,
+                    3,
+                    conv_config,
+                );
+                let norm =
+                    props
+                        .normalization
+                        .build(&(vs / format!("norm{i}")), out_channels, D::ND);
+                (conv, norm)
+            })
+            .collect();
+        let skip = if props.residual && in_channels != out_channels {
+            Some(D::conv(
+                &(vs / "skip"),
+                in_channels,
+                out_channels,
+                1,
+                Default::default(),
+            ))
+        } else {
+            None
+        };
+        Self {
+            convs,
+            skip,
+            props,
+            name: module_name(vs),
+        }
+    }
+}
+
+impl<D: ConvDims> ModuleT for ConvBlock<D> {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let last = self.convs.len() - 1;
+        let ys = self
+            .convs
+            .iter()
+            .enumerate()
+            .fold(xs.shallow_clone(), |ys, (i, (conv, norm))| {
+                let ys = conv.forward(&ys);
+                let ys = match norm {
+                    Some(norm) => norm.forward_t(&ys, train),
+                    None => ys,
+                };
+                if i == last && self.props.residual {
+                    // The activation of the last convolution is applied after the residual connection
+                    ys
+                } else {
+                    self.props.activation.apply(&ys)
+                }
+            });
+        let ys = if self.props.residual {
+            let shortcut = match &self.skip {
+                Some(skip) => skip.forward(xs),
+                None => xs.shallow_clone(),
+            };
+            // Unpadded convolutions shrink the output so the shortcut is cropped to match it
+            self.props
+                .activation
+                .apply(&(ys.shallow_clone() + center_crop_like(&shortcut, &ys, D::ND)))
+        } else {
+            ys
+        };
+        let ys = if self.props.dropout > 0.0 {
+            ys.dropout(self.props.dropout, train)
+        } else {
+            ys
+        };
+        trace(&self.name, &ys);
+        ys
+    }
+}
+
+/// Up-sampling used by the decoder of the UNet
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Upsampling {
+    /// Transposed convolution with a stride of 2
+    #[default]
+    Transpose,
+    /// Bilinear interpolation followed by a 3x3 convolution
+    Bilinear,
+    /// Nearest neighbour interpolation followed by a 3x3 convolution
+    Nearest,
+}
+
+/// Up-sampling layer doubling the resolution, its variables are named `upconv` whatever the mode
+#[derive(Debug)]
+pub enum UpSample {
+    Transpose(ConvTranspose2D),
+    Interpolate { mode: Upsampling, conv: Conv2D },
+}
+
+impl UpSample {
+    pub fn new(vs: &Path, in_channels: i64, out_channels: i64, mode: Upsampling) -> Self {
+        match mode {
+            Upsampling::Transpose => UpSample::Transpose(nn::conv_transpose2d(
+                vs / "upconv",
+                in_channels,
+                out_channels,
+                2,
+                ConvTransposeConfig {
+                    stride: 2,
+                    padding: 0,
+                    ..Default::default()
+                },
+            )),
+            Upsampling::Bilinear | Upsampling::Nearest => UpSample::Interpolate {
+                mode,
+                conv: nn::conv2d(
+                    vs / "upconv",
+                    in_channels,
+                    out_channels,
+                    3,
+                    ConvConfig {
+                        padding: 1,
+                        ..Default::default()
+                    },
+                ),
+            },
+        }
+    }
+}
+
+impl Module for UpSample {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        match self {
+            UpSample::Transpose(upconv) => upconv.forward(xs),
+            UpSample::Interpolate { mode, conv } => {
+                let size = xs.size();
+                let (h, w) = (size[size.len() - 2], size[size.len() - 1]);
+                let ys = interpolate2d(xs, &[h * 2, w * 2], *mode == Upsampling::Bilinear);
+                conv.forward(&ys)
+            }
+        }
+    }
+}
+
+/// Attention gate of Attention U-Net (Oktay et al. 2018) weighting the skip connection with the decoder signal
+#[derive(Debug)]
+pub struct AttentionGate {
+    gate: Conv2D,
+    skip: Conv2D,
+    psi: Conv2D,
+}
+
+impl AttentionGate {
+    /// `gate_channels` are the channels of the decoder signal and `skip_channels` the ones of the skip connection
+    pub fn new(vs: &Path, gate_channels: i64, skip_channels: i64) -> Self {
+        let inter_channels = (skip_channels / 2).max(1);
+        Self {
+            gate: nn::conv2d(
+                vs / "gate",
+                gate_channels,
+                inter_channels,
+                1,
+                Default::default(),
+            ),
+            skip: nn::conv2d(
+                vs / "skip",
+                skip_channels,
+                inter_channels,
+                1,
+                Default::default(),
+            ),
+            psi: nn::conv2d(vs / "psi", inter_channels, 1, 1, Default::default()),
+        }
+    }
+
+    /// Weights `skip` with the gating signal `gate`, both are expected to have the same resolution
+    pub fn forward(&self, gate: &Tensor, skip: &Tensor) -> Tensor {
+        let attention = (self.gate.forward(gate) + self.skip.forward(skip))
+            .relu()
+            .apply(&self.psi)
+            .sigmoid();
+        skip * attention
+    }
+}
@@ -1,83 +1,226 @@
-use tch::nn::{self, ConvConfig, Module, Path, Sequential};
-use tch_utils::types::FeatureExtractor;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use tch::{
+    nn::{self, ConvConfig, ModuleT, Path, SequentialT},
+    Tensor,
+};
+use tch_utils::{
+    config::{conv_config, FromConfig},
+    init::Initialization,
+    layers::{Activation, ConvDims, Normalization, Pooling},
+    summary::{module_name, trace},
+    types::FeatureExtractor,
+};
 
-#[derive(Debug)]
-pub struct BasicCNN {
-    layers: Vec<Sequential>,
+/// Configuration of a [`BasicCNN`].
+///
+/// The depth of the network is given by the const parameter of [`BasicCNNConfig::build`],
+/// the level `i` has `base_width * width_multiplier^i` channels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BasicCNNConfig {
+    pub in_channels: i64,
+    pub base_width: i64,
+    pub width_multiplier: f64,
+    pub convolutions: u32,
+    pub activation: Activation,
+    pub normalization: Normalization,
+    pub dropout: f64,
+    pub pooling: Pooling,
+    #[serde(with = "conv_config")]
+    pub conv_config: ConvConfig,
+    /// Initialization of the convolutions, relative to the encoder (e.g. `layer0.*`)
+    #[serde(default)]
+    pub init: Initialization,
 }
 
-impl BasicCNN {
-    pub fn new(vs: &Path, in_channels: u32) -> Self {
-        let mut previous_channels = in_channels as i64;
-        let mut layers = vec![];
-        for i in 0..4 {
-            let seq = nn::seq();
-            let seq = seq
-                .add(nn::conv2d(
-                    &(vs / format!("layer{i}")) / "0",
-                    previous_channels,
-                    64 * 2_i64.pow(i),
-                    3,
-                    Default::default(),
-                ))
-                .add(nn::conv2d(
-                    &(vs / format!("layer{i}")) / "1",
-                    64 * 2_i64.pow(i),
-                    64 * 2_i64.pow(i),
-                    3,
-                    Default::default(),
-                ));
-            previous_channels = 64 * 2_i64.pow(i);
-            layers.push(seq);
+impl BasicCNNConfig {
+    pub fn new(in_channels: u32) -> Self {
+        Self {
+            in_channels: in_channels as i64,
+            base_width: 64,
+            width_multiplier: 2.0,
+            convolutions: 2,
+            activation: Activation::ReLU,
+            normalization: Normalization::None,
+            dropout: 0.0,
+            pooling: Pooling::Max,
+            conv_config: Default::default(),
+            init: Initialization::default(),
         }
-        Self { layers }
     }
 
-    pub fn new_conf(vs: &Path, in_channels: u32, conf: ConvConfig) -> Self {
-        let mut previous_channels = in_channels as i64;
+    /// Number of channels of the first level
+    pub fn base_width(mut self, base_width: i64) -> Self {
+        self.base_width = base_width;
+        self
+    }
+
+    /// Factor applied to the number of channels at each level
+    pub fn width_multiplier(mut self, width_multiplier: f64) -> Self {
+        self.width_multiplier = width_multiplier;
+        self
+    }
+
+    /// Number of convolutions in each level
+    pub fn convolutions(mut self, convolutions: u32) -> Self {
+        self.convolutions = convolutions;
+        self
+    }
+
+    pub fn activation(mut self, activation: Activation) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Dropout probability applied at the end of each level
+    pub fn dropout(mut self, dropout: f64) -> Self {
+        self.dropout = dropout;
+        self
+    }
+
+    pub fn pooling(mut self, pooling: Pooling) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    /// Configuration of the 3x3 convolutions (e.g. `padding: 1` to keep the resolution)
+    pub fn conv_config(mut self, conv_config: ConvConfig) -> Self {
+        self.conv_config = conv_config;
+        self
+    }
+
+    pub fn init(mut self, init: Initialization) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// Number of channels of the level `level`
+    pub fn width(&self, level: usize) -> i64 {
+        (self.base_width as f64 * self.width_multiplier.powi(level as i32)).round() as i64
+    }
+
+    pub fn build<const L: usize>(&self, vs: &Path) -> BasicCNN<L> {
+        self.build_nd(vs)
+    }
+
+    /// Builds the encoder with the convolutions of `D`, e.g. `build_nd::<L, [i64; 3]>` for volumes
+    pub fn build_nd<const L: usize, D: ConvDims>(&self, vs: &Path) -> BasicCNN<L, D> {
+        assert!(L > 0, "A BasicCNN needs at least one level");
+        assert!(self.convolutions > 0, "convolutions should be above 0");
+        assert!(
+            (0.0..1.0).contains(&self.dropout),
+            "dropout should be in [0, 1)"
+        );
+
+        let mut chanels = [0; L];
+        let mut previous_channels = self.in_channels;
         let mut layers = vec![];
-        for i in 0..4 {
-            let seq = nn::seq();
-            let seq = seq
-                .add(nn::conv2d(
-                    &(vs / format!("layer{i}")) / "0",
-                    previous_channels,
-                    64 * 2_i64.pow(i),
-                    3,
-                    conf,
-                ))
-                .add(nn::conv2d(
-                    &(vs / format!("layer{i}")) / "1",
-                    64 * 2_i64.pow(i),
-                    64 * 2_i64.pow(i),
-                    3,
-                    conf,
-                ));
-            previous_channels = 64 * 2_i64.pow(i);
+        let mut names = vec![];
+        for (i, chanel) in chanels.iter_mut().enumerate() {
+            let vs = vs / format!("layer{i}");
+            names.push(module_name(&vs));
+            let width = self.width(i);
+            let activation = self.activation;
+            let seq = (0..self.convolutions).fold(nn::seq_t(), |seq, j| {
+                let in_channels = if j == 0 { previous_channels } else { width };
+                let seq = seq.add(D::conv(&(&vs / j), in_channels, width, 3, self.conv_config));
+                let seq = match self
+                    .normalization
+                    .build(&(&vs / format!("norm{j}")), width, D::ND)
+                {
+                    Some(norm) => seq.add(norm),
+                    None => seq,
+                };
+                seq.add_fn(move |xs| activation.apply(xs))
+            });
+            let dropout = self.dropout;
+            let seq = if dropout > 0.0 {
+                seq.add_fn_t(move |xs, train| xs.dropout(dropout, train))
+            } else {
+                seq
+            };
+            previous_channels = width;
+            *chanel = width;
             layers.push(seq);
         }
-        Self { layers }
+
+        BasicCNN {
+            layers,
+            names,
+            pooling: self.pooling,
+            chanels,
+            dims: PhantomData,
+        }
+    }
+}
+
+/// Stack of convolution levels separated by poolings, 2D by default and `BasicCNN<L, [i64; 3]>` for volumes
+#[derive(Debug)]
+pub struct BasicCNN<const L: usize = 4, D: ConvDims = [i64; 2]> {
+    layers: Vec<SequentialT>,
+    names: Vec<String>,
+    pooling: Pooling,
+    chanels: [i64; L],
+    dims: PhantomData<D>,
+}
+
+impl BasicCNN<4> {
+    pub fn new(vs: &Path, in_channels: u32) -> Self {
+        BasicCNNConfig::new(in_channels).build(vs)
+    }
+
+    pub fn new_conf(vs: &Path, in_channels: u32, conf: ConvConfig) -> Self {
+        BasicCNNConfig::new(in_channels).conv_config(conf).build(vs)
+    }
+}
+
+impl<const L: usize, D: ConvDims> FromConfig for BasicCNN<L, D> {
+    type Config = BasicCNNConfig;
+
+    fn from_config(vs: &Path, config: &BasicCNNConfig) -> anyhow::Result<Self> {
+        Ok(config.build_nd(vs))
+    }
+
+    fn initialization(config: &BasicCNNConfig) -> Initialization {
+        config.init.clone()
     }
 }
 
-impl nn::Module for BasicCNN {
-    fn forward(&self, xs: &tch::Tensor) -> tch::Tensor {
-        let xs = self.layers[0].forward(xs).max_pool2d_default(2);
-        let xs = self.layers[1].forward(&xs).max_pool2d_default(2);
-        let xs = self.layers[2].forward(&xs).max_pool2d_default(2);
-        self.layers[3].forward(&xs).max_pool2d_default(2)
+impl<const L: usize, D: ConvDims> nn::ModuleT for BasicCNN<L, D> {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        self.layers
+            .iter()
+            .zip(self.names.iter())
+            .fold(xs.shallow_clone(), |xs, (layer, name)| {
+                let fm = layer.forward_t(&xs, train);
+                trace(name, &fm);
+                self.pooling.apply(&fm, 2, D::ND)
+            })
     }
 }
 
-impl FeatureExtractor<4> for BasicCNN {
-    const CHANELS_COUNT: [i64; 4] = [64, 128, 256, 512];
+impl<const L: usize, D: ConvDims> FeatureExtractor<L> for BasicCNN<L, D> {
+    fn chanels_count(&self) -> [i64; L] {
+        self.chanels
+    }
 
-    fn forward_extracts(&self, xs: &tch::Tensor) -> ([tch::Tensor; 4], tch::Tensor) {
-        let x0 = self.layers[0].forward(xs);
-        let x1 = self.layers[1].forward(&x0.max_pool2d_default(2));
-        let x2 = self.layers[2].forward(&x1.max_pool2d_default(2));
-        let x3 = self.layers[3].forward(&x2.max_pool2d_default(2));
-        let y = x3.max_pool2d_default(2);
-        ([x0, x1, x2, x3], y)
+    fn forward_extracts_t(&self, xs: &Tensor, train: bool) -> ([Tensor; L], Tensor) {
+        let mut feature_maps = Vec::with_capacity(L);
+        let mut xs = xs.shallow_clone();
+        for (layer, name) in self.layers.iter().zip(self.names.iter()) {
+            let fm = layer.forward_t(&xs, train);
+            trace(name, &fm);
+            xs = self.pooling.apply(&fm, 2, D::ND);
+            feature_maps.push(fm);
+        }
+        let feature_maps = feature_maps
+            .try_into()
+            .expect("The encoder should have L layers");
+        (feature_maps, xs)
     }
 }
@@ -0,0 +1,246 @@
+use tch::{nn::ModuleT, Device, Kind, Tensor};
+use tch_utils::{
+    segmentation::SegmentationMode,
+    tensor::{extract_patch, patch_starts},
+};
+
+/// Weighting of the tiles when blending their overlap
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Blending {
+    /// Gaussian centered on the tile with a standard deviation of 1/8 of the tile
+    #[default]
+    Gaussian,
+    /// Weights decreasing linearly to the border of the tile over the overlap
+    Linear,
+}
+
+impl Blending {
+    fn weights1d(&self, size: i64, overlap: i64, device: Device) -> Tensor {
+        let positions = Tensor::arange(size, (Kind::Float, device));
+        match self {
+            Blending::Gaussian => {
+                let sigma = size as f64 / 8.0;
+                let center = (size - 1) as f64 / 2.0;
+                ((positions - center).square() / (-2.0 * sigma * sigma)).exp()
+            }
+            Blending::Linear => {
+                // Distance to the closest border of the tile
+                let distance = positions.minimum(&positions.flip(&[0]));
+                ((distance + 1.0) / (overlap + 1) as f64).clamp_max(1.0)
+            }
+        }
+    }
+
+    /// Weight map of a `[H,W]` tile
+    pub fn weights(&self, size: [i64; 2], overlap: i64, device: Device) -> Tensor {
+        let wh = self.weights1d(size[0], overlap, device);
+        let ww = self.weights1d(size[1], overlap, device);
+        wh.outer(&ww)
+    }
+}
+
+/// Sliding-window predictor for images too large for a single forward.
+///
+/// The image is cut in tiles of `tile` pixels whose outputs overlap by `overlap` pixels,
+/// the overlapping outputs being blended with [`Blending`].
+/// Works with unpadded models : the image is padded by the margin the model removes so that
+/// the outputs of the tiles cover the whole image.
+/// ```ignore
+/// let probabilities = SlidingWindow::new(256).overlap(64).batch_size(8).predict(&unet, &image);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlidingWindow {
+    pub tile: [i64; 2],
+    pub overlap: i64,
+    pub batch_size: usize,
+    pub blending: Blending,
+    pub mode: SegmentationMode,
+}
+
+impl SlidingWindow {
+    pub fn new(tile: i64) -> Self {
+        Self {
+            tile: [tile, tile],
+            overlap: tile / 4,
+            batch_size: 4,
+            blending: Blending::Gaussian,
+            mode: SegmentationMode::MultiClass,
+        }
+    }
+
+    /// Size of the tiles given to the model
+    pub fn tile(mut self, height: i64, width: i64) -> Self {
+        self.tile = [height, width];
+        self
+    }
+
+    /// Overlap in pixels between the outputs of neighbouring tiles
+    pub fn overlap(mut self, overlap: i64) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// Number of tiles given at once to the model
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn blending(mut self, blending: Blending) -> Self {
+        self.blending = blending;
+        self
+    }
+
+    /// Activation of the outputs of the model
+    pub fn mode(mut self, mode: SegmentationMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Predicts the `[K,H,W]` probability map of a `[C,H,W]` image.
+    ///
+    /// The outputs of the model are turned into probabilities with the activation of the [`SegmentationMode`],
+    /// a model with a single output channel always uses a sigmoid.
+    pub fn predict<M: ModuleT>(&self, model: &M, image: &Tensor) -> Tensor {
+        assert!(
+            image.dim() == 3,
+            "Expected [C,H,W] shaped tensor got {:?} instead",
+            image.size()
+        );
+        assert!(self.batch_size > 0, "The batch size should be above 0");
+        tch::no_grad(|| self.predict_no_grad(model, image))
+    }
+
+    fn predict_no_grad<M: ModuleT>(&self, model: &M, image: &Tensor) -> Tensor {
+        let size = image.size();
+        let (height, width) = (size[1], size[2]);
+        let device = image.device();
+
+        // Finding the size of the outputs of the model
+        let probe = Tensor::zeros(
+            &[1, size[0], self.tile[0], self.tile[1]],
+            (image.kind(), device),
+        );
+        let probe = model.forward_t(&probe, false);
+        let probe_size = probe.size();
+        let classes = probe_size[1];
+        let out = [probe_size[2], probe_size[3]];
+        let margin = [(self.tile[0] - out[0]) / 2, (self.tile[1] - out[1]) / 2];
+        assert!(
+            self.overlap < out[0] && self.overlap < out[1],
+            "The overlap ({}) should be smaller than the output of the model {:?}",
+            self.overlap,
+            out
+        );
+
+        // Padding the image so that the outputs of the tiles cover it
+        let covered = [height.max(out[0]), width.max(out[1])];
+        let padded = image.constant_pad_nd(&[
+            margin[1],
+            self.tile[1] - out[1] - margin[1] + covered[1] - width,
+            margin[0],
+            self.tile[0] - out[0] - margin[0] + covered[0] - height,
+        ]);
+
+        let weights = self.blending.weights(out, self.overlap, device);
+        let probabilities =
+            Tensor::zeros(&[classes, covered[0], covered[1]], (Kind::Float, device));
+        let total = Tensor::zeros(&[covered[0], covered[1]], (Kind::Float, device));
+
+        let rows = patch_starts(covered[0], out[0], out[0] - self.overlap);
+        let cols = patch_starts(covered[1], out[1], out[1] - self.overlap);
+        let positions: Vec<_> = rows
+            .iter()
+            .flat_map(|y| cols.iter().map(move |x| [*y, *x]))
+            .collect();
+
+        for batch in positions.chunks(self.batch_size) {
+            let tiles: Vec<_> = batch
+                .iter()
+                .map(|start| extract_patch(&padded, start, &self.tile))
+                .collect();
+            let ys = model.forward_t(&Tensor::stack(&tiles, 0), false);
+            let ys = if classes == 1 {
+                ys.sigmoid()
+            } else {
+                self.mode.probabilities(&ys)
+            };
+            for (i, start) in batch.iter().enumerate() {
+                let mut region = extract_patch(&probabilities, start, &out);
+                region += ys.get(i as i64).to_kind(Kind::Float) * &weights;
+                let mut region = extract_patch(&total, start, &out);
+                region += &weights;
+            }
+        }
+
+        (probabilities / total.clamp_min(1e-8))
+            .narrow(1, 0, height)
+            .narrow(2, 0, width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{self, ConvConfig, VarStore},
+        Device, Kind, Tensor,
+    };
+    use tch_utils::tensor::center_crop;
+
+    use super::{Blending, SlidingWindow};
+    use crate::{encoder::BasicCNNConfig, UNet, UnetProps};
+
+    #[test]
+    fn blending() {
+        let image = Tensor::randn(&[1, 70, 45], (Kind::Float, Device::Cpu));
+
+        // Each pixel is predicted from itself so the blending should give back the image
+        let identity = nn::func_t(|xs, _| xs.shallow_clone());
+        let cropping = nn::func_t(|xs, _| {
+            let size = xs.size();
+            center_crop(xs, &[size[2] - 8, size[3] - 8])
+        });
+        for blending in [Blending::Gaussian, Blending::Linear] {
+            let window = SlidingWindow::new(32).overlap(6).blending(blending);
+            let ys = window.predict(&identity, &image);
+            assert!(ys.allclose(&image.sigmoid(), 1e-5, 1e-6, false));
+            let ys = window.predict(&cropping, &image);
+            assert!(ys.allclose(&image.sigmoid(), 1e-5, 1e-6, false));
+        }
+    }
+
+    #[test]
+    fn unet() {
+        let image = Tensor::rand(&[3, 75, 50], (Kind::Float, Device::Cpu));
+
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(4)
+            .conv_config(ConvConfig {
+                padding: 1,
+                ..Default::default()
+            })
+            .build::<2>(&(&vs.root() / "encoder"));
+        let props = UnetProps {
+            same_padding: true,
+            ..Default::default()
+        };
+        let unet = UNet::new(&vs.root(), encoder, 3, props);
+        let ys = SlidingWindow::new(32).batch_size(3).predict(&unet, &image);
+        assert_eq!(ys.size(), vec![3, 75, 50]);
+        assert!(ys.sum_dim_intlist(&[0], false, Kind::Float).allclose(
+            &Tensor::ones(&[75, 50], (Kind::Float, Device::Cpu)),
+            1e-5,
+            1e-5,
+            false
+        ));
+
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(2)
+            .build::<2>(&(&vs.root() / "encoder"));
+        let unet = UNet::new(&vs.root(), encoder, 1, Default::default());
+        let ys = SlidingWindow::new(60).overlap(4).predict(&unet, &image);
+        assert_eq!(ys.size(), vec![1, 75, 50]);
+    }
+}
@@ -1,14 +1,48 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
 use tch::{
-    index::IndexOp,
-    nn::{self, Conv2D, ConvTranspose2D, Path, Sequential},
-    Tensor,
+    nn::{self, Conv2D, Module, ModuleT, Path},
+    Device, Tensor,
 };
-use tch_utils::types::FeatureExtractor;
+use tch_utils::{
+    config::{load_config, load_model, FromConfig},
+    init::Initialization,
+    summary::{module_name, trace},
+    tensor::{center_crop, center_crop_like, interpolate2d, pad_to_multiple},
+    types::{input, FeatureExtractor, MultiModuleT, NamedTensors},
+};
+use thiserror::Error;
+pub mod block;
 pub mod encoder;
+pub mod inference;
+
+use block::{AttentionGate, BlockProps, ConvBlock, UpSample, Upsampling};
+use encoder::{BasicCNN, BasicCNNConfig};
 
+#[derive(Debug, Error)]
+enum ConfigError {
+    #[error("The config describes a UNet of depth {0} while a depth of {1} was expected")]
+    Depth(usize, usize),
+    #[error("Unsupported UNet depth {0}, expected a depth between 1 and 6")]
+    UnsupportedDepth(usize),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UnetProps {
     pub decoder_block_convolutions: u32,
     pub center_block_convolutions: u32,
+    /// Uses padded convolutions in the center and decoder blocks so that the output has the resolution of the input.
+    /// The input is padded to a multiple of 2^L and the output cropped back to the input size.
+    /// The encoder should use padded convolutions as well (e.g. [`encoder::BasicCNNConfig::conv_config`]).
+    pub same_padding: bool,
+    /// Normalization, activation, dropout and residual connections of the center and decoder blocks
+    pub block: BlockProps,
+    /// Up-sampling of the decoder
+    pub upsampling: Upsampling,
+    /// Adds attention gates on the skip connections (Attention U-Net)
+    pub attention: bool,
+    /// Adds auxiliary segmentation heads on the deeper decoder levels, see [`UNet::forward_deep_t`]
+    pub deep_supervision: bool,
 }
 
 impl Default for UnetProps {
@@ -16,19 +50,49 @@ impl Default for UnetProps {
         Self {
             decoder_block_convolutions: 2,
             center_block_convolutions: 2,
+            same_padding: false,
+            block: Default::default(),
+            upsampling: Upsampling::Transpose,
+            attention: false,
+            deep_supervision: false,
         }
     }
 }
 
+/// Serializable description of a UNet with a [`BasicCNN`] encoder, see [`FromConfig`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnetConfig {
+    pub encoder: BasicCNNConfig,
+    /// Number of levels of the encoder
+    pub depth: usize,
+    pub class_count: u32,
+    pub props: UnetProps,
+    /// Initialization of the center and the decoder, relative to the UNet (e.g. `decoder.*`).
+    /// The encoder uses its own initialization.
+    #[serde(default)]
+    pub init: Initialization,
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    layer: usize,
+    up: UpSample,
+    attention: Option<AttentionGate>,
+    block: ConvBlock,
+    head: Option<Conv2D>,
+}
+
 #[derive(Debug)]
 pub struct UNet<E, const L: usize>
 where
     E: FeatureExtractor<L>,
 {
     encoder: E,
-    center: Sequential,
-    decoder: Vec<(usize, ConvTranspose2D, Sequential)>,
+    center: ConvBlock,
+    decoder: Vec<DecoderLayer>,
     classifier: Conv2D,
+    classifier_name: String,
+    same_padding: bool,
 }
 
 impl<E, const L: usize> UNet<E, L>
@@ -39,34 +103,21 @@ where
         let layer_count = L;
 
         let conv_conf = nn::ConvConfig {
+            padding: if props.same_padding { 1 } else { 0 },
             ..Default::default()
         };
 
-        let chanels = <E as FeatureExtractor<L>>::CHANELS_COUNT;
+        let chanels = encoder.chanels_count();
 
         // Creating the center convolutions
-        let center = nn::seq();
-        let center = center
-            .add(nn::conv2d(
-                &(vs / "center") / "conv0",
-                chanels[layer_count - 1],
-                chanels[layer_count - 1] * 2,
-                3,
-                conv_conf,
-            ))
-            .add_fn(Tensor::relu);
-        let center = (1..props.center_block_convolutions)
-            .into_iter()
-            .fold(center, |seq, i| {
-                let conv = nn::conv2d(
-                    &(vs / "center") / format!("conv{i}"),
-                    chanels[layer_count - 1] * 2,
-                    chanels[layer_count - 1] * 2,
-                    3,
-                    conv_conf,
-                );
-                seq.add(conv).add_fn(Tensor::relu)
-            });
+        let center = ConvBlock::new(
+            &(vs / "center"),
+            chanels[layer_count - 1],
+            chanels[layer_count - 1] * 2,
+            props.center_block_convolutions,
+            conv_conf,
+            props.block,
+        );
 
         // Creating the decoder layers
         let decoder_vs = &(vs / "decoder");
@@ -75,44 +126,52 @@ where
             .into_iter()
             .map(|layer| {
                 let vs = decoder_vs / format!("layer{layer}");
-                let seq = nn::seq();
 
-                // Creating the up-convolution and the first convolution
-                let upconv = nn::conv_transpose2d(
-                    &vs / "upconv",
+                // Channels coming from the center or from the previous decoder layer
+                let in_channels = if layer == layer_count - 1 {
+                    chanels[layer] * 2
+                } else {
+                    chanels[layer + 1]
+                };
+
+                // Creating the up-sampling, the attention gate and the convolutions
+                let up = UpSample::new(&vs, in_channels, chanels[layer], props.upsampling);
+                let attention = if props.attention {
+                    Some(AttentionGate::new(
+                        &(&vs / "attention"),
+                        chanels[layer],
+                        chanels[layer],
+                    ))
+                } else {
+                    None
+                };
+                let block = ConvBlock::new(
+                    &vs,
                     chanels[layer] * 2,
                     chanels[layer],
-                    2,
-                    nn::ConvTransposeConfigND {
-                        stride: 2,
-                        padding: 0,
-                        ..Default::default()
-                    },
+                    props.decoder_block_convolutions,
+                    conv_conf,
+                    props.block,
                 );
-                let seq = seq
-                    .add(nn::conv2d(
-                        &vs / "conv0",
-                        chanels[layer] * 2,
+                // The shallowest level is classified by the classifier
+                let head = if props.deep_supervision && layer > 0 {
+                    Some(nn::conv2d(
+                        &vs / "head",
                         chanels[layer],
-                        3,
-                        conv_conf,
+                        class_count as i64,
+                        1,
+                        Default::default(),
                     ))
-                    .add_fn(Tensor::relu);
-
-                // Adding the others convolutions
-                let seq = (1..props.decoder_block_convolutions)
-                    .into_iter()
-                    .fold(seq, |seq, i| {
-                        let conv = nn::conv2d(
-                            &vs / format!("conv{i}"),
-                            chanels[layer],
-                            chanels[layer],
-                            3,
-                            conv_conf,
-                        );
-                        seq.add(conv).add_fn(Tensor::relu)
-                    });
-                (layer, upconv, seq)
+                } else {
+                    None
+                };
+                DecoderLayer {
+                    layer,
+                    up,
+                    attention,
+                    block,
+                    head,
+                }
             })
             .collect();
 
@@ -122,7 +181,7 @@ where
             chanels[0],
             class_count as i64,
             1,
-            conv_conf,
+            Default::default(),
         );
 
         Self {
@@ -130,69 +189,252 @@ where
             center,
             decoder,
             classifier,
+            classifier_name: module_name(&(vs / "classifier")),
+            same_padding: props.same_padding,
         }
     }
-}
 
-impl<E, const L: usize> nn::Module for UNet<E, L>
-where
-    E: FeatureExtractor<L>,
-{
-    fn forward(&self, xs: &Tensor) -> Tensor {
+    /// Runs the network, the outputs of the auxiliary heads are only computed if `heads` is true
+    fn forward_heads_t(&self, xs: &Tensor, train: bool, heads: bool) -> (Tensor, Vec<Tensor>) {
         assert!(
             xs.dim() == 3 || xs.dim() == 4,
             "Expected [C,W,H]/[B,C,W,H] shaped tensor got {:?} instead",
             xs.size()
         );
 
+        // Padding the input so that every level of the encoder halves the resolution exactly
+        let size = xs.size();
+        let input = if self.same_padding {
+            pad_to_multiple(xs, 2_i64.pow(L as u32), 2)
+        } else {
+            xs.shallow_clone()
+        };
+
         // Extracting features with the encoder
-        let (feature_maps, xs) = <E as FeatureExtractor<L>>::forward_extracts(&self.encoder, xs);
+        let (feature_maps, xs) = self.encoder.forward_extracts_t(&input, train);
 
         // Taking the last feature map to be processed by the center convolutions
-        let mut xs = self.center.forward(&xs);
+        let mut xs = self.center.forward_t(&xs, train);
 
-        for (layer, upconv, convs) in self.decoder.iter() {
+        let mut aux = vec![];
+        for decoder_layer in self.decoder.iter() {
             // Upsampling
-            let xt = upconv.forward(&xs);
-            let fm = &feature_maps[*layer];
+            let xt = decoder_layer.up.forward(&xs);
+            let fm = &feature_maps[decoder_layer.layer];
 
             //Cropping the bypass
-            let x_size = xt.size();
-            let fm_size = fm.size();
-            let dw = fm_size[fm_size.len() - 2] - x_size[fm_size.len() - 2];
-            let dh = fm_size[fm_size.len() - 1] - x_size[x_size.len() - 1];
-
-            let resized_fm = if fm_size.len() == 3 {
-                fm.i((
-                    ..,
-                    dw / 2..fm_size[fm_size.len() - 2] - (dw + 1) / 2,
-                    dh / 2..fm_size[fm_size.len() - 1] - (dh + 1) / 2,
-                ))
-            } else {
-                fm.i((
-                    ..,
-                    ..,
-                    dw / 2..fm_size[fm_size.len() - 2] - (dw + 1) / 2,
-                    dh / 2..fm_size[fm_size.len() - 1] - (dh + 1) / 2,
-                ))
+            let resized_fm = center_crop_like(fm, &xt, 2);
+            let resized_fm = match &decoder_layer.attention {
+                Some(attention) => attention.forward(&xt, &resized_fm),
+                None => resized_fm,
             };
             let stacked = tch::Tensor::cat(&[xt, resized_fm], -3);
 
             //Convolutions
-            xs = convs.forward(&stacked);
+            xs = decoder_layer.block.forward_t(&stacked, train);
+
+            if let (true, Some(head)) = (heads, &decoder_layer.head) {
+                aux.push((decoder_layer.layer, head.forward(&xs)));
+            }
+        }
+        let ys = self.classifier.forward(&xs);
+        trace(&self.classifier_name, &ys);
+
+        // Upsampling the auxiliary outputs to the resolution of the output
+        let aux = aux
+            .into_iter()
+            .rev()
+            .map(|(layer, y)| {
+                let y_size = y.size();
+                let scale = 2_i64.pow(layer as u32);
+                let y = interpolate2d(
+                    &y,
+                    &[
+                        y_size[y_size.len() - 2] * scale,
+                        y_size[y_size.len() - 1] * scale,
+                    ],
+                    true,
+                );
+                center_crop_like(&y, &ys, 2)
+            })
+            .collect::<Vec<_>>();
+
+        // Removing the padding added to the input
+        if self.same_padding {
+            let size = &size[size.len() - 2..];
+            let aux = aux.iter().map(|y| center_crop(y, size)).collect();
+            (center_crop(&ys, size), aux)
+        } else {
+            (ys, aux)
+        }
+    }
+
+    /// Training forward of the deep supervision.
+    ///
+    /// Returns the output of the classifier followed by the outputs of the auxiliary heads
+    /// from the shallowest to the deepest decoder level, all at the resolution of the output.
+    /// Without [`UnetProps::deep_supervision`] only the output of the classifier is returned.
+    pub fn forward_deep_t(&self, xs: &Tensor, train: bool) -> Vec<Tensor> {
+        let (ys, aux) = self.forward_heads_t(xs, train, true);
+        let mut outputs = vec![ys];
+        outputs.extend(aux);
+        outputs
+    }
+}
+
+impl<E, const L: usize> nn::ModuleT for UNet<E, L>
+where
+    E: FeatureExtractor<L>,
+{
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        self.forward_heads_t(xs, train, false).0
+    }
+}
+
+impl<E, const L: usize> MultiModuleT for UNet<E, L>
+where
+    E: FeatureExtractor<L>,
+{
+    fn input_names(&self) -> Vec<&str> {
+        vec!["image"]
+    }
+
+    fn output_names(&self) -> Vec<&str> {
+        vec!["mask"]
+    }
+
+    fn forward_multi_t(&self, inputs: &NamedTensors, train: bool) -> anyhow::Result<NamedTensors> {
+        let ys = self.forward_t(input(inputs, "image")?, train);
+        Ok(NamedTensors::from([("mask".to_string(), ys)]))
+    }
+}
+
+impl<const L: usize> FromConfig for UNet<BasicCNN<L>, L> {
+    type Config = UnetConfig;
+
+    fn from_config(vs: &Path, config: &UnetConfig) -> anyhow::Result<Self> {
+        if config.depth != L {
+            bail!(ConfigError::Depth(config.depth, L));
+        }
+        let encoder = BasicCNN::from_config(&(vs / "encoder"), &config.encoder)?;
+        Ok(UNet::new(
+            vs,
+            encoder,
+            config.class_count,
+            config.props.clone(),
+        ))
+    }
+
+    fn initialization(config: &UnetConfig) -> Initialization {
+        let init = BasicCNN::<L>::initialization(&config.encoder)
+            .prefixed("encoder")
+            .extend(config.init.clone());
+        if !init.zero_residual || !config.props.block.residual {
+            return init;
+        }
+        // The last convolution of the residual blocks, the blocks without convolution are rejected by their constructor
+        let props = &config.props;
+        let init = match props.center_block_convolutions.checked_sub(1) {
+            Some(last) => init.zeros(&format!("center.conv{last}")),
+            None => init,
+        };
+        match props.decoder_block_convolutions.checked_sub(1) {
+            Some(last) => init.zeros(&format!("decoder.layer*.conv{last}")),
+            None => init,
         }
-        self.classifier.forward(&xs)
     }
 }
 
+/// [`UNet`] with a [`BasicCNN`] encoder whose depth is only known at runtime, see [`load_unet`]
+#[derive(Debug)]
+pub struct AnyUNet(Box<dyn ModuleT>);
+
+impl<const L: usize> From<UNet<BasicCNN<L>, L>> for AnyUNet {
+    fn from(unet: UNet<BasicCNN<L>, L>) -> Self {
+        AnyUNet(Box::new(unet))
+    }
+}
+
+impl ModuleT for AnyUNet {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        self.0.forward_t(xs, train)
+    }
+}
+
+/// Rebuilds a UNet with the depth of the config stored next to its weights and loads the weights, see [`load_model`]
+pub fn load_unet(
+    weights: &std::path::Path,
+    device: Device,
+) -> anyhow::Result<(nn::VarStore, AnyUNet)> {
+    fn load<const L: usize>(
+        weights: &std::path::Path,
+        device: Device,
+    ) -> anyhow::Result<(nn::VarStore, AnyUNet)> {
+        let (vs, unet) = load_model::<UNet<BasicCNN<L>, L>>(weights, device)?;
+        Ok((vs, unet.into()))
+    }
+
+    let config: UnetConfig = load_config(weights)?;
+    match config.depth {
+        1 => load::<1>(weights, device),
+        2 => load::<2>(weights, device),
+        3 => load::<3>(weights, device),
+        4 => load::<4>(weights, device),
+        5 => load::<5>(weights, device),
+        6 => load::<6>(weights, device),
+        depth => bail!(ConfigError::UnsupportedDepth(depth)),
+    }
+}
+
+/// Default weights of the deep supervision : each level weights half of the previous one
+pub fn deep_supervision_weights(count: usize) -> Vec<f64> {
+    (0..count).map(|i| 0.5_f64.powi(i as i32)).collect()
+}
+
+/// Weighted multi-scale loss of the outputs of [`UNet::forward_deep_t`].
+///
+/// `loss` computes the loss of one output and `weights[i]` weights the output `i`, the result is normalized by the sum of the weights.
+pub fn deep_supervision_loss<F>(outputs: &[Tensor], weights: &[f64], loss: F) -> Tensor
+where
+    F: Fn(&Tensor) -> Tensor,
+{
+    assert!(
+        outputs.len() == weights.len(),
+        "Expected {} weights got {} instead",
+        outputs.len(),
+        weights.len()
+    );
+    let total: f64 = weights.iter().sum();
+    outputs
+        .iter()
+        .zip(weights.iter())
+        .map(|(output, weight)| loss(output) * (*weight / total))
+        .reduce(|a, b| a + b)
+        .expect("Expected at least one output")
+}
+
 #[cfg(test)]
 mod tests {
+    use std::fs;
     use tch::{
-        nn::{Module, VarStore},
+        nn::{ConvConfig, ModuleT, VarStore},
         Device, Kind, Tensor,
     };
 
-    use crate::{encoder::BasicCNN, UNet, UnetProps};
+    use tch_utils::{
+        config::{build_model, config_path, save_config, FromConfig},
+        init::{InitScheme, Initialization},
+        layers::{Activation, Normalization, Pooling},
+        summary::Summary,
+        types::{FeatureExtractor, MultiModuleT, NamedTensors},
+    };
+
+    use crate::{
+        block::{BlockProps, Upsampling},
+        deep_supervision_loss, deep_supervision_weights,
+        encoder::{BasicCNN, BasicCNNConfig},
+        load_unet, UNet, UnetConfig, UnetProps,
+    };
 
     #[test]
     fn it_works() {
@@ -202,11 +444,284 @@ mod tests {
         let props = UnetProps {
             decoder_block_convolutions: 2,
             center_block_convolutions: 2,
+            ..Default::default()
         };
 
         let encoder = BasicCNN::new(&(&vs.root() / "encoder"), 3);
         let unet = UNet::new(&vs.root(), encoder, 3, props);
 
-        let y = unet.forward(&x);
+        let y = unet.forward_t(&x, false);
+    }
+
+    #[test]
+    fn basic_cnn_config() {
+        let x = Tensor::rand(&[2, 3, 64, 64], (Kind::Float, Device::Cpu));
+        let vs = VarStore::new(Device::Cpu);
+
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(8)
+            .width_multiplier(1.5)
+            .normalization(Normalization::Group(2))
+            .pooling(Pooling::Avg)
+            .dropout(0.1)
+            .conv_config(ConvConfig {
+                padding: 1,
+                ..Default::default()
+            })
+            .build::<3>(&vs.root());
+        assert_eq!(encoder.chanels_count(), [8, 12, 18]);
+
+        let (fms, y) = encoder.forward_extracts_t(&x, true);
+        assert_eq!(fms[2].size(), vec![2, 18, 16, 16]);
+        assert_eq!(y.size(), vec![2, 18, 8, 8]);
+
+        // The initialization of the config is applied to the standalone encoder
+        let vs = VarStore::new(Device::Cpu);
+        let config = BasicCNNConfig::new(3)
+            .base_width(4)
+            .init(Initialization::new().weights("layer1.*", InitScheme::Zeros));
+        let _ = build_model::<BasicCNN<2>>(&vs, &config).unwrap();
+        let variables = vs.variables();
+        assert_eq!(
+            f64::from(variables["layer1.0.weight"].abs().sum(Kind::Float)),
+            0.0
+        );
+        assert_ne!(
+            f64::from(variables["layer0.0.weight"].abs().sum(Kind::Float)),
+            0.0
+        );
+    }
+
+    #[test]
+    fn same_padding() {
+        let vs = VarStore::new(Device::Cpu);
+        let props = UnetProps {
+            same_padding: true,
+            ..Default::default()
+        };
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(8)
+            .conv_config(ConvConfig {
+                padding: 1,
+                ..Default::default()
+            })
+            .build::<3>(&(&vs.root() / "encoder"));
+        let unet = UNet::new(&vs.root(), encoder, 2, props);
+
+        let x = Tensor::rand(&[2, 3, 64, 64], (Kind::Float, Device::Cpu));
+        assert_eq!(unet.forward_t(&x, false).size(), vec![2, 2, 64, 64]);
+
+        let x = Tensor::rand(&[2, 3, 50, 37], (Kind::Float, Device::Cpu));
+        assert_eq!(unet.forward_t(&x, false).size(), vec![2, 2, 50, 37]);
+    }
+
+    #[test]
+    fn block_options() {
+        let conv_config = ConvConfig {
+            padding: 1,
+            ..Default::default()
+        };
+
+        // Without the options the variables are the ones of a plain stack of convolutions
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(4)
+            .conv_config(conv_config)
+            .build::<2>(&(&vs.root() / "encoder"));
+        let _ = UNet::new(&vs.root(), encoder, 1, Default::default());
+        let variables = vs.variables();
+        assert!(variables.contains_key("center.conv1.weight"));
+        assert!(variables.contains_key("decoder.layer0.conv1.bias"));
+        assert!(!variables.keys().any(|name| name.contains("norm")));
+        assert!(!variables.keys().any(|name| name.contains("skip")));
+
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(4)
+            .conv_config(conv_config)
+            .build::<2>(&(&vs.root() / "encoder"));
+        let props = UnetProps {
+            same_padding: true,
+            block: BlockProps {
+                activation: Activation::GELU,
+                normalization: Normalization::Batch,
+                dropout: 0.2,
+                residual: true,
+            },
+            ..Default::default()
+        };
+        let unet = UNet::new(&vs.root(), encoder, 1, props);
+        assert!(vs.variables().contains_key("decoder.layer1.skip.weight"));
+
+        let x = Tensor::rand(&[2, 3, 32, 32], (Kind::Float, Device::Cpu));
+        assert_eq!(unet.forward_t(&x, true).size(), vec![2, 1, 32, 32]);
+    }
+
+    #[test]
+    fn upsampling_and_attention() {
+        for upsampling in [Upsampling::Bilinear, Upsampling::Nearest] {
+            let vs = VarStore::new(Device::Cpu);
+            let encoder = BasicCNNConfig::new(3)
+                .base_width(4)
+                .build::<2>(&(&vs.root() / "encoder"));
+            let props = UnetProps {
+                upsampling,
+                attention: true,
+                ..Default::default()
+            };
+            let unet = UNet::new(&vs.root(), encoder, 2, props);
+            assert!(vs
+                .variables()
+                .contains_key("decoder.layer0.attention.psi.weight"));
+
+            let x = Tensor::rand(&[1, 3, 92, 92], (Kind::Float, Device::Cpu));
+            let y = unet.forward_t(&x, false);
+            assert_eq!(y.size()[..2], [1, 2]);
+        }
+    }
+
+    #[test]
+    fn deep_supervision() {
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(4)
+            .conv_config(ConvConfig {
+                padding: 1,
+                ..Default::default()
+            })
+            .build::<3>(&(&vs.root() / "encoder"));
+        let props = UnetProps {
+            same_padding: true,
+            deep_supervision: true,
+            ..Default::default()
+        };
+        let unet = UNet::new(&vs.root(), encoder, 2, props);
+
+        let x = Tensor::rand(&[2, 3, 40, 40], (Kind::Float, Device::Cpu));
+        let outputs = unet.forward_deep_t(&x, true);
+        assert_eq!(outputs.len(), 3);
+        for output in outputs.iter() {
+            assert_eq!(output.size(), vec![2, 2, 40, 40]);
+        }
+        assert_eq!(unet.forward_t(&x, false).size(), vec![2, 2, 40, 40]);
+
+        let weights = deep_supervision_weights(outputs.len());
+        let loss = deep_supervision_loss(&outputs, &weights, |y| y.mean(Kind::Float));
+        loss.backward();
+    }
+
+    #[test]
+    fn multi_input() {
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(4)
+            .build::<2>(&(&vs.root() / "encoder"));
+        let unet = UNet::new(&vs.root(), encoder, 2, Default::default());
+        assert_eq!(unet.input_names(), ["image"]);
+
+        let x = Tensor::rand(&[1, 3, 92, 92], (Kind::Float, Device::Cpu));
+        let inputs = NamedTensors::from([("image".to_string(), x.shallow_clone())]);
+        let outputs = unet.forward_multi(&inputs).unwrap();
+        assert!(outputs["mask"].equal(&unet.forward_t(&x, false)));
+        assert!(unet.forward_multi(&NamedTensors::new()).is_err());
+    }
+
+    #[test]
+    fn config() {
+        let config = UnetConfig {
+            encoder: BasicCNNConfig::new(3)
+                .base_width(4)
+                .conv_config(ConvConfig {
+                    padding: 1,
+                    ..Default::default()
+                }),
+            depth: 2,
+            class_count: 2,
+            props: UnetProps {
+                same_padding: true,
+                attention: true,
+                ..Default::default()
+            },
+            init: Initialization::new()
+                .weights("*", InitScheme::KaimingNormal)
+                .bias("*", 0.0)
+                .seed(3),
+        };
+        let config: UnetConfig =
+            serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+
+        let vs = VarStore::new(Device::Cpu);
+        let unet = build_model::<UNet<BasicCNN<2>, 2>>(&vs, &config).unwrap();
+        let other = VarStore::new(Device::Cpu);
+        let _ = build_model::<UNet<BasicCNN<2>, 2>>(&other, &config).unwrap();
+        // The seeded initialization is reproducible
+        let variables = other.variables();
+        for (name, var) in vs.variables() {
+            assert!(var.equal(&variables[&name]), "{name} differs");
+        }
+        assert!(vs
+            .variables()
+            .contains_key("decoder.layer1.attention.psi.weight"));
+        let x = Tensor::rand(&[1, 3, 20, 20], (Kind::Float, Device::Cpu));
+        assert_eq!(unet.forward_t(&x, false).size(), vec![1, 2, 20, 20]);
+
+        // The depth of the loaded UNet is read from the config
+        let weights = std::env::temp_dir().join(format!("unet-{}.pt", std::process::id()));
+        vs.save(&weights).unwrap();
+        save_config(&config, &weights).unwrap();
+        let loaded = load_unet(&weights, Device::Cpu);
+        fs::remove_file(&weights).unwrap();
+        fs::remove_file(config_path(&weights)).unwrap();
+        let (_, loaded) = loaded.unwrap();
+        assert!(loaded.forward_t(&x, false).allclose(
+            &unet.forward_t(&x, false),
+            1e-6,
+            1e-6,
+            false
+        ));
+
+        let vs = VarStore::new(Device::Cpu);
+        assert!(UNet::<BasicCNN<3>, 3>::from_config(&vs.root(), &config).is_err());
+
+        // The zero residual rules are skipped for blocks without convolution
+        let mut config = config;
+        config.props.block.residual = true;
+        config.props.center_block_convolutions = 0;
+        config.init = config.init.zero_residual(true);
+        let init = UNet::<BasicCNN<2>, 2>::initialization(&config);
+        assert!(init.zero_residual);
+    }
+
+    #[test]
+    fn summary() {
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(4)
+            .build::<2>(&(&vs.root() / "encoder"));
+        let unet = UNet::new(&vs.root(), encoder, 2, Default::default());
+        let x = Tensor::zeros(&[1, 3, 60, 60], (Kind::Float, Device::Cpu));
+        let summary = Summary::new(&vs, &unet, &x);
+
+        let names: Vec<_> = summary.layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "encoder.layer0",
+                "encoder.layer1",
+                "center",
+                "decoder.layer1",
+                "decoder.layer0",
+                "classifier"
+            ]
+        );
+        assert_eq!(summary.layers[0].output, Some(vec![1, 4, 56, 56]));
+        assert_eq!(
+            summary.params(),
+            vs.variables()
+                .values()
+                .map(|v| v.numel() as i64)
+                .sum::<i64>()
+        );
+        assert!(summary.flops() > 0);
     }
 }
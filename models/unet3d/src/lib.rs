@@ -0,0 +1,235 @@
+use tch::{
+    nn::{self, Conv3D, ConvTranspose3D, ConvTransposeConfig, Module, Path},
+    Tensor,
+};
+use tch_utils::{
+    tensor::{center_crop, center_crop_like, pad_to_multiple},
+    types::FeatureExtractor,
+};
+use unet::{
+    block::{BlockProps, ConvBlock},
+    encoder::BasicCNN,
+};
+
+/// Volumetric [`unet::block::ConvBlock`]
+pub type ConvBlock3D = ConvBlock<[i64; 3]>;
+
+/// Volumetric [`unet::encoder::BasicCNN`], built with [`unet::encoder::BasicCNNConfig::build_nd`]
+pub type BasicCNN3D<const L: usize = 4> = BasicCNN<L, [i64; 3]>;
+
+/// Configuration of a [`UNet3D`]
+pub struct Unet3DProps {
+    pub decoder_block_convolutions: u32,
+    pub center_block_convolutions: u32,
+    /// Uses padded convolutions in the center and decoder blocks so that the output has the resolution of the input.
+    /// The input is padded to a multiple of 2^L and the output cropped back to the input size.
+    pub same_padding: bool,
+    /// Normalization, activation, dropout and residual connections of the center and decoder blocks
+    pub block: BlockProps,
+}
+
+impl Default for Unet3DProps {
+    fn default() -> Self {
+        Self {
+            decoder_block_convolutions: 2,
+            center_block_convolutions: 2,
+            same_padding: false,
+            block: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    layer: usize,
+    upconv: ConvTranspose3D,
+    block: ConvBlock3D,
+}
+
+/// UNet working on `[C,D,H,W]`/`[B,C,D,H,W]` volumes (Çiçek et al. 2016), the encoder should extract volumes too.
+/// Its variables have the same names as the ones of [`unet::UNet`].
+#[derive(Debug)]
+pub struct UNet3D<E, const L: usize>
+where
+    E: FeatureExtractor<L>,
+{
+    encoder: E,
+    center: ConvBlock3D,
+    decoder: Vec<DecoderLayer>,
+    classifier: Conv3D,
+    same_padding: bool,
+}
+
+impl<E, const L: usize> UNet3D<E, L>
+where
+    E: FeatureExtractor<L>,
+{
+    pub fn new(vs: &Path, encoder: E, class_count: u32, props: Unet3DProps) -> Self {
+        let conv_conf = nn::ConvConfig {
+            padding: if props.same_padding { 1 } else { 0 },
+            ..Default::default()
+        };
+
+        let chanels = encoder.chanels_count();
+
+        // Creating the center convolutions
+        let center = ConvBlock3D::new(
+            &(vs / "center"),
+            chanels[L - 1],
+            chanels[L - 1] * 2,
+            props.center_block_convolutions,
+            conv_conf,
+            props.block,
+        );
+
+        // Creating the decoder layers
+        let decoder_vs = &(vs / "decoder");
+        let decoder = (0..L)
+            .rev()
+            .map(|layer| {
+                let vs = decoder_vs / format!("layer{layer}");
+
+                // Channels coming from the center or from the previous decoder layer
+                let in_channels = if layer == L - 1 {
+                    chanels[layer] * 2
+                } else {
+                    chanels[layer + 1]
+                };
+
+                let upconv = nn::conv_transpose3d(
+                    &vs / "upconv",
+                    in_channels,
+                    chanels[layer],
+                    2,
+                    ConvTransposeConfig {
+                        stride: 2,
+                        padding: 0,
+                        ..Default::default()
+                    },
+                );
+                let block = ConvBlock3D::new(
+                    &vs,
+                    chanels[layer] * 2,
+                    chanels[layer],
+                    props.decoder_block_convolutions,
+                    conv_conf,
+                    props.block,
+                );
+                DecoderLayer {
+                    layer,
+                    upconv,
+                    block,
+                }
+            })
+            .collect();
+
+        // Last convolution to classify voxels
+        let classifier = nn::conv3d(
+            &(vs / "classifier"),
+            chanels[0],
+            class_count as i64,
+            1,
+            Default::default(),
+        );
+
+        Self {
+            encoder,
+            center,
+            decoder,
+            classifier,
+            same_padding: props.same_padding,
+        }
+    }
+}
+
+impl<E, const L: usize> nn::ModuleT for UNet3D<E, L>
+where
+    E: FeatureExtractor<L>,
+{
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        assert!(
+            xs.dim() == 4 || xs.dim() == 5,
+            "Expected [C,D,H,W]/[B,C,D,H,W] shaped tensor got {:?} instead",
+            xs.size()
+        );
+
+        // Padding the input so that every level of the encoder halves the resolution exactly
+        let size = xs.size();
+        let input = if self.same_padding {
+            pad_to_multiple(xs, 2_i64.pow(L as u32), 3)
+        } else {
+            xs.shallow_clone()
+        };
+
+        // Extracting features with the encoder
+        let (feature_maps, xs) = self.encoder.forward_extracts_t(&input, train);
+
+        // Taking the last feature map to be processed by the center convolutions
+        let mut xs = self.center.forward_t(&xs, train);
+
+        for decoder_layer in self.decoder.iter() {
+            // Upsampling
+            let xt = decoder_layer.upconv.forward(&xs);
+
+            //Cropping the bypass
+            let resized_fm = center_crop_like(&feature_maps[decoder_layer.layer], &xt, 3);
+            let stacked = Tensor::cat(&[xt, resized_fm], -4);
+
+            //Convolutions
+            xs = decoder_layer.block.forward_t(&stacked, train);
+        }
+        let ys = self.classifier.forward(&xs);
+
+        // Removing the padding added to the input
+        if self.same_padding {
+            center_crop(&ys, &size[size.len() - 3..])
+        } else {
+            ys
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{ConvConfig, ModuleT, VarStore},
+        Device, Kind, Tensor,
+    };
+    use tch_utils::layers::Normalization;
+    use unet::encoder::BasicCNNConfig;
+
+    use crate::{BasicCNN3D, UNet3D, Unet3DProps};
+
+    #[test]
+    fn same_padding() {
+        let vs = VarStore::new(Device::Cpu);
+        let config = BasicCNNConfig::new(1)
+            .base_width(4)
+            .normalization(Normalization::Group(2))
+            .conv_config(ConvConfig {
+                padding: 1,
+                ..Default::default()
+            });
+        let encoder: BasicCNN3D<2> = config.build_nd(&(&vs.root() / "encoder"));
+        let props = Unet3DProps {
+            same_padding: true,
+            ..Default::default()
+        };
+        let unet = UNet3D::new(&vs.root(), encoder, 2, props);
+        assert!(vs.variables().contains_key("decoder.layer1.upconv.weight"));
+
+        let x = Tensor::rand(&[2, 1, 13, 16, 10], (Kind::Float, Device::Cpu));
+        assert_eq!(unet.forward_t(&x, true).size(), vec![2, 2, 13, 16, 10]);
+    }
+
+    #[test]
+    fn valid_padding() {
+        let vs = VarStore::new(Device::Cpu);
+        let config = BasicCNNConfig::new(1).base_width(2);
+        let encoder: BasicCNN3D<2> = config.build_nd(&(&vs.root() / "encoder"));
+        let unet = UNet3D::new(&vs.root(), encoder, 1, Default::default());
+
+        let x = Tensor::rand(&[1, 1, 44, 44, 44], (Kind::Float, Device::Cpu));
+        assert_eq!(unet.forward_t(&x, false).size(), vec![1, 1, 4, 4, 4]);
+    }
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, Conv2D, Module},
+    Tensor,
+};
+use tch_utils::{
+    config::FromConfig,
+    init::Initialization,
+    summary::{module_name, trace},
+    tensor::interpolate2d,
+    types::FeatureExtractor,
+};
+
+use crate::{transformer::Transformer, VitConfig};
+
+/// Configuration of a [`ViTEncoder`].
+///
+/// The depth of the encoder is given by the const parameter of [`VitEncoderConfig::build`],
+/// the level `i` has `base_width * width_multiplier^i` channels like a `BasicCNN`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VitEncoderConfig {
+    /// Transformer of the encoder, its classes are ignored
+    pub vit: VitConfig,
+    pub base_width: i64,
+    pub width_multiplier: f64,
+}
+
+impl VitEncoderConfig {
+    pub fn new(vit: VitConfig) -> Self {
+        Self {
+            vit,
+            base_width: 64,
+            width_multiplier: 2.0,
+        }
+    }
+
+    /// Number of channels of the first level
+    pub fn base_width(mut self, base_width: i64) -> Self {
+        self.base_width = base_width;
+        self
+    }
+
+    /// Factor applied to the number of channels at each level
+    pub fn width_multiplier(mut self, width_multiplier: f64) -> Self {
+        self.width_multiplier = width_multiplier;
+        self
+    }
+
+    /// Number of channels of the level `level`
+    pub fn width(&self, level: usize) -> i64 {
+        (self.base_width as f64 * self.width_multiplier.powi(level as i32)).round() as i64
+    }
+
+    pub fn build<const L: usize>(&self, vs: &nn::Path) -> ViTEncoder<L> {
+        assert!(L > 0, "A ViTEncoder needs at least one level");
+        assert!(
+            self.vit.depth >= L,
+            "The transformer has less blocks ({}) than the encoder has levels ({L})",
+            self.vit.depth
+        );
+
+        // The levels are extracted after evenly spaced blocks, the last level after the last block
+        let mut blocks = [0; L];
+        let mut chanels = [0; L];
+        for (level, (block, chanel)) in blocks.iter_mut().zip(chanels.iter_mut()).enumerate() {
+            *block = (level + 1) * self.vit.depth / L - 1;
+            *chanel = self.width(level);
+        }
+        let necks = (0..L)
+            .map(|level| {
+                let neck_vs = vs / format!("neck{level}");
+                let neck = nn::conv2d(
+                    &neck_vs,
+                    self.vit.embed_dim,
+                    chanels[level],
+                    1,
+                    Default::default(),
+                );
+                (neck, module_name(&neck_vs))
+            })
+            .collect();
+
+        ViTEncoder {
+            transformer: Transformer::new(vs, &self.vit),
+            blocks,
+            necks,
+            chanels,
+        }
+    }
+}
+
+/// [`FeatureExtractor`] backed by a Vision Transformer.
+///
+/// The tokens of the patches after some blocks are normalized and reshaped to `[N, D, H/P, W/P]` maps,
+/// resized to the resolution `H/2^i` of the level `i` and projected to its chanels by a 1x1 convolution (`neck{i}`),
+/// so that the encoder can back a decoder expecting a pyramid of feature maps (e.g. a `UNet`).
+#[derive(Debug)]
+pub struct ViTEncoder<const L: usize> {
+    transformer: Transformer,
+    blocks: [usize; L],
+    necks: Vec<(Conv2D, String)>,
+    chanels: [i64; L],
+}
+
+impl<const L: usize> FromConfig for ViTEncoder<L> {
+    type Config = VitEncoderConfig;
+
+    fn from_config(vs: &nn::Path, config: &VitEncoderConfig) -> anyhow::Result<Self> {
+        Ok(config.build(vs))
+    }
+
+    fn initialization(config: &VitEncoderConfig) -> Initialization {
+        config.vit.initialization()
+    }
+}
+
+impl<const L: usize> nn::ModuleT for ViTEncoder<L> {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        self.forward_extracts_t(xs, train).1
+    }
+}
+
+impl<const L: usize> FeatureExtractor<L> for ViTEncoder<L> {
+    fn chanels_count(&self) -> [i64; L] {
+        self.chanels
+    }
+
+    /// Expects `[C,H,W]`/`[N,C,H,W]` images whose size is a multiple of the patch size
+    fn forward_extracts_t(&self, xs: &Tensor, train: bool) -> ([Tensor; L], Tensor) {
+        if xs.dim() == 3 {
+            let (feature_maps, ys) = self.forward_extracts_t(&xs.unsqueeze(0), train);
+            return (feature_maps.map(|fm| fm.squeeze_dim(0)), ys.squeeze_dim(0));
+        }
+
+        let size = xs.size();
+        let (height, width) = (size[2], size[3]);
+        let dim = self.transformer.config().embed_dim;
+        let (outputs, (h, w)) = self.transformer.forward_blocks_t(xs, train);
+
+        let mut feature_maps = Vec::with_capacity(L);
+        for (level, (neck, name)) in self.necks.iter().enumerate() {
+            // The class token is dropped
+            let tokens = self.transformer.normalize(&outputs[self.blocks[level]]);
+            let tokens = tokens
+                .narrow(1, 1, h * w)
+                .transpose(1, 2)
+                .reshape(&[size[0], dim, h, w]);
+            let tokens = interpolate2d(&tokens, &[height >> level, width >> level], true);
+            let fm = neck.forward(&tokens);
+            trace(name, &fm);
+            feature_maps.push(fm);
+        }
+        let xs = feature_maps[L - 1].max_pool2d_default(2);
+        let feature_maps = feature_maps
+            .try_into()
+            .expect("The encoder should have L levels");
+        (feature_maps, xs)
+    }
+}
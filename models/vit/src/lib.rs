@@ -0,0 +1,284 @@
+mod encoder;
+mod transformer;
+
+pub use encoder::{ViTEncoder, VitEncoderConfig};
+pub use transformer::{MultiHeadAttention, PatchEmbedding};
+
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, Module, ModuleT},
+    Tensor,
+};
+use tch_utils::{
+    config::FromConfig,
+    init::Initialization,
+    summary::{module_name, trace},
+    tensor::images,
+};
+use transformer::Transformer;
+
+/// Configuration of a Vision Transformer (Dosovitskiy et al. 2020) : the images are split in patches embedded as tokens,
+/// a class token is prepended and the tokens go through pre-norm transformer blocks.
+///
+/// The variables are named `patch_embed`, `cls_token`, `pos_embedding`, `block{i}` (`attention.qkv`, `attention.proj`,
+/// `mlp.fc0`, `mlp.fc1`, `norm1`, `norm2`), `norm` and `head`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VitConfig {
+    pub in_channels: i64,
+    pub classes: i64,
+    /// Size of the (square) images the position embeddings are learned for, they are interpolated for other sizes
+    pub image_size: i64,
+    /// Size of the (square) patches, the images should be a multiple of it
+    pub patch_size: i64,
+    /// Dimention of the tokens
+    pub embed_dim: i64,
+    /// Number of transformer blocks
+    pub depth: usize,
+    /// Number of attention heads, they should divide `embed_dim`
+    pub heads: i64,
+    /// Width of the hidden layer of the MLPs relative to `embed_dim`
+    pub mlp_ratio: f64,
+    /// Dropout probability of the embeddings, the attention weights and the outputs of the attention and the MLPs
+    pub dropout: f64,
+    #[serde(default)]
+    pub init: Initialization,
+}
+
+impl VitConfig {
+    /// Small configuration for MNIST like images : 4x4 patches, tokens of size 64, 6 blocks of 4 heads
+    pub fn new(in_channels: i64, classes: i64, image_size: i64) -> Self {
+        Self {
+            in_channels,
+            classes,
+            image_size,
+            patch_size: 4,
+            embed_dim: 64,
+            depth: 6,
+            heads: 4,
+            mlp_ratio: 2.0,
+            dropout: 0.0,
+            init: Initialization::default(),
+        }
+    }
+
+    pub fn patch_size(mut self, patch_size: i64) -> Self {
+        self.patch_size = patch_size;
+        self
+    }
+
+    pub fn embed_dim(mut self, embed_dim: i64) -> Self {
+        self.embed_dim = embed_dim;
+        self
+    }
+
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    pub fn heads(mut self, heads: i64) -> Self {
+        self.heads = heads;
+        self
+    }
+
+    pub fn mlp_ratio(mut self, mlp_ratio: f64) -> Self {
+        self.mlp_ratio = mlp_ratio;
+        self
+    }
+
+    pub fn dropout(mut self, dropout: f64) -> Self {
+        self.dropout = dropout;
+        self
+    }
+
+    pub fn init(mut self, init: Initialization) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// Number of patches along each side of the images
+    fn grid_size(&self) -> i64 {
+        assert!(
+            self.image_size % self.patch_size == 0,
+            "The image size ({}) should be a multiple of the patch size ({})",
+            self.image_size,
+            self.patch_size
+        );
+        assert!(
+            (0.0..1.0).contains(&self.dropout),
+            "dropout should be in [0, 1)"
+        );
+        self.image_size / self.patch_size
+    }
+
+    /// Initialization of the transformer, the output projections of the attentions and of the MLPs
+    /// are zeroed with `zero_residual` so that the blocks start as identities
+    fn initialization(&self) -> Initialization {
+        let init = self.init.clone();
+        if init.zero_residual {
+            init.zeros("block*.attention.proj").zeros("block*.mlp.fc1")
+        } else {
+            init
+        }
+    }
+
+    pub fn build(&self, vs: &nn::Path) -> ViT {
+        ViT {
+            transformer: Transformer::new(vs, self),
+            head: nn::linear(
+                vs / "head",
+                self.embed_dim,
+                self.classes,
+                Default::default(),
+            ),
+            head_name: module_name(&(vs / "head")),
+        }
+    }
+}
+
+/// Classifier of the normalized class token
+#[derive(Debug)]
+pub struct ViT {
+    transformer: Transformer,
+    head: nn::Linear,
+    head_name: String,
+}
+
+impl FromConfig for ViT {
+    type Config = VitConfig;
+
+    fn from_config(vs: &nn::Path, config: &VitConfig) -> anyhow::Result<Self> {
+        Ok(config.build(vs))
+    }
+
+    fn initialization(config: &VitConfig) -> Initialization {
+        config.initialization()
+    }
+}
+
+impl ModuleT for ViT {
+    /// Expects `[N, C, H, W]` images or `[N, C*H*W]` flattened ones
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let config = self.transformer.config();
+        let xs = images(xs, config.in_channels, config.image_size);
+        let (mut outputs, _) = self.transformer.forward_blocks_t(&xs, train);
+        let tokens = outputs.pop().expect("The transformer should have blocks");
+        let cls_token = self.transformer.normalize(&tokens).select(1, 0);
+        let ys = self.head.forward(&cls_token);
+        trace(&self.head_name, &ys);
+        ys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{self, ModuleT},
+        Device, Kind, Tensor,
+    };
+    use tch_utils::{
+        config::{build_model, checkpoint_round_trip},
+        init::Initialization,
+        types::FeatureExtractor,
+    };
+    use unet::{UNet, UnetProps};
+
+    use crate::{MultiHeadAttention, ViT, ViTEncoder, VitConfig, VitEncoderConfig};
+
+    #[test]
+    fn classifier() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = VitConfig::new(1, 10, 28)
+            .embed_dim(16)
+            .depth(2)
+            .heads(2)
+            .init(Initialization::new().zero_residual(true));
+        let vit = build_model::<ViT>(&vs, &config).unwrap();
+
+        let x = Tensor::rand(&[2, 784], (Kind::Float, Device::Cpu));
+        assert_eq!(vit.forward_t(&x, true).size(), vec![2, 10]);
+        let x = Tensor::rand(&[3, 1, 28, 28], (Kind::Float, Device::Cpu));
+        assert_eq!(vit.forward_t(&x, false).size(), vec![3, 10]);
+
+        // 7x7 patches and the class token
+        let variables = vs.variables();
+        assert_eq!(variables["pos_embedding"].size(), vec![1, 50, 16]);
+        assert_eq!(variables["block1.mlp.fc0.weight"].size(), vec![32, 16]);
+        assert_eq!(
+            f64::from(
+                variables["block0.attention.proj.weight"]
+                    .abs()
+                    .sum(Kind::Float)
+            ),
+            0.0
+        );
+    }
+
+    #[test]
+    fn attention() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let attention = MultiHeadAttention::new(&vs.root(), 12, 3, 0.0);
+        let x = Tensor::rand(&[2, 5, 12], (Kind::Float, Device::Cpu));
+        let ys = attention.forward_t(&x, false);
+        assert_eq!(ys.size(), vec![2, 5, 12]);
+
+        // The attention is equivariant to the permutation of the tokens
+        let permutation = Tensor::of_slice(&[4_i64, 2, 0, 1, 3]);
+        let permuted = attention.forward_t(&x.index_select(1, &permutation), false);
+        assert!(permuted.allclose(&ys.index_select(1, &permutation), 1e-5, 1e-5, false));
+    }
+
+    #[test]
+    fn encoder() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config =
+            VitEncoderConfig::new(VitConfig::new(3, 0, 32).embed_dim(16).depth(4).heads(2))
+                .base_width(8);
+        let encoder = config.build::<3>(&(&vs.root() / "encoder"));
+        assert_eq!(encoder.chanels_count(), [8, 16, 32]);
+
+        let x = Tensor::rand(&[2, 3, 32, 32], (Kind::Float, Device::Cpu));
+        let (fms, ys) = encoder.forward_extracts_t(&x, true);
+        assert_eq!(fms[0].size(), vec![2, 8, 32, 32]);
+        assert_eq!(fms[1].size(), vec![2, 16, 16, 16]);
+        assert_eq!(fms[2].size(), vec![2, 32, 8, 8]);
+        assert_eq!(ys.size(), vec![2, 32, 4, 4]);
+        // The position embeddings are interpolated for other sizes
+        let (fms, _) = encoder.forward_extracts_t(
+            &Tensor::rand(&[3, 16, 48], (Kind::Float, Device::Cpu)),
+            false,
+        );
+        assert_eq!(fms[1].size(), vec![16, 8, 24]);
+
+        // The encoder backs a UNet like a BasicCNN
+        let unet = UNet::new(
+            &vs.root(),
+            encoder,
+            4,
+            UnetProps {
+                same_padding: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(unet.forward_t(&x, false).size(), vec![2, 4, 32, 32]);
+    }
+
+    #[test]
+    fn checkpoint() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = VitConfig::new(1, 10, 28)
+            .patch_size(7)
+            .embed_dim(8)
+            .depth(1)
+            .heads(2);
+        let vit = build_model::<ViT>(&vs, &config).unwrap();
+        let x = Tensor::rand(&[2, 784], (Kind::Float, Device::Cpu));
+        checkpoint_round_trip(&vs, &vit, &config, &x);
+
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = VitEncoderConfig::new(config).base_width(4);
+        let encoder = build_model::<ViTEncoder<1>>(&vs, &config).unwrap();
+        let x = Tensor::rand(&[2, 1, 28, 28], (Kind::Float, Device::Cpu));
+        checkpoint_round_trip(&vs, &encoder, &config, &x);
+    }
+}
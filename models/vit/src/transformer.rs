@@ -0,0 +1,229 @@
+use tch::{
+    nn::{self, Conv2D, ConvConfig, LayerNorm, Linear, Module, ModuleT},
+    Kind, Tensor,
+};
+use tch_utils::{
+    summary::{module_name, trace, trace_tokens},
+    tensor::interpolate2d,
+};
+
+use crate::VitConfig;
+
+/// Splits `[N,C,H,W]` images in non overlapping patches of `patch_size` pixels projected to `embed_dim` chanels
+/// by a strided convolution (`proj`)
+#[derive(Debug)]
+pub struct PatchEmbedding {
+    proj: Conv2D,
+    patch_size: i64,
+    name: String,
+}
+
+impl PatchEmbedding {
+    pub fn new(vs: &nn::Path, in_channels: i64, embed_dim: i64, patch_size: i64) -> Self {
+        let proj = nn::conv2d(
+            vs / "proj",
+            in_channels,
+            embed_dim,
+            patch_size,
+            ConvConfig {
+                stride: patch_size,
+                ..Default::default()
+            },
+        );
+        Self {
+            proj,
+            patch_size,
+            name: module_name(vs),
+        }
+    }
+
+    /// Tokens `[N, h*w, embed_dim]` of the images and the size `(h, w)` of the grid of patches
+    pub fn forward(&self, xs: &Tensor) -> (Tensor, (i64, i64)) {
+        let size = xs.size();
+        let (height, width) = (size[size.len() - 2], size[size.len() - 1]);
+        assert!(
+            height % self.patch_size == 0 && width % self.patch_size == 0,
+            "The size of the images ({height}x{width}) should be a multiple of the patch size ({})",
+            self.patch_size
+        );
+        let ys = self.proj.forward(xs);
+        trace(&self.name, &ys);
+        let grid = (height / self.patch_size, width / self.patch_size);
+        (ys.flatten(2, -1).transpose(1, 2), grid)
+    }
+}
+
+/// Multi-head self-attention (Vaswani et al. 2017) on `[N, T, D]` tokens : a joint projection of the queries,
+/// keys and values (`qkv`) followed by the projection of the concatenated heads (`proj`)
+#[derive(Debug)]
+pub struct MultiHeadAttention {
+    qkv: Linear,
+    proj: Linear,
+    heads: i64,
+    dropout: f64,
+}
+
+impl MultiHeadAttention {
+    pub fn new(vs: &nn::Path, dim: i64, heads: i64, dropout: f64) -> Self {
+        assert!(
+            heads > 0 && dim % heads == 0,
+            "The embedding dimention ({dim}) should be divisible by the number of heads ({heads})"
+        );
+        Self {
+            qkv: nn::linear(vs / "qkv", dim, 3 * dim, Default::default()),
+            proj: nn::linear(vs / "proj", dim, dim, Default::default()),
+            heads,
+            dropout,
+        }
+    }
+}
+
+impl ModuleT for MultiHeadAttention {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let (n, t, d) = xs.size3().expect("Expected [N,T,D] shaped tokens");
+        let head_dim = d / self.heads;
+        let qkv = self
+            .qkv
+            .forward(xs)
+            .view([n, t, 3, self.heads, head_dim])
+            .permute(&[2, 0, 3, 1, 4]);
+        let (q, k, v) = (qkv.get(0), qkv.get(1), qkv.get(2));
+
+        // Scaled dot-product attention of each head, [N, heads, T, T]
+        let attention = (q.matmul(&k.transpose(-2, -1)) / (head_dim as f64).sqrt())
+            .softmax(-1, Kind::Float)
+            .dropout(self.dropout, train);
+        let ys = attention.matmul(&v).transpose(1, 2).reshape(&[n, t, d]);
+        self.proj.forward(&ys).dropout(self.dropout, train)
+    }
+}
+
+/// Pre-norm transformer block : `x + attention(norm1(x))` followed by `x + mlp(norm2(x))`
+/// where the MLP (`mlp.fc0`, `mlp.fc1`) has a GELU hidden layer
+#[derive(Debug)]
+struct Block {
+    norm1: LayerNorm,
+    attention: MultiHeadAttention,
+    norm2: LayerNorm,
+    fc0: Linear,
+    fc1: Linear,
+    dropout: f64,
+    name: String,
+}
+
+impl Block {
+    fn new(vs: &nn::Path, config: &VitConfig) -> Self {
+        let dim = config.embed_dim;
+        let hidden = (dim as f64 * config.mlp_ratio).round() as i64;
+        let mlp = vs / "mlp";
+        Self {
+            norm1: nn::layer_norm(vs / "norm1", vec![dim], Default::default()),
+            attention: MultiHeadAttention::new(
+                &(vs / "attention"),
+                dim,
+                config.heads,
+                config.dropout,
+            ),
+            norm2: nn::layer_norm(vs / "norm2", vec![dim], Default::default()),
+            fc0: nn::linear(&mlp / "fc0", dim, hidden, Default::default()),
+            fc1: nn::linear(&mlp / "fc1", hidden, dim, Default::default()),
+            dropout: config.dropout,
+            name: module_name(vs),
+        }
+    }
+}
+
+impl ModuleT for Block {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let xs = xs + self.attention.forward_t(&self.norm1.forward(xs), train);
+        let hidden = self
+            .fc0
+            .forward(&self.norm2.forward(&xs))
+            .gelu()
+            .dropout(self.dropout, train);
+        let ys = &xs + self.fc1.forward(&hidden).dropout(self.dropout, train);
+        trace_tokens(&self.name, &ys);
+        ys
+    }
+}
+
+/// Trunk shared by the classifier and the encoder : the patch embedding (`patch_embed`), the class token (`cls_token`),
+/// the learned position embeddings (`pos_embedding`), the blocks (`block{i}`) and the final layer norm (`norm`)
+#[derive(Debug)]
+pub(crate) struct Transformer {
+    patch_embed: PatchEmbedding,
+    cls_token: Tensor,
+    pos_embedding: Tensor,
+    blocks: Vec<Block>,
+    norm: LayerNorm,
+    config: VitConfig,
+}
+
+impl Transformer {
+    pub(crate) fn new(vs: &nn::Path, config: &VitConfig) -> Self {
+        assert!(config.depth > 0, "Expected at least one block");
+        let grid = config.grid_size();
+        let dim = config.embed_dim;
+        let embedding_init = nn::Init::Randn {
+            mean: 0.0,
+            stdev: 0.02,
+        };
+        Self {
+            patch_embed: PatchEmbedding::new(
+                &(vs / "patch_embed"),
+                config.in_channels,
+                dim,
+                config.patch_size,
+            ),
+            cls_token: vs.var("cls_token", &[1, 1, dim], embedding_init),
+            pos_embedding: vs.var("pos_embedding", &[1, 1 + grid * grid, dim], embedding_init),
+            blocks: (0..config.depth)
+                .map(|i| Block::new(&(vs / format!("block{i}")), config))
+                .collect(),
+            norm: nn::layer_norm(vs / "norm", vec![dim], Default::default()),
+            config: config.clone(),
+        }
+    }
+
+    pub(crate) fn config(&self) -> &VitConfig {
+        &self.config
+    }
+
+    /// Position embeddings of the patches, interpolated when the grid differs from the one of `image_size`
+    fn position_embeddings(&self, (height, width): (i64, i64)) -> Tensor {
+        let grid = self.config.grid_size();
+        if (height, width) == (grid, grid) {
+            return self.pos_embedding.shallow_clone();
+        }
+        let dim = self.config.embed_dim;
+        let patches = self
+            .pos_embedding
+            .narrow(1, 1, grid * grid)
+            .transpose(1, 2)
+            .reshape(&[1, dim, grid, grid]);
+        let patches = interpolate2d(&patches, &[height, width], true)
+            .flatten(2, -1)
+            .transpose(1, 2);
+        Tensor::cat(&[self.pos_embedding.narrow(1, 0, 1), patches], 1)
+    }
+
+    /// Tokens `[N, 1 + h*w, D]` (the class token first) after each block and the size `(h, w)` of the grid of patches
+    pub(crate) fn forward_blocks_t(&self, xs: &Tensor, train: bool) -> (Vec<Tensor>, (i64, i64)) {
+        let (tokens, grid) = self.patch_embed.forward(xs);
+        let cls_token = self.cls_token.expand(&[tokens.size()[0], -1, -1], false);
+        let xs = (Tensor::cat(&[cls_token, tokens], 1) + self.position_embeddings(grid))
+            .dropout(self.config.dropout, train);
+
+        let mut outputs: Vec<Tensor> = Vec::with_capacity(self.blocks.len());
+        for block in self.blocks.iter() {
+            let ys = block.forward_t(outputs.last().unwrap_or(&xs), train);
+            outputs.push(ys);
+        }
+        (outputs, grid)
+    }
+
+    /// Final layer norm of the tokens
+    pub(crate) fn normalize(&self, tokens: &Tensor) -> Tensor {
+        self.norm.forward(tokens)
+    }
+}
@@ -1,16 +1,63 @@
 use tch::{
-    nn::{self, ConvConfig, ConvTranspose2D, ConvTransposeConfig, Module, Path, Sequential},
+    nn::{
+        self, Conv2D, ConvConfig, ConvTranspose2D, ConvTransposeConfig, Module, Path, Sequential,
+    },
     Tensor,
 };
+use tch_utils::{
+    tensor::interpolate2d,
+    types::{input, FeatureExtractor, MultiModuleT, NamedTensors},
+};
+
+/// Options of a [`VruNet`]
+#[derive(Debug, Clone)]
+pub struct VruNetProps {
+    /// Channels of the auxiliary input (0 to disable it)
+    pub aux_channels: i64,
+    /// Decoder levels receiving the auxiliary input (resized to their resolution), `None` for the deepest level only
+    pub aux_levels: Option<Vec<usize>>,
+    /// Adds a 1x1 convolution classifying the pixels, without it the features of the last decoder level are returned
+    pub class_count: Option<u32>,
+}
+
+impl Default for VruNetProps {
+    fn default() -> Self {
+        Self {
+            aux_channels: 4,
+            aux_levels: None,
+            class_count: None,
+        }
+    }
+}
+
+struct DecoderLayer {
+    layer: usize,
+    up: ConvTranspose2D,
+    convs: Sequential,
+    aux: bool,
+}
 
-pub struct VruNet<E: tch_utils::types::FeatureExtractor<L>, const L: usize> {
+pub struct VruNet<E: FeatureExtractor<L>, const L: usize> {
     encoder: E,
     center: Sequential,
-    decoder: Vec<(ConvTranspose2D, Sequential)>,
+    decoder: Vec<DecoderLayer>,
+    classifier: Option<Conv2D>,
 }
 
-impl<E: tch_utils::types::FeatureExtractor<L>, const L: usize> VruNet<E, L> {
+impl<E: FeatureExtractor<L>, const L: usize> VruNet<E, L> {
     pub fn new(vs: &Path, encoder: E) -> Self {
+        Self::new_props(vs, encoder, Default::default())
+    }
+
+    pub fn new_props(vs: &Path, encoder: E, props: VruNetProps) -> Self {
+        let chanels = encoder.chanels_count();
+        let aux_levels = props.aux_levels.unwrap_or_else(|| vec![L - 1]);
+        assert!(
+            aux_levels.iter().all(|l| *l < L),
+            "The auxiliary levels should be below {} got {:?}",
+            L,
+            aux_levels
+        );
         let conf = ConvConfig {
             padding: 1,
             ..Default::default()
@@ -21,16 +68,16 @@ impl<E: tch_utils::types::FeatureExtractor<L>, const L: usize> VruNet<E, L> {
             center
                 .add(nn::conv2d(
                     &(&vs / "conv1"),
-                    E::CHANELS_COUNT[L - 1],
-                    E::CHANELS_COUNT[L - 1] * 2,
+                    chanels[L - 1],
+                    chanels[L - 1] * 2,
                     3,
                     conf,
                 ))
                 .add_fn(Tensor::relu)
                 .add(nn::conv2d(
                     &(&vs / "conv1"),
-                    E::CHANELS_COUNT[L - 1] * 2,
-                    E::CHANELS_COUNT[L - 1] * 2,
+                    chanels[L - 1] * 2,
+                    chanels[L - 1] * 2,
                     3,
                     conf,
                 ))
@@ -40,17 +87,19 @@ impl<E: tch_utils::types::FeatureExtractor<L>, const L: usize> VruNet<E, L> {
             stride: 2,
             ..Default::default()
         };
-        dbg!(&conf);
         let decoder = {
             let vs = vs / "decoder";
-            E::CHANELS_COUNT
+            chanels
                 .iter()
                 .enumerate()
                 .rev()
                 .map(|(l, fc)| {
                     let vs = &vs / format!("layer{l}");
-                    let sub_sampl_dim = if l == L - 1 { 4 } else { 0 };
-                    let up = nn::conv_transpose2d(&(&vs / "upconv"), fc * 2, *fc, 2, conft);
+                    let aux = props.aux_channels > 0 && aux_levels.contains(&l);
+                    let sub_sampl_dim = if aux { props.aux_channels } else { 0 };
+                    // Channels coming from the center or from the previous decoder layer
+                    let in_channels = if l == L - 1 { fc * 2 } else { chanels[l + 1] };
+                    let up = nn::conv_transpose2d(&(&vs / "upconv"), in_channels, *fc, 2, conft);
                     let convs = nn::seq()
                         .add(nn::conv2d(
                             &(&vs / "conv1"),
@@ -62,44 +111,94 @@ impl<E: tch_utils::types::FeatureExtractor<L>, const L: usize> VruNet<E, L> {
                         .add_fn(Tensor::relu)
                         .add(nn::conv2d(&(&vs / "conv2"), *fc, *fc, 3, conf))
                         .add_fn(Tensor::relu);
-                    (up, convs)
+                    DecoderLayer {
+                        layer: l,
+                        up,
+                        convs,
+                        aux,
+                    }
                 })
                 .collect()
         };
+        let classifier = props.class_count.map(|class_count| {
+            nn::conv2d(
+                &(vs / "classifier"),
+                chanels[0],
+                class_count as i64,
+                1,
+                Default::default(),
+            )
+        });
         Self {
             encoder,
             center,
             decoder,
+            classifier,
         }
     }
 
     pub fn forward(&self, xp1: &Tensor, xp2: &Tensor) -> Tensor {
+        self.forward_t(xp1, xp2, false)
+    }
+
+    pub fn forward_t(&self, xp1: &Tensor, xp2: &Tensor, train: bool) -> Tensor {
         // NOTE : The specification of the decoder is bad returning an array doesnt allow to drop progressively the tensor as they are not needed
-        let (fms, bot) = self.encoder.forward_extracts(xp1);
+        let (fms, bot) = self.encoder.forward_extracts_t(xp1, train);
 
         let c = {
             let bot = bot; // We move tensors so that they can quickly be droped
             self.center.forward(&bot)
         };
 
-        let mut dec = self.decoder.iter();
+        let x = self.decoder.iter().fold(c, |x, layer| {
+            let x = layer.up.forward(&x);
+            let fm = &fms[layer.layer];
+            let x = if layer.aux {
+                // Resizing the auxiliary input to the resolution of the level
+                let size = fm.size();
+                let aux = interpolate2d(xp2, &size[size.len() - 2..], true);
+                Tensor::cat(&[&x, fm, &aux], -3)
+            } else {
+                Tensor::cat(&[&x, fm], -3)
+            };
+            layer.convs.forward(&x)
+        });
+
+        match &self.classifier {
+            Some(classifier) => classifier.forward(&x),
+            None => x,
+        }
+    }
 
-        let x = {
-            let c = c;
-            let (up, convs) = dec
-                .next()
-                .expect("The network should at least have one layer");
+    /// True if a decoder level receives the auxiliary input
+    pub fn uses_aux(&self) -> bool {
+        self.decoder.iter().any(|layer| layer.aux)
+    }
+}
 
-            let x = up.forward(&c);
-            let x = Tensor::cat(&[&x, &fms[L - 1], xp2], -3);
-            convs.forward(&x)
-        };
+impl<E: FeatureExtractor<L>, const L: usize> MultiModuleT for VruNet<E, L> {
+    fn input_names(&self) -> Vec<&str> {
+        if self.uses_aux() {
+            vec!["image", "aux"]
+        } else {
+            vec!["image"]
+        }
+    }
 
-        dec.enumerate().fold(x, |x, (i, (up, convs))| {
-            let x = up.forward(&x);
-            let x = Tensor::cat(&[&x, &fms[L - i - 2]], -3);
-            convs.forward(&x)
-        })
+    fn output_names(&self) -> Vec<&str> {
+        vec!["output"]
+    }
+
+    fn forward_multi_t(&self, inputs: &NamedTensors, train: bool) -> anyhow::Result<NamedTensors> {
+        let image = input(inputs, "image")?;
+        // The auxiliary input is never read when no level receives it
+        let aux = if self.uses_aux() {
+            input(inputs, "aux")?
+        } else {
+            image
+        };
+        let ys = self.forward_t(image, aux, train);
+        Ok(NamedTensors::from([("output".to_string(), ys)]))
     }
 }
 
@@ -109,18 +208,16 @@ mod tests {
         nn::{ConvConfig, VarStore},
         Device, Kind, Tensor,
     };
-    use unet::encoder::BasicCNN;
+    use tch_utils::types::{MultiModuleT, NamedTensors};
+    use unet::encoder::{BasicCNN, BasicCNNConfig};
 
-    use crate::VruNet;
+    use crate::{VruNet, VruNetProps};
 
     #[test]
     fn it_works() {
         let xa = Tensor::rand(&[10, 7, 256, 256], (Kind::Float, Device::Cpu));
         let xb = Tensor::rand(&[10, 4, 32, 32], (Kind::Float, Device::Cpu));
 
-        print!("{}", xa.requires_grad());
-        print!("{}", xb.requires_grad());
-
         let vs = VarStore::new(Device::Cpu);
 
         let cnv_cfg = ConvConfig {
@@ -132,4 +229,55 @@ mod tests {
 
         let _res = tnet.forward(&xa, &xb);
     }
+
+    #[test]
+    fn props_and_backward() {
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(4)
+            .conv_config(ConvConfig {
+                padding: 1,
+                ..Default::default()
+            })
+            .build::<3>(&(&vs.root() / "encoder"));
+        let props = VruNetProps {
+            aux_channels: 2,
+            aux_levels: Some(vec![0, 2]),
+            class_count: Some(3),
+        };
+        let net = VruNet::new_props(&vs.root(), encoder, props);
+
+        let xa = Tensor::rand(&[2, 3, 32, 32], (Kind::Float, Device::Cpu));
+        let xb = Tensor::rand(&[2, 2, 5, 5], (Kind::Float, Device::Cpu));
+        let y = net.forward_t(&xa, &xb, true);
+        assert_eq!(y.size(), vec![2, 3, 32, 32]);
+
+        y.mean(Kind::Float).backward();
+        for var in vs.trainable_variables() {
+            assert!(var.grad().defined());
+        }
+    }
+
+    #[test]
+    fn multi_input() {
+        let vs = VarStore::new(Device::Cpu);
+        let encoder = BasicCNNConfig::new(3)
+            .base_width(4)
+            .conv_config(ConvConfig {
+                padding: 1,
+                ..Default::default()
+            })
+            .build::<2>(&(&vs.root() / "encoder"));
+        let net = VruNet::new(&vs.root(), encoder);
+        assert_eq!(net.input_names(), ["image", "aux"]);
+
+        let xa = Tensor::rand(&[1, 3, 16, 16], (Kind::Float, Device::Cpu));
+        let xb = Tensor::rand(&[1, 4, 8, 8], (Kind::Float, Device::Cpu));
+        let mut inputs = NamedTensors::from([("image".to_string(), xa.shallow_clone())]);
+        assert!(net.forward_multi(&inputs).is_err());
+
+        inputs.insert("aux".to_string(), xb.shallow_clone());
+        let outputs = net.forward_multi(&inputs).unwrap();
+        assert!(outputs["output"].equal(&net.forward(&xa, &xb)));
+    }
 }
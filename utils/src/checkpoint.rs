@@ -0,0 +1,188 @@
+use std::{collections::BTreeMap, fmt::Display};
+use tch::{Kind, Tensor};
+
+use crate::import::NameMapping;
+
+/// Shape, kind and statistics of a tensor of a checkpoint
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorStats {
+    pub name: String,
+    pub shape: Vec<i64>,
+    pub kind: Kind,
+    /// L2 norm of the values
+    pub norm: f64,
+    pub mean: f64,
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl TensorStats {
+    pub fn new(name: &str, tensor: &Tensor) -> Self {
+        let values = tensor.to_kind(Kind::Double);
+        let (norm, mean, std, min, max) = match tensor.numel() {
+            0 => (0.0, 0.0, 0.0, 0.0, 0.0),
+            numel => (
+                f64::from(values.norm()),
+                f64::from(values.mean(Kind::Double)),
+                // The unbiased std of a single value is not defined
+                if numel > 1 {
+                    f64::from(values.std(true))
+                } else {
+                    0.0
+                },
+                f64::from(values.min()),
+                f64::from(values.max()),
+            ),
+        };
+        Self {
+            name: name.to_string(),
+            shape: tensor.size(),
+            kind: tensor.kind(),
+            norm,
+            mean,
+            std,
+            min,
+            max,
+        }
+    }
+}
+
+/// Statistics of every tensor of a checkpoint, sorted by name
+pub fn stats(tensors: &[(String, Tensor)]) -> Vec<TensorStats> {
+    let mut stats: Vec<_> = tensors
+        .iter()
+        .map(|(name, tensor)| TensorStats::new(name, tensor))
+        .collect();
+    stats.sort_by(|a, b| a.name.cmp(&b.name));
+    stats
+}
+
+/// Difference between the tensors of two checkpoints
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CheckpointDiff {
+    /// Tensors in both checkpoints : `(name, L2 norm of the difference, relative to the norm of the first one)`
+    pub common: Vec<(String, f64, f64)>,
+    /// Tensors in both checkpoints with different shapes : `(name, first shape, second shape)`
+    pub shape_mismatch: Vec<(String, Vec<i64>, Vec<i64>)>,
+    /// Tensors only in the first checkpoint
+    pub only_first: Vec<String>,
+    /// Tensors only in the second checkpoint
+    pub only_second: Vec<String>,
+}
+
+impl CheckpointDiff {
+    pub fn new(first: &[(String, Tensor)], second: &[(String, Tensor)]) -> Self {
+        let first: BTreeMap<_, _> = first.iter().map(|(n, t)| (n, t)).collect();
+        let second: BTreeMap<_, _> = second.iter().map(|(n, t)| (n, t)).collect();
+        let mut diff = Self::default();
+        for (name, a) in first.iter() {
+            let b = match second.get(name) {
+                Some(b) => b,
+                None => {
+                    diff.only_first.push(name.to_string());
+                    continue;
+                }
+            };
+            if a.size() != b.size() {
+                diff.shape_mismatch
+                    .push((name.to_string(), a.size(), b.size()));
+                continue;
+            }
+            let a = a.to_kind(Kind::Double);
+            let b = b.to_device(a.device()).to_kind(Kind::Double);
+            let l2 = f64::from((&a - &b).norm());
+            let norm = f64::from(a.norm());
+            let relative = if norm > 0.0 { l2 / norm } else { l2 };
+            diff.common.push((name.to_string(), l2, relative));
+        }
+        diff.only_second = second
+            .keys()
+            .filter(|name| !first.contains_key(*name))
+            .map(|name| name.to_string())
+            .collect();
+        diff
+    }
+
+    /// True if both checkpoints have the same tensors with a difference below `tolerance`
+    pub fn is_same(&self, tolerance: f64) -> bool {
+        self.shape_mismatch.is_empty()
+            && self.only_first.is_empty()
+            && self.only_second.is_empty()
+            && self.common.iter().all(|(_, l2, _)| *l2 <= tolerance)
+    }
+}
+
+impl Display for CheckpointDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = self
+            .common
+            .iter()
+            .map(|(name, _, _)| name.len())
+            .max()
+            .unwrap_or(0)
+            .max(6);
+        writeln!(f, "{:width$}  {:>12}  {:>12}", "tensor", "l2", "relative")?;
+        for (name, l2, relative) in self.common.iter() {
+            writeln!(f, "{name:width$}  {l2:>12.6e}  {relative:>12.6e}")?;
+        }
+        for (name, first, second) in self.shape_mismatch.iter() {
+            writeln!(f, "mismatch       : {name} {first:?} != {second:?}")?;
+        }
+        for name in self.only_first.iter() {
+            writeln!(f, "only in first  : {name}")?;
+        }
+        for name in self.only_second.iter() {
+            writeln!(f, "only in second : {name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Renames the tensors of a checkpoint, e.g. `NameMapping::new().rule("", "model")`
+/// moves the weights of `MLP::new(&vs.root(), ..)` to the ones of `MLP::new(&(vs.root() / "model"), ..)`
+pub fn rename(tensors: Vec<(String, Tensor)>, mapping: &NameMapping) -> Vec<(String, Tensor)> {
+    tensors
+        .into_iter()
+        .map(|(name, tensor)| (mapping.map(&name), tensor))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{Device, Kind, Tensor};
+
+    use super::{rename, stats, CheckpointDiff};
+    use crate::import::NameMapping;
+
+    #[test]
+    fn diff() {
+        let ones = |size: &[i64]| Tensor::ones(size, (Kind::Float, Device::Cpu));
+        let first = vec![
+            ("layer0.weight".to_string(), ones(&[2, 2])),
+            ("layer0.bias".to_string(), ones(&[2])),
+            ("layer1.weight".to_string(), ones(&[2, 2])),
+        ];
+        let second = vec![
+            ("layer0.weight".to_string(), ones(&[2, 2]) * 2),
+            ("layer0.bias".to_string(), ones(&[3])),
+            ("layer2.weight".to_string(), ones(&[2, 2])),
+        ];
+
+        let stats = stats(&first);
+        assert_eq!(stats[0].name, "layer0.bias");
+        assert_eq!(stats[1].norm, 2.0);
+        assert_eq!(stats[1].std, 0.0);
+
+        let diff = CheckpointDiff::new(&first, &second);
+        assert_eq!(diff.common, vec![("layer0.weight".to_string(), 2.0, 1.0)]);
+        assert_eq!(diff.shape_mismatch.len(), 1);
+        assert_eq!(diff.only_first, vec!["layer1.weight".to_string()]);
+        assert_eq!(diff.only_second, vec!["layer2.weight".to_string()]);
+        assert!(!diff.is_same(0.0));
+        assert!(CheckpointDiff::new(&first, &first).is_same(0.0));
+
+        let renamed = rename(first, &NameMapping::new().rule("", "model"));
+        assert_eq!(renamed[0].0, "model.layer0.weight");
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tch::{nn, Device, Tensor};
+
+use crate::init::Initialization;
+
+/// Name of the weights in a checkpoint directory, the config is stored next to them (see [`config_path`])
+pub const WEIGHTS_FILE: &str = "weights.pt";
+
+/// Models that can be rebuilt from a serializable config
+pub trait FromConfig: Sized {
+    type Config: Serialize + DeserializeOwned;
+
+    fn from_config(vs: &nn::Path, config: &Self::Config) -> anyhow::Result<Self>;
+
+    /// Initialization of the variables described by the config, applied by [`build_model`]
+    fn initialization(_config: &Self::Config) -> Initialization {
+        Initialization::default()
+    }
+}
+
+/// Builds a model at the root of the store and initializes its variables as described by the config
+pub fn build_model<M: FromConfig>(vs: &nn::VarStore, config: &M::Config) -> anyhow::Result<M> {
+    let model = M::from_config(&vs.root(), config)?;
+    M::initialization(config).apply(vs, "");
+    Ok(model)
+}
+
+/// Path of the config stored next to the weights of a model
+pub fn config_path(weights: &Path) -> PathBuf {
+    weights.with_extension("json")
+}
+
+/// Writes the config next to the weights
+pub fn save_config<C: Serialize>(config: &C, weights: &Path) -> anyhow::Result<()> {
+    let path = config_path(weights);
+    let content = serde_json::to_string_pretty(config)?;
+    fs::write(&path, content).with_context(|| format!("Couldnt write {path:?}"))
+}
+
+/// Reads the config stored next to the weights
+pub fn load_config<C: DeserializeOwned>(weights: &Path) -> anyhow::Result<C> {
+    let path = config_path(weights);
+    let content = fs::read_to_string(&path).with_context(|| format!("Couldnt read {path:?}"))?;
+    serde_json::from_str(&content).with_context(|| format!("Invalid config {path:?}"))
+}
+
+/// Rebuilds a model from the config stored next to its weights and loads the weights
+pub fn load_model<M: FromConfig>(
+    weights: &Path,
+    device: Device,
+) -> anyhow::Result<(nn::VarStore, M)> {
+    let config = load_config(weights)?;
+    let mut vs = nn::VarStore::new(device);
+    let model = M::from_config(&vs.root(), &config)?;
+    vs.load(weights)
+        .with_context(|| format!("Couldnt load the weights {weights:?}"))?;
+    Ok((vs, model))
+}
+
+/// Saves the weights and the config of a model in a checkpoint directory
+pub fn save_checkpoint<C: Serialize>(
+    dir: &Path,
+    vs: &nn::VarStore,
+    config: &C,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(dir)?;
+    let weights = dir.join(WEIGHTS_FILE);
+    vs.save(&weights)?;
+    save_config(config, &weights)
+}
+
+/// Rebuilds a model from a checkpoint directory written by [`save_checkpoint`]
+pub fn load_checkpoint<M: FromConfig>(
+    dir: &Path,
+    device: Device,
+) -> anyhow::Result<(nn::VarStore, M)> {
+    load_model(&dir.join(WEIGHTS_FILE), device)
+}
+
+/// Saves `model` with [`save_checkpoint`] in a temporary folder unique to the call, loads it back
+/// and checks that both give the same outputs for `xs` in evaluation mode. Meant for the tests of the models.
+pub fn checkpoint_round_trip<M: FromConfig + nn::ModuleT>(
+    vs: &nn::VarStore,
+    model: &M,
+    config: &M::Config,
+    xs: &Tensor,
+) -> M {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "checkpoint-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    save_checkpoint(&dir, vs, config).expect("Couldnt save the checkpoint");
+    let loaded = load_checkpoint::<M>(&dir, vs.device());
+    fs::remove_dir_all(&dir).expect("Couldnt remove the checkpoint");
+    let (_, loaded) = loaded.expect("Couldnt load the checkpoint");
+
+    assert!(
+        loaded
+            .forward_t(xs, false)
+            .allclose(&model.forward_t(xs, false), 1e-6, 1e-6, false),
+        "The loaded model doesnt give the outputs of the saved one"
+    );
+    loaded
+}
+
+/// Serialization of the architecture related fields of a [`nn::ConvConfig`], to be used with `#[serde(with = "...")]`.
+/// The initializations are not saved as they don't change the architecture.
+pub mod conv_config {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use tch::nn::ConvConfig;
+
+    #[derive(Serialize, Deserialize)]
+    struct ConvSettings {
+        stride: i64,
+        padding: i64,
+        dilation: i64,
+        groups: i64,
+        bias: bool,
+    }
+
+    pub fn serialize<S: Serializer>(config: &ConvConfig, serializer: S) -> Result<S::Ok, S::Error> {
+        ConvSettings {
+            stride: config.stride,
+            padding: config.padding,
+            dilation: config.dilation,
+            groups: config.groups,
+            bias: config.bias,
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ConvConfig, D::Error> {
+        let settings = ConvSettings::deserialize(deserializer)?;
+        Ok(ConvConfig {
+            stride: settings.stride,
+            padding: settings.padding,
+            dilation: settings.dilation,
+            groups: settings.groups,
+            bias: settings.bias,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use tch::nn::ConvConfig;
+
+    use crate::layers::{Activation, Normalization};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Config {
+        activation: Activation,
+        normalization: Normalization,
+        #[serde(with = "super::conv_config")]
+        conv: ConvConfig,
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = Config {
+            activation: Activation::GELU,
+            normalization: Normalization::Group(4),
+            conv: ConvConfig {
+                padding: 1,
+                bias: false,
+                ..Default::default()
+            },
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        let config: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(config.activation, Activation::GELU);
+        assert_eq!(config.normalization, Normalization::Group(4));
+        assert_eq!(config.conv.padding, 1);
+        assert!(!config.conv.bias);
+    }
+}
@@ -1,17 +1,47 @@
 use anyhow::bail;
 use std::{
-    fs::{self, ReadDir},
+    fs::{self, File, ReadDir},
     io,
     path::{Path, PathBuf},
 };
+use tch::{vision::image, Tensor};
 use thiserror::Error;
+use tiff::decoder::{Decoder, DecodingResult};
+
+use crate::tensor::{extract_patch, patch_starts};
 
 pub trait Dataset<X: Send, Y: Send>: Iterator<Item = (X, Y)> + Send {}
 
 #[derive(Debug, Error)]
 enum DataSetError {
-    #[error("The two folder dont have the same size")]
+    #[error("The folders dont have the same size")]
     NotTheSameSize,
+    #[error("Only 8 bits tiff images are supported")]
+    UnsupportedTiff,
+}
+
+/// Loads an RGB image as a `[3,H,W]` uint8 tensor, the tiff images are read with `tiff`
+/// and the other formats with `tch::vision::image`
+pub fn load_image(path: &Path) -> anyhow::Result<Tensor> {
+    let is_tiff = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("tif" | "tiff")
+    );
+    if !is_tiff {
+        return Ok(image::load(path)?);
+    }
+
+    let file = File::open(path)?;
+    let mut decoder = Decoder::new(file)?;
+    let (x, y) = decoder.dimensions()?;
+    let img = match decoder.read_image()? {
+        DecodingResult::U8(i) => i,
+        _ => bail!(DataSetError::UnsupportedTiff),
+    };
+    Ok(Tensor::from(img.as_slice())
+        .reshape(&[y as i64, x as i64, 3])
+        .swapaxes(0, 2)
+        .swapaxes(1, 2))
 }
 
 pub struct Datafolder<'a, X: Send, Y: Send> {
@@ -88,6 +118,157 @@ impl<'a, X: Send, Y: Send> Iterator for Datafolder<'a, X, Y> {
     }
 }
 
+/// Lists the files of a folder sorted by name
+fn list_folder(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !path.exists() {
+        bail!(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{path:?} not found")
+        ));
+    }
+    if !path.is_dir() {
+        bail!(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{path:?} is not a directory")
+        ))
+    }
+    let mut files = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.sort();
+    Ok(files)
+}
+
+/// Dataset of `((x, aux), y)` triplets loaded from three folders (e.g. image, auxiliary input and mask),
+/// the files are matched by their order in the folders sorted by name.
+pub struct TripletFolder<'a, X: Send, A: Send, Y: Send> {
+    x_loader: &'a (dyn Fn(PathBuf) -> X + Send + Sync),
+    aux_loader: &'a (dyn Fn(PathBuf) -> A + Send + Sync),
+    y_loader: &'a (dyn Fn(PathBuf) -> Y + Send + Sync),
+    files: std::vec::IntoIter<(PathBuf, PathBuf, PathBuf)>,
+}
+
+impl<'a, X: Send, A: Send, Y: Send> TripletFolder<'a, X, A, Y> {
+    pub fn from(
+        path: &Path,
+        folders: [&str; 3],
+        x_loader: &'a (dyn Fn(PathBuf) -> X + Send + Sync),
+        aux_loader: &'a (dyn Fn(PathBuf) -> A + Send + Sync),
+        y_loader: &'a (dyn Fn(PathBuf) -> Y + Send + Sync),
+    ) -> anyhow::Result<Self> {
+        let [xs, auxs, ys] = folders;
+        let xs = list_folder(&path.join(xs))?;
+        let auxs = list_folder(&path.join(auxs))?;
+        let ys = list_folder(&path.join(ys))?;
+        if xs.len() != auxs.len() || xs.len() != ys.len() {
+            bail!(DataSetError::NotTheSameSize);
+        }
+        let files: Vec<_> = xs
+            .into_iter()
+            .zip(auxs)
+            .zip(ys)
+            .map(|((x, aux), y)| (x, aux, y))
+            .collect();
+        Ok(Self {
+            x_loader,
+            aux_loader,
+            y_loader,
+            files: files.into_iter(),
+        })
+    }
+
+    /// Number of triplets left
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a, X: Send, A: Send, Y: Send> Dataset<(X, A), Y> for TripletFolder<'a, X, A, Y> {}
+
+impl<'a, X: Send, A: Send, Y: Send> Iterator for TripletFolder<'a, X, A, Y> {
+    type Item = ((X, A), Y);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (x, aux, y) = self.files.next()?;
+        Some((
+            ((self.x_loader)(x), (self.aux_loader)(aux)),
+            (self.y_loader)(y),
+        ))
+    }
+}
+
+/// Dataset of the 3D patches of a set of volumes.
+///
+/// The volumes are `[C,D,H,W]` shaped (the masks may have other channels) and are cut in patches of
+/// `patch` every `stride` voxels, the last patches of each dimension are moved back to cover the whole volume.
+/// ```ignore
+/// let volumes = Datafolder::from(path, "images".into(), "masks".into(), &load, &load)?.collect();
+/// let patches = VolumePatches::new(volumes, [64; 3], [32; 3]);
+/// ```
+pub struct VolumePatches {
+    volumes: Vec<(Tensor, Tensor)>,
+    patch: [i64; 3],
+    positions: Vec<(usize, [i64; 3])>,
+    index: usize,
+}
+
+impl VolumePatches {
+    pub fn new(volumes: Vec<(Tensor, Tensor)>, patch: [i64; 3], stride: [i64; 3]) -> Self {
+        let mut positions = vec![];
+        for (v, (x, y)) in volumes.iter().enumerate() {
+            let size = x.size();
+            assert!(
+                size.len() >= 3 && size[size.len() - 3..] == y.size()[y.dim() - 3..],
+                "The volume and the mask should have the same last 3 dimentions got {:?} and {:?}",
+                size,
+                y.size()
+            );
+            let size = &size[size.len() - 3..];
+            let starts: Vec<_> = (0..3)
+                .map(|i| patch_starts(size[i], patch[i], stride[i]))
+                .collect();
+            for d in starts[0].iter() {
+                for h in starts[1].iter() {
+                    for w in starts[2].iter() {
+                        positions.push((v, [*d, *h, *w]));
+                    }
+                }
+            }
+        }
+        Self {
+            volumes,
+            patch,
+            positions,
+            index: 0,
+        }
+    }
+
+    /// Number of patches of the dataset
+    pub fn patch_count(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+impl Dataset<Tensor, Tensor> for VolumePatches {}
+
+impl Iterator for VolumePatches {
+    type Item = (Tensor, Tensor);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (v, start) = self.positions.get(self.index)?;
+        self.index += 1;
+        let (x, y) = &self.volumes[*v];
+        Some((
+            extract_patch(x, start, &self.patch),
+            extract_patch(y, start, &self.patch),
+        ))
+    }
+}
+
 /*pub trait DataLoader<X, Y, BX, BY>: Iterator<Item = (BX, BY)>
     where BX: FromIterator<X>, BY: FromIterator<Y>{}
 
@@ -120,3 +301,22 @@ impl< X: Send, Y: Send, BX, BY> Iterator for SingleThreadedDataLoader<X, Y, BX,
         self.dataset.chunks(self.batch_size)
     }
 } */
+
+#[cfg(test)]
+mod tests {
+    use tch::{Device, Kind, Tensor};
+
+    use super::VolumePatches;
+
+    #[test]
+    fn patches() {
+        let volume = Tensor::rand(&[1, 20, 16, 12], (Kind::Float, Device::Cpu));
+        let mask = volume.gt(0.5);
+        let patches = VolumePatches::new(vec![(volume, mask)], [8, 8, 8], [8, 8, 8]);
+        assert_eq!(patches.patch_count(), 3 * 2 * 2);
+        for (x, y) in patches {
+            assert_eq!(x.size(), vec![1, 8, 8, 8]);
+            assert_eq!(y.size(), vec![1, 8, 8, 8]);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use tch::nn::{Optimizer, OptimizerConfig, VarStore};
+
+fn normalize(prefix: &str) -> String {
+    prefix
+        .trim_matches(|c| c == '.' || c == '/')
+        .replace('/', ".")
+}
+
+fn has_prefix(name: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || name == prefix
+        || (name.starts_with(prefix) && name[prefix.len()..].starts_with('.'))
+}
+
+fn set_requires_grad(vs: &VarStore, prefix: &str, requires_grad: bool) -> usize {
+    let prefix = normalize(prefix);
+    let variables = vs.variables_.lock().unwrap();
+    let trainables: Vec<_> = variables
+        .trainable_variables
+        .iter()
+        .map(|var| var.tensor.data_ptr())
+        .collect();
+    let mut count = 0;
+    for (name, var) in variables.named_variables.iter() {
+        // Buffers such as the running stats of the batch norms are never trained
+        if has_prefix(name, &prefix) && trainables.contains(&var.data_ptr()) {
+            let _ = var.set_requires_grad(requires_grad);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Freezes the variables whose path starts with `prefix` (e.g. `encoder/`).
+/// Returns the number of variables frozen.
+pub fn freeze(vs: &VarStore, prefix: &str) -> usize {
+    set_requires_grad(vs, prefix, false)
+}
+
+/// Unfreezes the variables whose path starts with `prefix`.
+/// Returns the number of variables unfrozen.
+pub fn unfreeze(vs: &VarStore, prefix: &str) -> usize {
+    set_requires_grad(vs, prefix, true)
+}
+
+/// Learning rate and weight decay applied to the variables under a prefix
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamGroup {
+    pub prefix: String,
+    pub lr: f64,
+    pub weight_decay: f64,
+}
+
+/// Per-prefix hyper-parameters used to build an optimizer.
+///
+/// The variables matched by no prefix use the default learning rate and weight decay,
+/// when several prefixes match a variable the longest one is used.
+/// ```ignore
+/// let groups = ParamGroups::new(1e-3, 0.0).group("encoder", 1e-4, 1e-5);
+/// let mut opt = groups.build(&vs, nn::Adam::default())?;
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamGroups {
+    pub lr: f64,
+    pub weight_decay: f64,
+    pub groups: Vec<ParamGroup>,
+}
+
+impl ParamGroups {
+    pub fn new(lr: f64, weight_decay: f64) -> Self {
+        Self {
+            lr,
+            weight_decay,
+            groups: vec![],
+        }
+    }
+
+    /// Adds a group for the variables under `prefix`
+    pub fn group(mut self, prefix: &str, lr: f64, weight_decay: f64) -> Self {
+        self.groups.push(ParamGroup {
+            prefix: normalize(prefix),
+            lr,
+            weight_decay,
+        });
+        self
+    }
+
+    /// Index of the optimizer group used for the variable `name` (0 is the default group)
+    pub fn group_index(&self, name: &str) -> usize {
+        self.groups
+            .iter()
+            .enumerate()
+            .filter(|(_, group)| has_prefix(name, &group.prefix))
+            .max_by_key(|(_, group)| group.prefix.len())
+            .map(|(i, _)| i + 1)
+            .unwrap_or(0)
+    }
+
+    /// Index of the optimizer group of `prefix`
+    pub fn index_of(&self, prefix: &str) -> Option<usize> {
+        let prefix = normalize(prefix);
+        self.groups
+            .iter()
+            .position(|group| group.prefix == prefix)
+            .map(|i| i + 1)
+    }
+
+    /// Assigns the variables of `vs` to their group and builds the optimizer.
+    ///
+    /// The groups are stored in the `VarStore` so this should be called after the model is created.
+    pub fn build<OC: OptimizerConfig>(
+        &self,
+        vs: &VarStore,
+        config: OC,
+    ) -> anyhow::Result<Optimizer> {
+        {
+            let mut variables = vs.variables_.lock().unwrap();
+            let groups: Vec<_> = variables
+                .named_variables
+                .iter()
+                .map(|(name, var)| (var.data_ptr(), self.group_index(name)))
+                .collect();
+            for var in variables.trainable_variables.iter_mut() {
+                if let Some((_, group)) =
+                    groups.iter().find(|(ptr, _)| *ptr == var.tensor.data_ptr())
+                {
+                    var.group = *group;
+                }
+            }
+        }
+
+        let mut opt = config.build(vs, self.lr)?;
+        opt.set_weight_decay_group(0, self.weight_decay);
+        for (i, group) in self.groups.iter().enumerate() {
+            opt.set_lr_group(i + 1, group.lr);
+            opt.set_weight_decay_group(i + 1, group.weight_decay);
+        }
+        Ok(opt)
+    }
+
+    /// Sets the learning rate of every group with the same factor (useful for schedulers)
+    pub fn scale_lr(&self, opt: &mut Optimizer, factor: f64) {
+        opt.set_lr_group(0, self.lr * factor);
+        for (i, group) in self.groups.iter().enumerate() {
+            opt.set_lr_group(i + 1, group.lr * factor);
+        }
+    }
+}
+
+/// Warm-up of a pretrained part of a model : the variables under `prefix` are frozen during the first
+/// `frozen_epochs` epochs and are then trained normally.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarmUp {
+    pub prefix: String,
+    pub frozen_epochs: u32,
+}
+
+impl WarmUp {
+    pub fn new(prefix: &str, frozen_epochs: u32) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            frozen_epochs,
+        }
+    }
+
+    /// Freezes or unfreezes the variables for the epoch `epoch` (starting at 1).
+    /// Returns true when the state of the variables changed.
+    pub fn step(&self, vs: &VarStore, epoch: u32) -> bool {
+        if self.frozen_epochs == 0 {
+            return false;
+        }
+        if epoch == 1 {
+            freeze(vs, &self.prefix);
+            true
+        } else if epoch == self.frozen_epochs + 1 {
+            unfreeze(vs, &self.prefix);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// True if the variables are frozen during the epoch `epoch`
+    pub fn is_frozen(&self, epoch: u32) -> bool {
+        epoch <= self.frozen_epochs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{self, VarStore},
+        Device,
+    };
+
+    use super::{freeze, unfreeze, ParamGroups};
+
+    #[test]
+    fn freezing() {
+        let vs = VarStore::new(Device::Cpu);
+        let _ = nn::linear(&vs.root() / "encoder", 2, 2, Default::default());
+        let _ = nn::linear(&vs.root() / "decoder", 2, 2, Default::default());
+
+        assert_eq!(freeze(&vs, "encoder/"), 2);
+        let variables = vs.variables();
+        assert!(!variables["encoder.weight"].requires_grad());
+        assert!(variables["decoder.weight"].requires_grad());
+
+        assert_eq!(unfreeze(&vs, "encoder"), 2);
+        assert!(variables["encoder.bias"].requires_grad());
+    }
+
+    #[test]
+    fn groups() {
+        let groups = ParamGroups::new(1e-3, 0.0)
+            .group("encoder", 1e-4, 0.0)
+            .group("encoder/layer0", 1e-5, 0.0);
+        assert_eq!(groups.group_index("decoder.layer0.weight"), 0);
+        assert_eq!(groups.group_index("encoder.layer1.0.weight"), 1);
+        assert_eq!(groups.group_index("encoder.layer0.0.weight"), 2);
+        assert_eq!(groups.group_index("encoderx.weight"), 0);
+        assert_eq!(groups.index_of("encoder/"), Some(1));
+        assert_eq!(groups.index_of("decoder"), None);
+    }
+}
@@ -0,0 +1,296 @@
+use anyhow::{bail, Context};
+use std::{fmt::Display, fs, path::Path};
+use tch::{nn::VarStore, Tensor};
+use thiserror::Error;
+
+use crate::pickle::{is_torch_save, load_torch_save};
+
+#[derive(Debug, Error)]
+enum ImportError {
+    #[error("Invalid mapping rule on line {0} : expected `<source> <target>`")]
+    InvalidRule(usize),
+}
+
+/// Table of rules renaming the entries of a PyTorch `state_dict` into the paths of a `VarStore`.
+///
+/// A rule replaces a prefix of the name by another one, prefixes are only matched on whole components
+/// (`features.1` matches `features.1.weight` but not `features.10.weight`).
+/// When several rules match the longest one wins, names matched by no rule are kept as is.
+/// An empty source matches every name (`"" -> "model"` moves everything under `model`)
+/// and an empty target removes the prefix.
+/// Both `.` and `/` are accepted as separators so `encoder/layer0/0` and `encoder.layer0.0` are the same target.
+#[derive(Debug, Default, Clone)]
+pub struct NameMapping {
+    rules: Vec<(String, String)>,
+}
+
+impl NameMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule renaming the prefix `from` into `to`
+    pub fn rule(mut self, from: &str, to: &str) -> Self {
+        self.rules.push((normalize(from), normalize(to)));
+        self
+    }
+
+    /// Reads a mapping table from a file with one `<source> <target>` rule per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path).with_context(|| format!("Couldnt read {path:?}"))?;
+        let mut mapping = Self::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(from), Some(to), None) => mapping = mapping.rule(from, to),
+                _ => bail!(ImportError::InvalidRule(i + 1)),
+            }
+        }
+        Ok(mapping)
+    }
+
+    /// Gives the `VarStore` name of an entry of the `state_dict`
+    pub fn map(&self, name: &str) -> String {
+        let name = normalize(name);
+        self.rules
+            .iter()
+            .filter(|(from, _)| has_prefix(&name, from))
+            .max_by_key(|(from, _)| from.len())
+            .map(|(from, to)| match (from.is_empty(), to.is_empty()) {
+                (true, false) => format!("{to}.{name}"),
+                (false, true) => name[from.len()..].trim_start_matches('.').to_string(),
+                _ => format!("{to}{}", &name[from.len()..]),
+            })
+            .unwrap_or(name)
+    }
+}
+
+/// True if `prefix` is empty or made of whole leading components of `name`
+fn has_prefix(name: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || name == prefix
+        || (name.starts_with(prefix) && name[prefix.len()..].starts_with('.'))
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_matches(|c| c == '.' || c == '/')
+        .replace('/', ".")
+}
+
+/// Outcome of an import
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Variables of the `VarStore` that were overwritten
+    pub loaded: Vec<String>,
+    /// Variables of the `VarStore` (under the imported prefix) that were not found in the file
+    pub missing: Vec<String>,
+    /// Entries of the file that do not correspond to any variable of the `VarStore` under the imported prefix
+    pub unexpected: Vec<String>,
+    /// Entries whose shape differ from the variable's : `(name, expected, found)`
+    pub shape_mismatch: Vec<(String, Vec<i64>, Vec<i64>)>,
+}
+
+impl ImportReport {
+    /// True if every variable under the prefix was loaded
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.shape_mismatch.is_empty()
+    }
+}
+
+impl Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "loaded {} tensors", self.loaded.len())?;
+        for name in self.missing.iter() {
+            writeln!(f, "missing    : {name}")?;
+        }
+        for name in self.unexpected.iter() {
+            writeln!(f, "unexpected : {name}")?;
+        }
+        for (name, expected, found) in self.shape_mismatch.iter() {
+            writeln!(
+                f,
+                "mismatch   : {name} expected {expected:?} found {found:?}"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads the named tensors of a `state_dict` file.
+///
+/// The files written by `torch.save(model.state_dict(), "weights.pt")` are read with [`load_torch_save`],
+/// `.npz` archives are read with numpy's format and anything else is expected to be a libtorch archive.
+/// A `state_dict` can also be exported from Python with :
+/// ```python
+/// np.savez("weights.npz", **{k: v.cpu().numpy() for k, v in model.state_dict().items()})
+/// ```
+pub fn load_state_dict(path: &Path) -> anyhow::Result<Vec<(String, Tensor)>> {
+    let tensors = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("npz") => Ok(Tensor::read_npz(path)?),
+        _ if is_torch_save(path) => load_torch_save(path),
+        _ => Ok(Tensor::load_multi(path)?),
+    };
+    tensors.with_context(|| format!("Couldnt load the state dict {path:?}"))
+}
+
+/// Copies the tensors of a `state_dict` file into the variables of `vs` after renaming them with `mapping`.
+///
+/// Only the variables whose name starts with `prefix` are loaded (e.g. `encoder`),
+/// the others are left untouched and are not reported as missing, the entries mapped to them are unexpected.
+pub fn import_weights(
+    vs: &VarStore,
+    path: &Path,
+    mapping: &NameMapping,
+    prefix: &str,
+) -> anyhow::Result<ImportReport> {
+    let tensors = load_state_dict(path)?;
+    Ok(import_tensors(vs, tensors, mapping, prefix))
+}
+
+/// Same as [`import_weights`] for tensors that are already loaded
+pub fn import_tensors(
+    vs: &VarStore,
+    tensors: Vec<(String, Tensor)>,
+    mapping: &NameMapping,
+    prefix: &str,
+) -> ImportReport {
+    let prefix = normalize(prefix);
+    let mut report = ImportReport::default();
+    let mut variables = vs.variables_.lock().unwrap();
+
+    for (name, tensor) in tensors {
+        let target = mapping.map(&name);
+        let var = match variables.named_variables.get_mut(&target) {
+            Some(var) if has_prefix(&target, &prefix) => var,
+            _ => {
+                report.unexpected.push(name);
+                continue;
+            }
+        };
+        if var.size() != tensor.size() {
+            report
+                .shape_mismatch
+                .push((target, var.size(), tensor.size()));
+            continue;
+        }
+        let src = tensor.to_device(var.device()).to_kind(var.kind());
+        tch::no_grad(|| var.copy_(&src));
+        report.loaded.push(target);
+    }
+
+    let mut missing: Vec<_> = variables
+        .named_variables
+        .keys()
+        .filter(|name| has_prefix(name, &prefix))
+        .filter(|name| !report.loaded.contains(*name))
+        .filter(|name| !report.shape_mismatch.iter().any(|(n, _, _)| n == *name))
+        .cloned()
+        .collect();
+    missing.sort();
+    report.missing = missing;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{self, VarStore},
+        Device, Kind, Tensor,
+    };
+
+    use super::{import_tensors, NameMapping};
+
+    #[test]
+    fn mapping() {
+        let mapping = NameMapping::new()
+            .rule("features.0", "encoder/layer0/0")
+            .rule("features", "backbone");
+        assert_eq!(mapping.map("features.0.weight"), "encoder.layer0.0.weight");
+        assert_eq!(mapping.map("features.10.weight"), "backbone.10.weight");
+        assert_eq!(mapping.map("fc.bias"), "fc.bias");
+
+        let mapping = NameMapping::new().rule("", "model").rule("model", "");
+        assert_eq!(mapping.map("layer0.weight"), "model.layer0.weight");
+        assert_eq!(mapping.map("model.layer0.weight"), "layer0.weight");
+    }
+
+    #[test]
+    fn import() {
+        let vs = VarStore::new(Device::Cpu);
+        let _ = nn::conv2d(
+            &(&vs.root() / "encoder") / "layer0",
+            3,
+            4,
+            3,
+            Default::default(),
+        );
+        let _ = nn::conv2d(
+            &(&vs.root() / "encoder") / "layer1",
+            4,
+            4,
+            3,
+            Default::default(),
+        );
+
+        let weight = Tensor::ones(&[4, 3, 3, 3], (Kind::Float, Device::Cpu));
+        let tensors = vec![
+            ("features.0.weight".to_string(), weight),
+            (
+                "features.0.bias".to_string(),
+                Tensor::ones(&[5], (Kind::Float, Device::Cpu)),
+            ),
+            (
+                "fc.weight".to_string(),
+                Tensor::ones(&[2], (Kind::Float, Device::Cpu)),
+            ),
+        ];
+        let mapping = NameMapping::new().rule("features.0", "encoder.layer0");
+        let report = import_tensors(&vs, tensors, &mapping, "encoder");
+
+        assert_eq!(report.loaded, vec!["encoder.layer0.weight".to_string()]);
+        assert_eq!(report.unexpected, vec!["fc.weight".to_string()]);
+        assert_eq!(report.shape_mismatch.len(), 1);
+        assert_eq!(
+            report.missing,
+            vec![
+                "encoder.layer1.bias".to_string(),
+                "encoder.layer1.weight".to_string()
+            ]
+        );
+        let variables = vs.variables();
+        let loaded = &variables["encoder.layer0.weight"];
+        assert_eq!(f64::from(loaded.sum(Kind::Float)), 108.0);
+    }
+
+    #[test]
+    fn outside_prefix() {
+        let vs = VarStore::new(Device::Cpu);
+        let _ = nn::linear(&(&vs.root() / "encoder") / "fc", 2, 2, Default::default());
+        let classifier = nn::linear(&vs.root() / "classifier", 2, 2, Default::default());
+        let before = classifier.ws.copy();
+
+        // A head of the file with the same name as the head of the model is not imported
+        let tensors = vec![
+            (
+                "fc.weight".to_string(),
+                Tensor::ones(&[2, 2], (Kind::Float, Device::Cpu)),
+            ),
+            (
+                "classifier.weight".to_string(),
+                Tensor::ones(&[2, 2], (Kind::Float, Device::Cpu)),
+            ),
+        ];
+        let mapping = NameMapping::new().rule("fc", "encoder.fc");
+        let report = import_tensors(&vs, tensors, &mapping, "encoder");
+
+        assert_eq!(report.loaded, vec!["encoder.fc.weight".to_string()]);
+        assert_eq!(report.unexpected, vec!["classifier.weight".to_string()]);
+        assert_eq!(report.missing, vec!["encoder.fc.bias".to_string()]);
+        assert!(classifier.ws.equal(&before));
+    }
+}
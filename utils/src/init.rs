@@ -0,0 +1,287 @@
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+use tch::{nn::VarStore, Kind, Tensor};
+
+/// Initialization of the weights of the convolutions and of the linear layers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum InitScheme {
+    /// Keeps the initialization of tch (kaiming uniform)
+    #[default]
+    Default,
+    /// He initialization for ReLU networks : `N(0, 2/fan_in)`
+    KaimingNormal,
+    KaimingUniform,
+    /// Glorot initialization : `N(0, 2/(fan_in+fan_out))`
+    XavierNormal,
+    XavierUniform,
+    /// (Semi-)orthogonal matrix of the flattened weights (Saxe et al. 2013)
+    Orthogonal,
+    Zeros,
+    /// `N(0, std^2)` with a fixed standard deviation (e.g. 0.02 for DCGAN)
+    Normal(f64),
+}
+
+impl InitScheme {
+    /// New values of a `[out, in, k...]` weight, `None` for [`InitScheme::Default`]
+    pub fn sample(&self, ws: &Tensor) -> Option<Tensor> {
+        let size = ws.size();
+        let receptive: i64 = size[2..].iter().product();
+        let fan_in = (size[1] * receptive) as f64;
+        let fan_out = (size[0] * receptive) as f64;
+        let options = (ws.kind(), ws.device());
+        let normal = |std: f64| Tensor::randn(&size, options) * std;
+        let uniform = |bound: f64| Tensor::rand(&size, options) * (2.0 * bound) - bound;
+        let values = match self {
+            InitScheme::Default => return None,
+            InitScheme::KaimingNormal => normal((2.0 / fan_in).sqrt()),
+            InitScheme::KaimingUniform => uniform((6.0 / fan_in).sqrt()),
+            InitScheme::XavierNormal => normal((2.0 / (fan_in + fan_out)).sqrt()),
+            InitScheme::XavierUniform => uniform((6.0 / (fan_in + fan_out)).sqrt()),
+            InitScheme::Orthogonal => orthogonal(&size, ws.device()).to_kind(ws.kind()),
+            InitScheme::Zeros => Tensor::zeros(&size, options),
+            InitScheme::Normal(std) => normal(*std),
+        };
+        Some(values)
+    }
+}
+
+/// Schemes selectable from the command line of the binaries,
+/// the schemes with parameters (e.g. [`InitScheme::Normal`]) are only available from the configs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum InitParam {
+    /// Initialization of tch (kaiming uniform)
+    Default,
+    KaimingNormal,
+    KaimingUniform,
+    XavierNormal,
+    XavierUniform,
+    Orthogonal,
+}
+
+impl From<InitParam> for InitScheme {
+    fn from(init: InitParam) -> Self {
+        match init {
+            InitParam::Default => InitScheme::Default,
+            InitParam::KaimingNormal => InitScheme::KaimingNormal,
+            InitParam::KaimingUniform => InitScheme::KaimingUniform,
+            InitParam::XavierNormal => InitScheme::XavierNormal,
+            InitParam::XavierUniform => InitScheme::XavierUniform,
+            InitParam::Orthogonal => InitScheme::Orthogonal,
+        }
+    }
+}
+
+/// Orthogonal rows (or columns) of the `[out, in * k...]` flattened weights
+fn orthogonal(size: &[i64], device: tch::Device) -> Tensor {
+    let rows = size[0];
+    let cols = size[1..].iter().product::<i64>();
+    let a = Tensor::randn(&[rows.max(cols), rows.min(cols)], (Kind::Float, device));
+    let (q, r) = a.linalg_qr("reduced");
+    // Making the decomposition unique so that q is uniformly distributed
+    let q = q * r.diagonal(0, 0, 1).sign().unsqueeze(0);
+    let q = if rows < cols { q.tr() } else { q };
+    q.contiguous().view(size)
+}
+
+/// True if `name` matches `pattern` where `*` matches any sequence of characters (dots included)
+fn glob(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((head, tail)) => {
+            name.starts_with(head) && (head.len()..=name.len()).any(|i| glob(tail, &name[i..]))
+        }
+    }
+}
+
+/// Initialization of the variables of a model selected by layer path.
+///
+/// The patterns are matched against the path of the layers relative to the model, with `.` as separator
+/// and `*` as wildcard (e.g. `decoder.*` or `layer*`), the last matching rule wins.
+/// The weights with less than two dimensions (normalization layers) are left untouched.
+/// ```ignore
+/// let init = Initialization::new()
+///     .weights("*", InitScheme::KaimingNormal)
+///     .bias("*", 0.0)
+///     .weights("classifier", InitScheme::XavierUniform)
+///     .seed(42);
+/// init.apply(&vs, "");
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Initialization {
+    #[serde(default)]
+    pub weights: Vec<(String, InitScheme)>,
+    #[serde(default)]
+    pub biases: Vec<(String, f64)>,
+    /// Seed of the random generator of torch, set before initializing the variables
+    pub seed: Option<i64>,
+    /// Zero-initializes the last layer of the residual blocks so that they start as identities.
+    /// The layers are chosen by the models (see [`crate::config::FromConfig::initialization`]).
+    #[serde(default)]
+    pub zero_residual: bool,
+}
+
+impl Initialization {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn weights(mut self, pattern: &str, scheme: InitScheme) -> Self {
+        self.weights.push((pattern.to_string(), scheme));
+        self
+    }
+
+    /// Fills the biases with a constant
+    pub fn bias(mut self, pattern: &str, value: f64) -> Self {
+        self.biases.push((pattern.to_string(), value));
+        self
+    }
+
+    pub fn seed(mut self, seed: i64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn zero_residual(mut self, zero_residual: bool) -> Self {
+        self.zero_residual = zero_residual;
+        self
+    }
+
+    /// Zeroes the weights and the biases of the layers
+    pub fn zeros(self, pattern: &str) -> Self {
+        self.weights(pattern, InitScheme::Zeros).bias(pattern, 0.0)
+    }
+
+    /// Rules of a sub-model whose variables are under `prefix`
+    pub fn prefixed(&self, prefix: &str) -> Self {
+        let prefix = |pattern: &String| format!("{prefix}.{pattern}");
+        Self {
+            weights: self
+                .weights
+                .iter()
+                .map(|(pattern, scheme)| (prefix(pattern), *scheme))
+                .collect(),
+            biases: self
+                .biases
+                .iter()
+                .map(|(pattern, value)| (prefix(pattern), *value))
+                .collect(),
+            seed: self.seed,
+            zero_residual: self.zero_residual,
+        }
+    }
+
+    /// Appends the rules of `other` (which win over the current ones), the seed of `other` is used if set
+    pub fn extend(mut self, other: Initialization) -> Self {
+        self.weights.extend(other.weights);
+        self.biases.extend(other.biases);
+        self.seed = other.seed.or(self.seed);
+        self.zero_residual |= other.zero_residual;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_empty() && self.biases.is_empty()
+    }
+
+    /// Initializes the variables of the model under `prefix` (empty for the whole store).
+    /// Returns the number of variables initialized.
+    pub fn apply(&self, vs: &VarStore, prefix: &str) -> usize {
+        if let Some(seed) = self.seed {
+            tch::manual_seed(seed);
+        }
+        // The variables are sorted so that the seeded initialization is reproducible
+        let mut variables: Vec<_> = vs.variables().into_iter().collect();
+        variables.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut count = 0;
+        for (name, mut var) in variables {
+            let name = match prefix {
+                "" => name.as_str(),
+                prefix => match name.strip_prefix(prefix).and_then(|n| n.strip_prefix('.')) {
+                    Some(name) => name,
+                    None => continue,
+                },
+            };
+            let (layer, kind) = match name.rsplit_once('.') {
+                Some(split) => split,
+                None => ("", name),
+            };
+            let values = match kind {
+                "weight" if var.dim() >= 2 => {
+                    last_match(&self.weights, layer).and_then(|scheme| scheme.sample(&var))
+                }
+                "bias" => last_match(&self.biases, layer)
+                    .map(|value| Tensor::full(&var.size(), value, (var.kind(), var.device()))),
+                _ => None,
+            };
+            if let Some(values) = values {
+                tch::no_grad(|| var.copy_(&values));
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+fn last_match<T: Copy>(rules: &[(String, T)], layer: &str) -> Option<T> {
+    rules
+        .iter()
+        .rev()
+        .find(|(pattern, _)| glob(pattern, layer))
+        .map(|(_, value)| *value)
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{nn, Device, Kind, Tensor};
+
+    use super::{glob, InitScheme, Initialization};
+
+    #[test]
+    fn patterns() {
+        assert!(glob("*", "decoder.layer1.conv0"));
+        assert!(glob("decoder.*.conv0", "decoder.layer1.conv0"));
+        assert!(glob("layer*", "layer12"));
+        assert!(!glob("layer*", "encoder.layer1"));
+        assert!(!glob("classifier", "classifier.head"));
+    }
+
+    #[test]
+    fn schemes() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let root = vs.root();
+        let model = &root / "model";
+        let _ = nn::linear(&model / "layer0", 64, 32, Default::default());
+        let _ = nn::linear(&model / "layer1", 32, 32, Default::default());
+        let _ = nn::linear(&root / "other", 32, 32, Default::default());
+
+        let init = Initialization::new()
+            .weights("*", InitScheme::Orthogonal)
+            .bias("*", 0.5)
+            .zeros("layer1")
+            .seed(0);
+        assert_eq!(init.apply(&vs, "model"), 4);
+
+        let variables = vs.variables();
+        let ws = &variables["model.layer0.weight"];
+        let identity = Tensor::eye(32, (Kind::Float, Device::Cpu));
+        assert!(ws.matmul(&ws.tr()).allclose(&identity, 1e-4, 1e-4, false));
+        assert_eq!(
+            f64::from(variables["model.layer0.bias"].mean(Kind::Float)),
+            0.5
+        );
+        assert_eq!(
+            f64::from(variables["model.layer1.weight"].abs().sum(Kind::Float)),
+            0.0
+        );
+        assert_ne!(
+            f64::from(variables["other.bias"].abs().sum(Kind::Float)),
+            0.0
+        );
+
+        // The same seed gives the same weights
+        let first = ws.copy();
+        init.apply(&vs, "model");
+        assert!(vs.variables()["model.layer0.weight"].equal(&first));
+    }
+}
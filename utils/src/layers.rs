@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+use tch::{
+    nn::{self, Conv2D, Conv3D, ConvConfig, Module, ModuleT, Path},
+    Tensor,
+};
+
+/// Activation functions selectable in the configuration of the models
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Activation {
+    Identity,
+    #[default]
+    ReLU,
+    LeakyReLU,
+    GELU,
+    SiLU,
+    Tanh,
+    Sigmoid,
+}
+
+impl Activation {
+    pub fn apply(&self, xs: &Tensor) -> Tensor {
+        match self {
+            Activation::Identity => xs.shallow_clone(),
+            Activation::ReLU => xs.relu(),
+            Activation::LeakyReLU => xs.leaky_relu(),
+            Activation::GELU => xs.gelu(),
+            Activation::SiLU => xs.silu(),
+            Activation::Tanh => xs.tanh(),
+            Activation::Sigmoid => xs.sigmoid(),
+        }
+    }
+}
+
+/// Normalization layers selectable in the configuration of the models
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Normalization {
+    #[default]
+    None,
+    /// Batch normalization over the channels
+    Batch,
+    /// Group normalization with the given number of groups
+    Group(i64),
+    /// Instance normalization (normalize each channel of each sample)
+    Instance,
+    /// Layer normalization over the last dimension (meant for fully connected layers)
+    Layer,
+}
+
+impl Normalization {
+    /// Creates the normalization layer for `channels` channels and `nd` spatial dimensions.
+    /// Returns `None` for [`Normalization::None`].
+    pub fn build(&self, vs: &Path, channels: i64, nd: usize) -> Option<Norm> {
+        let norm = match self {
+            Normalization::None => return None,
+            Normalization::Batch => Norm::Batch(match nd {
+                0 | 1 => nn::batch_norm1d(vs, channels, Default::default()),
+                2 => nn::batch_norm2d(vs, channels, Default::default()),
+                3 => nn::batch_norm3d(vs, channels, Default::default()),
+                _ => panic!("Batch normalization is only supported up to 3 dimentions"),
+            }),
+            Normalization::Group(groups) => {
+                assert!(
+                    channels % groups == 0,
+                    "Group normalization expects the channels ({channels}) to be divisible by the group count ({groups})"
+                );
+                Norm::Group {
+                    groups: *groups,
+                    ws: vs.ones("weight", &[channels]),
+                    bs: vs.zeros("bias", &[channels]),
+                }
+            }
+            Normalization::Instance => Norm::Instance {
+                ws: vs.ones("weight", &[channels]),
+                bs: vs.zeros("bias", &[channels]),
+            },
+            Normalization::Layer => {
+                Norm::Layer(nn::layer_norm(vs, vec![channels], Default::default()))
+            }
+        };
+        Some(norm)
+    }
+}
+
+/// Normalization layer created by [`Normalization::build`]
+#[derive(Debug)]
+pub enum Norm {
+    Batch(nn::BatchNorm),
+    Group { groups: i64, ws: Tensor, bs: Tensor },
+    Instance { ws: Tensor, bs: Tensor },
+    Layer(nn::LayerNorm),
+}
+
+impl ModuleT for Norm {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        match self {
+            Norm::Batch(bn) => bn.forward_t(xs, train),
+            Norm::Group { groups, ws, bs } => {
+                xs.group_norm(*groups, Some(ws), Some(bs), 1e-5, true)
+            }
+            Norm::Instance { ws, bs } => {
+                xs.instance_norm(Some(ws), Some(bs), None, None, true, 0.1, 1e-5, true)
+            }
+            Norm::Layer(ln) => ln.forward_t(xs, train),
+        }
+    }
+}
+
+/// Applies an optional normalization layer, e.g. the one returned by [`Normalization::build`]
+pub fn normalize(norm: &Option<Norm>, xs: Tensor, train: bool) -> Tensor {
+    match norm {
+        Some(norm) => norm.forward_t(&xs, train),
+        None => xs,
+    }
+}
+
+/// Down-sampling used between the levels of the encoders
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Pooling {
+    #[default]
+    Max,
+    Avg,
+}
+
+impl Pooling {
+    /// Down-samples the last two dimensions of `xs` by `ksize`
+    pub fn apply2d(&self, xs: &Tensor, ksize: i64) -> Tensor {
+        match self {
+            Pooling::Max => xs.max_pool2d_default(ksize),
+            Pooling::Avg => xs.avg_pool2d_default(ksize),
+        }
+    }
+
+    /// Down-samples the last three dimensions of `xs` by `ksize`
+    pub fn apply3d(&self, xs: &Tensor, ksize: i64) -> Tensor {
+        let ksize = [ksize; 3];
+        match self {
+            Pooling::Max => xs.max_pool3d(&ksize, &ksize, &[0; 3], &[1; 3], false),
+            Pooling::Avg => xs.avg_pool3d(&ksize, &ksize, &[0; 3], false, true, None),
+        }
+    }
+
+    /// Down-samples the last `nd` dimensions of `xs` by `ksize`
+    pub fn apply(&self, xs: &Tensor, ksize: i64, nd: usize) -> Tensor {
+        match nd {
+            2 => self.apply2d(xs, ksize),
+            3 => self.apply3d(xs, ksize),
+            _ => panic!("Pooling is only supported in 2 and 3 dimentions"),
+        }
+    }
+}
+
+/// Spatial dimentions of the convolutions of a layer, `[i64; 2]` for images and `[i64; 3]` for volumes
+/// (like the `Conv<ND>` of tch) so that the same blocks can be built for both
+pub trait ConvDims: std::fmt::Debug + Send + 'static {
+    /// Number of spatial dimentions
+    const ND: usize;
+
+    type Conv: Module + 'static;
+
+    /// Creates a convolution with a `ksize` wide kernel along every dimention
+    fn conv(
+        vs: &Path,
+        in_channels: i64,
+        out_channels: i64,
+        ksize: i64,
+        config: ConvConfig,
+    ) -> Self::Conv;
+}
+
+impl ConvDims for [i64; 2] {
+    const ND: usize = 2;
+
+    type Conv = Conv2D;
+
+    fn conv(
+        vs: &Path,
+        in_channels: i64,
+        out_channels: i64,
+        ksize: i64,
+        config: ConvConfig,
+    ) -> Conv2D {
+        nn::conv2d(vs, in_channels, out_channels, ksize, config)
+    }
+}
+
+impl ConvDims for [i64; 3] {
+    const ND: usize = 3;
+
+    type Conv = Conv3D;
+
+    fn conv(
+        vs: &Path,
+        in_channels: i64,
+        out_channels: i64,
+        ksize: i64,
+        config: ConvConfig,
+    ) -> Conv3D {
+        nn::conv3d(vs, in_channels, out_channels, ksize, config)
+    }
+}
@@ -1,5 +1,17 @@
+pub mod checkpoint;
+pub mod config;
 pub mod data;
+pub mod finetune;
+pub mod import;
+pub mod init;
+pub mod layers;
 pub mod metrics;
+pub mod pickle;
+pub mod safetensors;
+pub mod segmentation;
+pub mod summary;
+pub mod tensor;
+pub mod tta;
 pub mod types;
 
 #[cfg(test)]
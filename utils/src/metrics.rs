@@ -12,3 +12,89 @@ pub fn dice_score_1c(y_hat: &Tensor, y: &Tensor) -> Tensor {
         + y.sum_dim_intlist(&[1, 2], false, tch::Kind::Float);
     2.0_f32 * intersect + union
 }
+
+/// Per class intersection and union of `[B,K,H,W]` binary masks, both `[K]` shaped
+#[dims(y_hat(B, K, W, H), y(B, K, W, H))]
+pub fn intersection_union(y_hat: &Tensor, y: &Tensor) -> (Tensor, Tensor) {
+    let y_hat = y_hat.to_kind(tch::Kind::Bool);
+    let y = y.to_kind(tch::Kind::Bool);
+    let intersection = y_hat
+        .logical_and(&y)
+        .sum_dim_intlist(&[0, 2, 3], false, tch::Kind::Float);
+    let union = y_hat
+        .logical_or(&y)
+        .sum_dim_intlist(&[0, 2, 3], false, tch::Kind::Float);
+    (intersection, union)
+}
+
+/// Per class IoU and Dice score accumulated over several batches
+#[derive(Debug)]
+pub struct ClassMetrics {
+    intersection: Vec<f64>,
+    union: Vec<f64>,
+}
+
+impl ClassMetrics {
+    pub fn new(class_count: usize) -> Self {
+        Self {
+            intersection: vec![0.0; class_count],
+            union: vec![0.0; class_count],
+        }
+    }
+
+    /// Accumulates the `[B,K,H,W]` binary masks of a batch
+    pub fn update(&mut self, y_hat: &Tensor, y: &Tensor) {
+        let (intersection, union) = intersection_union(y_hat, y);
+        for (acc, value) in self
+            .intersection
+            .iter_mut()
+            .zip(Vec::<f64>::from(&intersection.to_kind(tch::Kind::Double)))
+        {
+            *acc += value;
+        }
+        for (acc, value) in self
+            .union
+            .iter_mut()
+            .zip(Vec::<f64>::from(&union.to_kind(tch::Kind::Double)))
+        {
+            *acc += value;
+        }
+    }
+
+    /// IoU of each class (NaN for a class absent from both the predictions and the targets)
+    pub fn iou(&self) -> Vec<f64> {
+        self.intersection
+            .iter()
+            .zip(self.union.iter())
+            .map(|(i, u)| i / u)
+            .collect()
+    }
+
+    /// Dice score of each class (NaN for a class absent from both the predictions and the targets)
+    pub fn dice(&self) -> Vec<f64> {
+        self.intersection
+            .iter()
+            .zip(self.union.iter())
+            .map(|(i, u)| 2.0 * i / (u + i))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::Tensor;
+
+    use super::ClassMetrics;
+
+    #[test]
+    fn class_metrics() {
+        let y_hat = Tensor::of_slice(&[true, true, false, false]).view([1, 2, 1, 2]);
+        let y = Tensor::of_slice(&[true, false, false, false]).view([1, 2, 1, 2]);
+        let mut metrics = ClassMetrics::new(2);
+        metrics.update(&y_hat, &y);
+        metrics.update(&y_hat, &y);
+        assert_eq!(metrics.iou()[0], 0.5);
+        assert!((metrics.dice()[0] - 2.0 / 3.0).abs() < 1e-9);
+        assert!(metrics.iou()[1].is_nan());
+    }
+}
@@ -0,0 +1,554 @@
+use anyhow::{bail, Context};
+use std::{collections::HashMap, fs::File, io::Read, path::Path, rc::Rc};
+use tch::{Kind, Tensor};
+use thiserror::Error;
+use zip::ZipArchive;
+
+#[derive(Debug, Error)]
+enum PickleError {
+    #[error("Unexpected end of the pickle")]
+    Truncated,
+    #[error("Unsupported pickle opcode 0x{0:02x}")]
+    UnsupportedOpcode(u8),
+    #[error("Invalid pickle : {0}")]
+    Invalid(&'static str),
+    #[error("Unknown storage type {0}")]
+    UnknownStorage(String),
+    #[error("The archive has no data.pkl, it wasnt written by torch.save")]
+    NoPickle,
+    #[error("The archive was saved on a big endian machine")]
+    BigEndian,
+    #[error("The pickle isnt a dict of tensors")]
+    NotAStateDict,
+}
+
+/// Python objects the unpickler keeps track of, everything it doesnt need (floats, bytes, unknown classes...) is `Other`
+#[derive(Debug, Clone)]
+enum Value {
+    Mark,
+    Int(i64),
+    String(String),
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Dict(Vec<(Value, Value)>),
+    Global(String, String),
+    Storage(Rc<Tensor>),
+    Tensor(Rc<Tensor>),
+    Other,
+}
+
+fn storage_kind(storage: &str) -> anyhow::Result<Kind> {
+    Ok(match storage {
+        "DoubleStorage" => Kind::Double,
+        "FloatStorage" => Kind::Float,
+        "HalfStorage" => Kind::Half,
+        "BFloat16Storage" => Kind::BFloat16,
+        "LongStorage" => Kind::Int64,
+        "IntStorage" => Kind::Int,
+        "ShortStorage" => Kind::Int16,
+        "CharStorage" => Kind::Int8,
+        "ByteStorage" => Kind::Uint8,
+        "BoolStorage" => Kind::Bool,
+        storage => bail!(PickleError::UnknownStorage(storage.to_string())),
+    })
+}
+
+fn ints(value: &Value) -> anyhow::Result<Vec<i64>> {
+    match value {
+        Value::Tuple(values) | Value::List(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::Int(i) => Ok(*i),
+                _ => bail!(PickleError::Invalid("expected a tuple of ints")),
+            })
+            .collect(),
+        _ => bail!(PickleError::Invalid("expected a tuple of ints")),
+    }
+}
+
+/// Calls the few functions of a `state_dict` pickle, the other callables give opaque objects
+fn reduce(callable: Value, args: Value) -> anyhow::Result<Value> {
+    let (module, name) = match callable {
+        Value::Global(module, name) => (module, name),
+        _ => return Ok(Value::Other),
+    };
+    let args = match args {
+        Value::Tuple(args) => args,
+        _ => bail!(PickleError::Invalid("REDUCE expects a tuple of arguments")),
+    };
+    Ok(match (module.as_str(), name.as_str(), args.as_slice()) {
+        (
+            "torch._utils",
+            "_rebuild_tensor" | "_rebuild_tensor_v2",
+            [Value::Storage(storage), Value::Int(offset), size, stride, ..],
+        ) => Value::Tensor(Rc::new(storage.f_as_strided(
+            &ints(size)?,
+            &ints(stride)?,
+            *offset,
+        )?)),
+        ("torch._utils", "_rebuild_parameter", [Value::Tensor(tensor), ..]) => {
+            Value::Tensor(tensor.clone())
+        }
+        ("collections", "OrderedDict", []) => Value::Dict(vec![]),
+        _ => Value::Other,
+    })
+}
+
+/// Minimal pickle virtual machine, enough for the protocol 2 pickles of `torch.save`.
+///
+/// The memo keeps copies of the values, so a dict or a list that is filled after being memoized
+/// and referenced again is seen empty (it doesnt happen in a `state_dict`).
+struct Unpickler<'a> {
+    data: &'a [u8],
+    pos: usize,
+    stack: Vec<Value>,
+    memo: HashMap<u64, Value>,
+}
+
+impl<'a> Unpickler<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            stack: vec![],
+            memo: HashMap::new(),
+        }
+    }
+
+    fn take(&mut self, count: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(count)
+            .and_then(|end| self.data.get(self.pos..end))
+            .context(PickleError::Truncated)?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn line(&mut self) -> anyhow::Result<String> {
+        let len = self.data[self.pos..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .context(PickleError::Truncated)?;
+        let line = String::from_utf8_lossy(self.take(len)?).to_string();
+        self.take(1)?;
+        Ok(line)
+    }
+
+    fn string(&mut self, len: usize) -> anyhow::Result<Value> {
+        let bytes = self.take(len)?;
+        Ok(Value::String(String::from_utf8(bytes.to_vec())?))
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> anyhow::Result<Value> {
+        self.stack
+            .pop()
+            .context(PickleError::Invalid("pop from an empty stack"))
+    }
+
+    /// Pops the values up to the last mark
+    fn pop_mark(&mut self) -> anyhow::Result<Vec<Value>> {
+        let mark = self
+            .stack
+            .iter()
+            .rposition(|value| matches!(value, Value::Mark))
+            .context(PickleError::Invalid("no mark on the stack"))?;
+        let values = self.stack.split_off(mark + 1);
+        self.stack.pop();
+        Ok(values)
+    }
+
+    fn top(&mut self) -> anyhow::Result<&mut Value> {
+        self.stack
+            .last_mut()
+            .context(PickleError::Invalid("empty stack"))
+    }
+
+    fn memoize(&mut self, index: u64) -> anyhow::Result<()> {
+        let value = self.top()?.clone();
+        self.memo.insert(index, value);
+        Ok(())
+    }
+
+    fn get(&mut self, index: u64) -> anyhow::Result<()> {
+        let value = self
+            .memo
+            .get(&index)
+            .context(PickleError::Invalid("unknown memo index"))?
+            .clone();
+        self.push(value);
+        Ok(())
+    }
+
+    fn set_items(&mut self, items: Vec<Value>) -> anyhow::Result<()> {
+        if !items.len().is_multiple_of(2) {
+            bail!(PickleError::Invalid("odd number of dict items"));
+        }
+        // The items of unknown objects (e.g. a `defaultdict`) are dropped
+        if let Value::Dict(dict) = self.top()? {
+            let mut items = items.into_iter();
+            while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                dict.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, values: Vec<Value>) -> anyhow::Result<()> {
+        if let Value::List(list) = self.top()? {
+            list.extend(values);
+        }
+        Ok(())
+    }
+
+    /// Runs the pickle, `persistent_load` gives the objects stored out of the pickle from their id
+    fn load(
+        mut self,
+        mut persistent_load: impl FnMut(Value) -> anyhow::Result<Value>,
+    ) -> anyhow::Result<Value> {
+        loop {
+            match self.u8()? {
+                // PROTO, FRAME
+                0x80 => {
+                    self.take(1)?;
+                }
+                0x95 => {
+                    self.take(8)?;
+                }
+                // STOP
+                b'.' => return self.pop(),
+                // MARK, POP, POP_MARK, DUP
+                b'(' => self.push(Value::Mark),
+                b'0' => {
+                    self.pop()?;
+                }
+                b'1' => {
+                    self.pop_mark()?;
+                }
+                b'2' => {
+                    let value = self.top()?.clone();
+                    self.push(value);
+                }
+                // NONE, NEWTRUE, NEWFALSE
+                b'N' => self.push(Value::Other),
+                0x88 => self.push(Value::Int(1)),
+                0x89 => self.push(Value::Int(0)),
+                // BININT, BININT1, BININT2, LONG1
+                b'J' => {
+                    let i = i32::from_le_bytes(self.take(4)?.try_into().unwrap());
+                    self.push(Value::Int(i as i64));
+                }
+                b'K' => {
+                    let i = self.u8()?;
+                    self.push(Value::Int(i as i64));
+                }
+                b'M' => {
+                    let i = u16::from_le_bytes(self.take(2)?.try_into().unwrap());
+                    self.push(Value::Int(i as i64));
+                }
+                0x8a => {
+                    let len = self.u8()? as usize;
+                    let bytes = self.take(len)?;
+                    if len > 8 {
+                        bail!(PickleError::Invalid("integer too large"));
+                    }
+                    // Little endian two's complement, sign extended
+                    let fill = match bytes.last() {
+                        Some(byte) if byte & 0x80 != 0 => 0xff,
+                        _ => 0,
+                    };
+                    let mut buffer = [fill; 8];
+                    buffer[..len].copy_from_slice(bytes);
+                    self.push(Value::Int(i64::from_le_bytes(buffer)));
+                }
+                // BINFLOAT
+                b'G' => {
+                    self.take(8)?;
+                    self.push(Value::Other);
+                }
+                // SHORT_BINUNICODE, BINUNICODE, BINUNICODE8, SHORT_BINSTRING, BINSTRING
+                0x8c | b'U' => {
+                    let len = self.u8()? as usize;
+                    let value = self.string(len)?;
+                    self.push(value);
+                }
+                b'X' | b'T' => {
+                    let len = self.u32()? as usize;
+                    let value = self.string(len)?;
+                    self.push(value);
+                }
+                0x8d => {
+                    let len = self.u64()? as usize;
+                    let value = self.string(len)?;
+                    self.push(value);
+                }
+                // SHORT_BINBYTES, BINBYTES
+                b'C' => {
+                    let len = self.u8()? as usize;
+                    self.take(len)?;
+                    self.push(Value::Other);
+                }
+                b'B' => {
+                    let len = self.u32()? as usize;
+                    self.take(len)?;
+                    self.push(Value::Other);
+                }
+                // EMPTY_TUPLE, TUPLE, TUPLE1, TUPLE2, TUPLE3
+                b')' => self.push(Value::Tuple(vec![])),
+                b't' => {
+                    let values = self.pop_mark()?;
+                    self.push(Value::Tuple(values));
+                }
+                op @ 0x85..=0x87 => {
+                    let len = (op - 0x84) as usize;
+                    if self.stack.len() < len {
+                        bail!(PickleError::Invalid("pop from an empty stack"));
+                    }
+                    let values = self.stack.split_off(self.stack.len() - len);
+                    self.push(Value::Tuple(values));
+                }
+                // EMPTY_LIST, LIST, APPEND, APPENDS
+                b']' => self.push(Value::List(vec![])),
+                b'l' => {
+                    let values = self.pop_mark()?;
+                    self.push(Value::List(values));
+                }
+                b'a' => {
+                    let value = self.pop()?;
+                    self.append(vec![value])?;
+                }
+                b'e' => {
+                    let values = self.pop_mark()?;
+                    self.append(values)?;
+                }
+                // EMPTY_DICT, DICT, SETITEM, SETITEMS
+                b'}' => self.push(Value::Dict(vec![])),
+                b'd' => {
+                    let items = self.pop_mark()?;
+                    self.push(Value::Dict(vec![]));
+                    self.set_items(items)?;
+                }
+                b's' => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    self.set_items(vec![key, value])?;
+                }
+                b'u' => {
+                    let items = self.pop_mark()?;
+                    self.set_items(items)?;
+                }
+                // GLOBAL, STACK_GLOBAL
+                b'c' => {
+                    let module = self.line()?;
+                    let name = self.line()?;
+                    self.push(Value::Global(module, name));
+                }
+                0x93 => match (self.pop()?, self.pop()?) {
+                    (Value::String(name), Value::String(module)) => {
+                        self.push(Value::Global(module, name))
+                    }
+                    _ => bail!(PickleError::Invalid("STACK_GLOBAL expects two strings")),
+                },
+                // REDUCE, NEWOBJ, BUILD (the state of the objects is ignored)
+                b'R' => {
+                    let args = self.pop()?;
+                    let callable = self.pop()?;
+                    self.push(reduce(callable, args)?);
+                }
+                0x81 => {
+                    self.pop()?;
+                    self.pop()?;
+                    self.push(Value::Other);
+                }
+                b'b' => {
+                    self.pop()?;
+                }
+                // BINPERSID
+                b'Q' => {
+                    let pid = self.pop()?;
+                    self.push(persistent_load(pid)?);
+                }
+                // BINPUT, LONG_BINPUT, MEMOIZE, BINGET, LONG_BINGET
+                b'q' => {
+                    let index = self.u8()? as u64;
+                    self.memoize(index)?;
+                }
+                b'r' => {
+                    let index = self.u32()? as u64;
+                    self.memoize(index)?;
+                }
+                0x94 => {
+                    let index = self.memo.len() as u64;
+                    self.memoize(index)?;
+                }
+                b'h' => {
+                    let index = self.u8()? as u64;
+                    self.get(index)?;
+                }
+                b'j' => {
+                    let index = self.u32()? as u64;
+                    self.get(index)?;
+                }
+                op => bail!(PickleError::UnsupportedOpcode(op)),
+            }
+        }
+    }
+}
+
+/// Flattens nested dicts of tensors into `.` separated names, the other values are dropped
+fn flatten(prefix: &str, value: Value, tensors: &mut Vec<(String, Tensor)>) {
+    if let Value::Dict(items) = value {
+        for (key, value) in items {
+            let key = match key {
+                Value::String(key) => key,
+                Value::Int(i) => i.to_string(),
+                _ => continue,
+            };
+            let name = if prefix.is_empty() {
+                key
+            } else {
+                format!("{prefix}.{key}")
+            };
+            match value {
+                Value::Tensor(tensor) => tensors.push((name, tensor.shallow_clone())),
+                value => flatten(&name, value, tensors),
+            }
+        }
+    }
+}
+
+fn is_zip_entry(archive: &ZipArchive<File>, suffix: &str) -> bool {
+    archive.file_names().any(|name| name.ends_with(suffix))
+}
+
+/// True if the file is a zip archive written by `torch.save` (PyTorch >= 1.6),
+/// the TorchScript and libtorch archives also have a `data.pkl` but they have `constants.pkl` too
+pub fn is_torch_save(path: &Path) -> bool {
+    match File::open(path).map(ZipArchive::new) {
+        Ok(Ok(archive)) => {
+            is_zip_entry(&archive, "data.pkl") && !is_zip_entry(&archive, "constants.pkl")
+        }
+        _ => false,
+    }
+}
+
+/// Loads the tensors (on the cpu) of a file written by `torch.save`, usually a `state_dict`.
+///
+/// Nested dicts are flattened, `torch.save({"model": model.state_dict(), "epoch": 3})` gives tensors named `model.<name>`.
+/// The legacy format (`_use_new_zipfile_serialization=False`) isnt supported.
+pub fn load_torch_save(path: &Path) -> anyhow::Result<Vec<(String, Tensor)>> {
+    let file = File::open(path).with_context(|| format!("Couldnt read {path:?}"))?;
+    let mut archive = ZipArchive::new(file)?;
+    let pickle_name = archive
+        .file_names()
+        .find(|name| name.ends_with("data.pkl"))
+        .context(PickleError::NoPickle)?
+        .to_string();
+    let prefix = pickle_name.trim_end_matches("data.pkl").to_string();
+
+    if let Ok(mut entry) = archive.by_name(&format!("{prefix}byteorder")) {
+        let mut byteorder = String::new();
+        entry.read_to_string(&mut byteorder)?;
+        if byteorder.trim() == "big" {
+            bail!(PickleError::BigEndian);
+        }
+    }
+    let mut pickle = vec![];
+    archive.by_name(&pickle_name)?.read_to_end(&mut pickle)?;
+
+    // The storages are in `data/<key>`, they are shared by the tensors viewing them
+    let mut storages: HashMap<String, Rc<Tensor>> = HashMap::new();
+    let value = Unpickler::new(&pickle).load(|pid| {
+        let (storage, key) = match &pid {
+            Value::Tuple(pid) => match pid.as_slice() {
+                [Value::String(tag), Value::Global(_, storage), Value::String(key), ..]
+                    if tag == "storage" =>
+                {
+                    (storage, key)
+                }
+                _ => bail!(PickleError::Invalid("unknown persistent id")),
+            },
+            _ => bail!(PickleError::Invalid("unknown persistent id")),
+        };
+        if let Some(storage) = storages.get(key) {
+            return Ok(Value::Storage(storage.clone()));
+        }
+        let kind = storage_kind(storage)?;
+        let mut bytes = vec![];
+        archive
+            .by_name(&format!("{prefix}data/{key}"))?
+            .read_to_end(&mut bytes)?;
+        let numel = (bytes.len() / kind.elt_size_in_bytes()) as i64;
+        let storage = Rc::new(Tensor::f_of_data_size(&bytes, &[numel], kind)?);
+        storages.insert(key.clone(), storage.clone());
+        Ok(Value::Storage(storage))
+    })?;
+
+    if !matches!(value, Value::Dict(_)) {
+        bail!(PickleError::NotAStateDict);
+    }
+    let mut tensors = vec![];
+    flatten("", value, &mut tensors);
+    Ok(tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Write};
+    use tch::Tensor;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    use super::{is_torch_save, load_torch_save};
+
+    /// `torch.save` of an `OrderedDict` with `layer.weight` and `layer.bias` viewing the same float storage `0`
+    /// and a scalar `steps` in the long storage `1`
+    const PICKLE: &[u8] = b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x01(X\x0c\x00\x00\x00layer.weightq\x02ctorch._utils\n_rebuild_tensor_v2\nq\x03((X\x07\x00\x00\x00storageq\x04ctorch\nFloatStorage\nq\x05X\x01\x00\x00\x000q\x06X\x03\x00\x00\x00cpuq\x07K\x06tq\x08QK\x00K\x02K\x03\x86q\tK\x03K\x01\x86q\n\x89h\x00)Rq\x0btq\x0cRq\rX\n\x00\x00\x00layer.biasq\x0eh\x03((h\x04h\x05h\x06h\x07K\x06tq\x0fQK\x03K\x03\x85q\x10K\x01\x85q\x11\x89h\x00)Rq\x12tq\x13Rq\x14X\x05\x00\x00\x00stepsq\x15h\x03((h\x04ctorch\nLongStorage\nq\x16X\x01\x00\x00\x001q\x17h\x07K\x01tq\x18QK\x00))\x89h\x00)Rq\x19tq\x1aRq\x1bu}q\x1cX\t\x00\x00\x00_metadataq\x1dh\x00)Rq\x1eX\x00\x00\x00\x00q\x1f}q X\x07\x00\x00\x00versionq!K\x01sssb.";
+
+    #[test]
+    fn state_dict() {
+        let dir = std::env::temp_dir().join(format!("torch_save_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("weights.pt");
+
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("weights/data.pkl", options).unwrap();
+        zip.write_all(PICKLE).unwrap();
+        zip.start_file("weights/data/0", options).unwrap();
+        for value in [1f32, 2., 3., 4., 5., 6.] {
+            zip.write_all(&value.to_le_bytes()).unwrap();
+        }
+        zip.start_file("weights/data/1", options).unwrap();
+        zip.write_all(&7i64.to_le_bytes()).unwrap();
+        zip.finish().unwrap();
+
+        assert!(is_torch_save(&path));
+        let tensors = load_torch_save(&path).unwrap();
+        let names: Vec<_> = tensors.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["layer.weight", "layer.bias", "steps"]);
+        assert!(tensors[0]
+            .1
+            .equal(&Tensor::of_slice(&[1f32, 2., 3., 4., 5., 6.]).view([2, 3])));
+        assert!(tensors[1].1.equal(&Tensor::of_slice(&[4f32, 5., 6.])));
+        assert_eq!(tensors[2].1.size(), Vec::<i64>::new());
+        assert_eq!(i64::from(&tensors[2].1), 7);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
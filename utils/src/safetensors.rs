@@ -0,0 +1,377 @@
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path, process::Command};
+use tch::{nn::VarStore, Device, Kind, Tensor};
+use thiserror::Error;
+
+use crate::config::FromConfig;
+
+const METADATA_KEY: &str = "__metadata__";
+
+#[derive(Debug, Error)]
+enum SafetensorsError {
+    #[error("Invalid safetensors header")]
+    InvalidHeader,
+    #[error("Tensors of kind {0:?} can't be saved as safetensors")]
+    UnsupportedKind(Kind),
+    #[error("Unknown safetensors dtype {0}")]
+    UnknownDtype(String),
+    #[error("The data of {0} is out of the file")]
+    OutOfBounds(String),
+    #[error("{0} has the invalid shape {1:?}")]
+    InvalidShape(String, Vec<i64>),
+    #[error("{0} has {1} bytes of data while its shape and dtype need {2}")]
+    DataSize(String, usize, usize),
+    #[error("{0} is missing from the file")]
+    Missing(String),
+    #[error("{0} has the shape {1:?} in the file while {2:?} was expected")]
+    Shape(String, Vec<i64>, Vec<i64>),
+    #[error("The file has no architecture config")]
+    NoConfig,
+}
+
+fn dtype(kind: Kind) -> anyhow::Result<&'static str> {
+    Ok(match kind {
+        Kind::Double => "F64",
+        Kind::Float => "F32",
+        Kind::Half => "F16",
+        Kind::BFloat16 => "BF16",
+        Kind::Int64 => "I64",
+        Kind::Int => "I32",
+        Kind::Int16 => "I16",
+        Kind::Int8 => "I8",
+        Kind::Uint8 => "U8",
+        Kind::Bool => "BOOL",
+        kind => bail!(SafetensorsError::UnsupportedKind(kind)),
+    })
+}
+
+fn kind(dtype: &str) -> anyhow::Result<Kind> {
+    Ok(match dtype {
+        "F64" => Kind::Double,
+        "F32" => Kind::Float,
+        "F16" => Kind::Half,
+        "BF16" => Kind::BFloat16,
+        "I64" => Kind::Int64,
+        "I32" => Kind::Int,
+        "I16" => Kind::Int16,
+        "I8" => Kind::Int8,
+        "U8" => Kind::Uint8,
+        "BOOL" => Kind::Bool,
+        dtype => bail!(SafetensorsError::UnknownDtype(dtype.to_string())),
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TensorInfo {
+    dtype: String,
+    shape: Vec<i64>,
+    data_offsets: [usize; 2],
+}
+
+/// Free-form metadata stored in the header of a safetensors file
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub entries: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(mut self, key: &str, value: &str) -> Self {
+        self.entries.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Architecture config of the model (see [`FromConfig`]) stored as json under `config`
+    pub fn config<C: Serialize>(self, config: &C) -> anyhow::Result<Self> {
+        Ok(self.insert("config", &serde_json::to_string(config)?))
+    }
+
+    /// Commit of the code that trained the model, stored under `commit`
+    pub fn commit(self, commit: &str) -> Self {
+        self.insert("commit", commit)
+    }
+
+    /// Hash of the training data (see [`dataset_hash`]), stored under `dataset_hash`
+    pub fn dataset_hash(self, hash: &str) -> Self {
+        self.insert("dataset_hash", hash)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|value| value.as_str())
+    }
+
+    /// Architecture config stored with [`Metadata::config`]
+    pub fn parse_config<C: DeserializeOwned>(&self) -> anyhow::Result<C> {
+        let config = self.get("config").context(SafetensorsError::NoConfig)?;
+        Ok(serde_json::from_str(config)?)
+    }
+}
+
+/// Commit of the git repository of the current directory, `None` outside of a repository
+pub fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// FNV-1a hash of the names and contents of the files of a folder (recursively) or of a single file
+pub fn dataset_hash(path: &Path) -> anyhow::Result<String> {
+    fn visit(path: &Path, root: &Path, hash: &mut u64) -> anyhow::Result<()> {
+        let mut update = |bytes: &[u8]| {
+            for byte in bytes {
+                *hash ^= *byte as u64;
+                *hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.sort();
+            for entry in entries {
+                visit(&entry, root, hash)?;
+            }
+        } else {
+            let name = path.strip_prefix(root).unwrap_or(path);
+            update(name.to_string_lossy().as_bytes());
+            update(&fs::read(path).with_context(|| format!("Couldnt read {path:?}"))?);
+        }
+        Ok(())
+    }
+    let mut hash = 0xcbf29ce484222325;
+    visit(path, path, &mut hash)?;
+    Ok(format!("{hash:016x}"))
+}
+
+/// Writes named tensors in the safetensors format
+pub fn save_tensors(
+    path: &Path,
+    tensors: &[(String, Tensor)],
+    metadata: &Metadata,
+) -> anyhow::Result<()> {
+    let mut header = BTreeMap::new();
+    if !metadata.entries.is_empty() {
+        header.insert(
+            METADATA_KEY.to_string(),
+            serde_json::to_value(&metadata.entries)?,
+        );
+    }
+    let mut data = vec![];
+    for (name, tensor) in tensors {
+        let tensor = tensor.to_device(Device::Cpu).contiguous();
+        let numel = tensor.numel();
+        let start = data.len();
+        data.resize(start + numel * tensor.kind().elt_size_in_bytes(), 0);
+        tensor.f_copy_data_u8(&mut data[start..], numel)?;
+        let info = TensorInfo {
+            dtype: dtype(tensor.kind())?.to_string(),
+            shape: tensor.size(),
+            data_offsets: [start, data.len()],
+        };
+        header.insert(name.clone(), serde_json::to_value(info)?);
+    }
+
+    // The header is padded with spaces so that the data is aligned on 8 bytes
+    let mut header = serde_json::to_vec(&header)?;
+    header.resize(header.len().div_ceil(8) * 8, b' ');
+    let mut content = Vec::with_capacity(8 + header.len() + data.len());
+    content.extend((header.len() as u64).to_le_bytes());
+    content.extend(header);
+    content.extend(data);
+    fs::write(path, content).with_context(|| format!("Couldnt write {path:?}"))
+}
+
+/// Reads the named tensors (on the cpu) and the metadata of a safetensors file
+pub fn load_tensors(path: &Path) -> anyhow::Result<(Vec<(String, Tensor)>, Metadata)> {
+    let content = fs::read(path).with_context(|| format!("Couldnt read {path:?}"))?;
+    let header_size = content
+        .get(..8)
+        .map(|size| u64::from_le_bytes(size.try_into().unwrap()) as usize)
+        .context(SafetensorsError::InvalidHeader)?;
+    let data_start = 8usize
+        .checked_add(header_size)
+        .context(SafetensorsError::InvalidHeader)?;
+    let header = content
+        .get(8..data_start)
+        .context(SafetensorsError::InvalidHeader)?;
+    let data = &content[data_start..];
+    let mut header: BTreeMap<String, serde_json::Value> =
+        serde_json::from_slice(header).context(SafetensorsError::InvalidHeader)?;
+
+    let metadata = Metadata {
+        entries: match header.remove(METADATA_KEY) {
+            Some(entries) => serde_json::from_value(entries)?,
+            None => BTreeMap::new(),
+        },
+    };
+    let mut tensors = vec![];
+    for (name, info) in header {
+        let info: TensorInfo = serde_json::from_value(info)?;
+        let kind = kind(&info.dtype)?;
+        let [start, end] = info.data_offsets;
+        if start > end {
+            bail!(SafetensorsError::OutOfBounds(name));
+        }
+        let bytes = data
+            .get(start..end)
+            .with_context(|| SafetensorsError::OutOfBounds(name.clone()))?;
+        // The data is copied by torch without checking its size
+        let size = info
+            .shape
+            .iter()
+            .try_fold(kind.elt_size_in_bytes(), |size, dim| {
+                usize::try_from(*dim)
+                    .ok()
+                    .and_then(|dim| size.checked_mul(dim))
+            })
+            .with_context(|| SafetensorsError::InvalidShape(name.clone(), info.shape.clone()))?;
+        if bytes.len() != size {
+            bail!(SafetensorsError::DataSize(name, bytes.len(), size));
+        }
+        let tensor = Tensor::f_of_data_size(bytes, &info.shape, kind)?;
+        tensors.push((name, tensor));
+    }
+    Ok((tensors, metadata))
+}
+
+/// Saves the variables of a store in the safetensors format
+pub fn save_var_store(vs: &VarStore, path: &Path, metadata: &Metadata) -> anyhow::Result<()> {
+    let mut tensors: Vec<_> = vs.variables().into_iter().collect();
+    tensors.sort_by(|a, b| a.0.cmp(&b.0));
+    save_tensors(path, &tensors, metadata)
+}
+
+/// Loads the variables of a store from a safetensors file, like [`VarStore::load`] every variable should be in the file.
+/// Returns the metadata of the file.
+pub fn load_var_store(vs: &mut VarStore, path: &Path) -> anyhow::Result<Metadata> {
+    let (tensors, metadata) = load_tensors(path)?;
+    copy_tensors(vs, tensors)?;
+    Ok(metadata)
+}
+
+/// Copies named tensors into the variables of a store, every variable should be given
+fn copy_tensors(vs: &VarStore, tensors: Vec<(String, Tensor)>) -> anyhow::Result<()> {
+    let tensors: BTreeMap<_, _> = tensors.into_iter().collect();
+    for (name, mut var) in vs.variables() {
+        let tensor = tensors
+            .get(&name)
+            .with_context(|| SafetensorsError::Missing(name.clone()))?;
+        if tensor.size() != var.size() {
+            bail!(SafetensorsError::Shape(name, tensor.size(), var.size()));
+        }
+        tch::no_grad(|| var.copy_(tensor));
+    }
+    Ok(())
+}
+
+/// Rebuilds a model from the config stored in the metadata of a safetensors file and loads its weights
+pub fn load_model<M: FromConfig>(
+    path: &Path,
+    device: Device,
+) -> anyhow::Result<(VarStore, M, Metadata)> {
+    let (tensors, metadata) = load_tensors(path)?;
+    let config = metadata.parse_config()?;
+    let vs = VarStore::new(device);
+    let model = M::from_config(&vs.root(), &config)?;
+    copy_tensors(&vs, tensors)?;
+    Ok((vs, model, metadata))
+}
+
+/// True if the path has the `.safetensors` extension
+pub fn is_safetensors(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("safetensors")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+    use tch::{nn, Device, Kind, Tensor};
+
+    use super::{load_tensors, load_var_store, save_tensors, save_var_store, Metadata};
+
+    /// Path of the temporary file of a test, unique to the process
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}.safetensors", std::process::id()))
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round_trip");
+        let tensors = vec![
+            (
+                "a".to_string(),
+                Tensor::rand(&[2, 3], (Kind::Float, Device::Cpu)),
+            ),
+            ("b.c".to_string(), Tensor::of_slice(&[1_i64, -2, 3])),
+            ("d".to_string(), Tensor::of_slice(&[true, false])),
+        ];
+        let metadata = Metadata::new().commit("abc").dataset_hash("0123");
+        save_tensors(&path, &tensors, &metadata).unwrap();
+
+        let loaded = load_tensors(&path);
+        fs::remove_file(&path).unwrap();
+        let (loaded, loaded_metadata) = loaded.unwrap();
+        assert_eq!(loaded_metadata, metadata);
+        for ((name, tensor), (loaded_name, loaded)) in tensors.iter().zip(loaded.iter()) {
+            assert_eq!(name, loaded_name);
+            assert!(tensor.equal(loaded));
+        }
+    }
+
+    #[test]
+    fn var_store() {
+        let path = temp_path("var_store");
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _ = nn::linear(&vs.root() / "layer0", 4, 3, Default::default());
+        save_var_store(&vs, &path, &Metadata::new()).unwrap();
+
+        let mut other = nn::VarStore::new(Device::Cpu);
+        let _ = nn::linear(&other.root() / "layer0", 4, 3, Default::default());
+        load_var_store(&mut other, &path).unwrap();
+        let variables = other.variables();
+        for (name, var) in vs.variables() {
+            assert!(var.equal(&variables[&name]));
+        }
+
+        let mut wrong = nn::VarStore::new(Device::Cpu);
+        let _ = nn::linear(&wrong.root() / "layer0", 4, 2, Default::default());
+        assert!(load_var_store(&mut wrong, &path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        // Writes a file with a single tensor of 8 bytes of data described by `entry`
+        let write = |path: &PathBuf, entry: &str| {
+            let header = format!(r#"{{"a":{entry}}}"#);
+            let mut content = (header.len() as u64).to_le_bytes().to_vec();
+            content.extend(header.as_bytes());
+            content.extend([0; 8]);
+            fs::write(path, content).unwrap();
+        };
+        let path = temp_path("truncated");
+        let entries = [
+            r#"{"dtype":"F32","shape":[4,4],"data_offsets":[0,8]}"#,
+            r#"{"dtype":"F32","shape":[-2,-1],"data_offsets":[0,8]}"#,
+            r#"{"dtype":"F32","shape":[2],"data_offsets":[8,0]}"#,
+            r#"{"dtype":"F32","shape":[4],"data_offsets":[0,16]}"#,
+        ];
+        for entry in entries {
+            write(&path, entry);
+            assert!(load_tensors(&path).is_err(), "{entry} was loaded");
+        }
+        write(&path, r#"{"dtype":"F32","shape":[2],"data_offsets":[0,8]}"#);
+        let loaded = load_tensors(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap().0[0].1.size(), vec![2]);
+    }
+}
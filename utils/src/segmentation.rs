@@ -0,0 +1,340 @@
+use anyhow::{bail, Context};
+use clap::ArgEnum;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tch::{nn::ConvConfig, Kind, Reduction, Tensor};
+use thiserror::Error;
+
+/// Label of the pixels ignored by the losses and the metrics
+pub const IGNORE_INDEX: i64 = -100;
+
+#[derive(Debug, Error)]
+enum LabelError {
+    #[error("Invalid class on line {0} : expected `<name> <r> <g> <b>`")]
+    InvalidClass(usize),
+    #[error("No class found in {0:?}")]
+    Empty(PathBuf),
+}
+
+/// Name and color of a segmentation class
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassInfo {
+    pub name: String,
+    pub color: [u8; 3],
+}
+
+/// Classes of a segmentation model, the index of a class is its label.
+///
+/// The classes are stored next to the weights of the model (see [`classes_path`]) in a text file
+/// with one `<name> <r> <g> <b>` class per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelMap {
+    pub classes: Vec<ClassInfo>,
+}
+
+impl LabelMap {
+    pub fn new(classes: Vec<ClassInfo>) -> Self {
+        assert!(!classes.is_empty(), "Expected at least one class");
+        Self { classes }
+    }
+
+    /// Single white `foreground` class of the binary segmentation
+    pub fn binary() -> Self {
+        Self::new(vec![ClassInfo {
+            name: "foreground".to_string(),
+            color: [255, 255, 255],
+        }])
+    }
+
+    /// Reads the classes from a file with one `<name> <r> <g> <b>` class per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path).with_context(|| format!("Couldnt read {path:?}"))?;
+        let mut classes = vec![];
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<_> = line.split_whitespace().collect();
+            let color: Vec<_> = parts
+                .iter()
+                .skip(1)
+                .filter_map(|c| c.parse().ok())
+                .collect();
+            match (parts.first(), color.as_slice()) {
+                (Some(name), [r, g, b]) if parts.len() == 4 => classes.push(ClassInfo {
+                    name: name.to_string(),
+                    color: [*r, *g, *b],
+                }),
+                _ => bail!(LabelError::InvalidClass(i + 1)),
+            }
+        }
+        if classes.is_empty() {
+            bail!(LabelError::Empty(path.to_path_buf()));
+        }
+        Ok(Self::new(classes))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let content: String = self
+            .classes
+            .iter()
+            .map(|class| {
+                let [r, g, b] = class.color;
+                format!("{} {r} {g} {b}\n", class.name)
+            })
+            .collect();
+        fs::write(path, content).with_context(|| format!("Couldnt write {path:?}"))
+    }
+
+    pub fn len(&self) -> usize {
+        self.classes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+
+    fn colors(&self) -> Tensor {
+        let colors: Vec<_> = self.classes.iter().flat_map(|class| class.color).collect();
+        Tensor::of_slice(&colors).view([-1, 3])
+    }
+
+    /// Converts a `[3,H,W]` color mask into a `[H,W]` label mask,
+    /// the pixels whose color is not the one of a class are labeled [`IGNORE_INDEX`]
+    pub fn decode_palette(&self, mask: &Tensor) -> Tensor {
+        assert!(
+            mask.dim() == 3 && mask.size()[0] == 3,
+            "Expected [3,H,W] shaped tensor got {:?} instead",
+            mask.size()
+        );
+        let colors = self.colors().to_device(mask.device()).view([-1, 3, 1, 1]);
+        // [K,H,W] true where the pixel has the color of the class
+        let matches = mask
+            .to_kind(Kind::Uint8)
+            .unsqueeze(0)
+            .eq_tensor(&colors)
+            .all_dim(1, false);
+        let found = matches.any_dim(0, false);
+        matches
+            .to_kind(Kind::Int64)
+            .argmax(0, false)
+            .where_scalarother(&found, IGNORE_INDEX)
+    }
+
+    /// Converts a `[C,H,W]`/`[H,W]` mask whose pixels (of the first channel) are the labels into a `[H,W]` label mask,
+    /// the values that are not a label are replaced by [`IGNORE_INDEX`]
+    pub fn decode_indices(&self, mask: &Tensor) -> Tensor {
+        let mask = if mask.dim() == 3 {
+            mask.select(0, 0)
+        } else {
+            mask.shallow_clone()
+        };
+        let mask = mask.to_kind(Kind::Int64);
+        let valid = mask.lt(self.len() as i64);
+        mask.where_scalarother(&valid, IGNORE_INDEX)
+    }
+
+    /// Converts a `[H,W]` label mask into a `[3,H,W]` color mask, the ignored pixels are black
+    pub fn colorize(&self, labels: &Tensor) -> Tensor {
+        let colors = self.colors().to_device(labels.device());
+        let valid = labels.ge(0).logical_and(&labels.lt(self.len() as i64));
+        let labels = labels.where_scalarother(&valid, 0);
+        colors
+            .index_select(0, &labels.flatten(0, -1))
+            .view([labels.size()[0], labels.size()[1], 3])
+            .permute(&[2, 0, 1])
+            * valid.unsqueeze(0)
+    }
+
+    /// Converts `[K,H,W]` binary masks into a `[3,H,W]` color mask, overlapping classes are merged with a maximum
+    pub fn colorize_masks(&self, masks: &Tensor) -> Tensor {
+        let colors = self.colors().to_device(masks.device()).view([-1, 3, 1, 1]);
+        (colors * masks.to_kind(Kind::Uint8).unsqueeze(1)).amax(&[0], false)
+    }
+}
+
+/// Encoding of the classes in the mask files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum MaskFormat {
+    /// The color of a pixel is the color of its class
+    #[default]
+    Palette,
+    /// The value of a pixel is the index of its class
+    Index,
+    /// Each channel is the binary mask of a class
+    Channels,
+}
+
+impl MaskFormat {
+    /// Decodes a `[C,H,W]` mask into a `[H,W]` label mask or a `[K,H,W]` binary mask (channels)
+    pub fn decode(&self, labels: &LabelMap, mask: &Tensor) -> Tensor {
+        match self {
+            MaskFormat::Palette => labels.decode_palette(mask),
+            MaskFormat::Index => labels.decode_indices(mask),
+            MaskFormat::Channels => mask.narrow(0, 0, labels.len() as i64).gt(127),
+        }
+    }
+
+    /// Format used by default in a segmentation mode
+    pub fn default_for(mode: SegmentationMode) -> Self {
+        match mode {
+            SegmentationMode::MultiClass => MaskFormat::Palette,
+            SegmentationMode::MultiLabel => MaskFormat::Channels,
+        }
+    }
+}
+
+/// Padding of the convolutions of the segmentation models, selectable from the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum PaddingParam {
+    /// Padded convolutions, the predicted mask has the resolution of the image
+    Same,
+    /// Unpadded convolutions, the mask is cropped to the resolution of the prediction
+    Valid,
+}
+
+impl PaddingParam {
+    pub fn is_same(&self) -> bool {
+        *self == PaddingParam::Same
+    }
+
+    /// Config of the 3x3 convolutions of the encoders
+    pub fn conv_config(&self) -> ConvConfig {
+        ConvConfig {
+            padding: if self.is_same() { 1 } else { 0 },
+            ..Default::default()
+        }
+    }
+}
+
+/// Path of the classes stored next to the weights of a model
+pub fn classes_path(weights: &Path) -> PathBuf {
+    weights.with_extension("classes")
+}
+
+/// Activation and loss of the segmentation models
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum SegmentationMode {
+    /// One class per pixel : softmax over the classes and cross-entropy.
+    /// The targets are `[B,H,W]` label masks.
+    #[default]
+    MultiClass,
+    /// Any number of classes per pixel (binary segmentation with a single class) : independent sigmoids
+    /// and binary cross-entropy. The targets are `[B,K,H,W]` binary masks.
+    MultiLabel,
+}
+
+impl SegmentationMode {
+    /// Loss of the `[B,K,H,W]` logits of a model
+    pub fn loss(&self, logits: &Tensor, target: &Tensor) -> Tensor {
+        match self {
+            SegmentationMode::MultiClass => logits.cross_entropy_loss::<Tensor>(
+                target,
+                None,
+                Reduction::Mean,
+                IGNORE_INDEX,
+                0.0,
+            ),
+            SegmentationMode::MultiLabel => logits.binary_cross_entropy_with_logits::<Tensor>(
+                &target.to_kind(Kind::Float),
+                None,
+                None,
+                Reduction::Mean,
+            ),
+        }
+    }
+
+    /// Probabilities of the classes of the `[B,K,H,W]` logits of a model
+    pub fn probabilities(&self, logits: &Tensor) -> Tensor {
+        match self {
+            SegmentationMode::MultiClass => logits.softmax(-3, Kind::Float),
+            SegmentationMode::MultiLabel => logits.sigmoid(),
+        }
+    }
+
+    /// Binary `[B,K,H,W]` masks of the predictions and of the target, the ignored pixels are false in both
+    pub fn masks(&self, logits: &Tensor, target: &Tensor) -> (Tensor, Tensor) {
+        match self {
+            SegmentationMode::MultiClass => {
+                let classes = logits.size()[1];
+                let valid = target.ge(0).unsqueeze(1);
+                let one_hot = |labels: &Tensor| {
+                    labels
+                        .clamp_min(0)
+                        .one_hot(classes)
+                        .permute(&[0, 3, 1, 2])
+                        .to_kind(Kind::Bool)
+                        .logical_and(&valid)
+                };
+                (one_hot(&logits.argmax(1, false)), one_hot(target))
+            }
+            SegmentationMode::MultiLabel => (logits.gt(0.0), target.to_kind(Kind::Bool)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{Kind, Tensor};
+
+    use super::{ClassInfo, LabelMap, SegmentationMode, IGNORE_INDEX};
+
+    fn label_map() -> LabelMap {
+        LabelMap::new(vec![
+            ClassInfo {
+                name: "background".to_string(),
+                color: [0, 0, 0],
+            },
+            ClassInfo {
+                name: "cell".to_string(),
+                color: [255, 0, 0],
+            },
+        ])
+    }
+
+    #[test]
+    fn palette() {
+        let labels = label_map();
+        let mask = Tensor::of_slice(&[0_u8, 255, 7, 0, 0, 0, 0, 0, 0]).view([3, 1, 3]);
+        let decoded = labels.decode_palette(&mask);
+        assert_eq!(
+            Vec::<i64>::from(&decoded.flatten(0, -1)),
+            [0, 1, IGNORE_INDEX]
+        );
+        assert!(labels
+            .colorize(&decoded)
+            .equal(&Tensor::of_slice(&[0_u8, 255, 0, 0, 0, 0, 0, 0, 0]).view([3, 1, 3])));
+
+        let mask = Tensor::of_slice(&[1_u8, 0, 4]).view([1, 1, 3]);
+        let decoded = labels.decode_indices(&mask);
+        assert_eq!(
+            Vec::<i64>::from(&decoded.flatten(0, -1)),
+            [1, 0, IGNORE_INDEX]
+        );
+    }
+
+    #[test]
+    fn modes() {
+        let logits = Tensor::of_slice(&[2.0_f32, -1.0, -2.0, 1.0]).view([1, 2, 1, 2]);
+
+        let target = Tensor::of_slice(&[0_i64, IGNORE_INDEX]).view([1, 1, 2]);
+        let loss = SegmentationMode::MultiClass.loss(&logits, &target);
+        assert!(f64::from(&loss) > 0.0);
+        let (predicted, target) = SegmentationMode::MultiClass.masks(&logits, &target);
+        assert_eq!(
+            Vec::<bool>::from(&predicted.flatten(0, -1)),
+            [true, false, false, false]
+        );
+        assert!(predicted.equal(&target));
+
+        let target = Tensor::of_slice(&[1.0_f32, 0.0, 0.0, 1.0]).view([1, 2, 1, 2]);
+        let (predicted, target) = SegmentationMode::MultiLabel.masks(&logits, &target);
+        assert!(predicted.equal(&target));
+        let probabilities = SegmentationMode::MultiLabel.probabilities(&logits);
+        assert_eq!(probabilities.kind(), Kind::Float);
+    }
+}
@@ -0,0 +1,358 @@
+use std::{cell::RefCell, fmt::Display};
+use tch::{
+    nn::{ModuleT, Path, VarStore},
+    Kind, Tensor,
+};
+
+/// Name, output shape, kind and feature axis of the traced modules
+type Trace = Vec<(String, Vec<i64>, Kind, usize)>;
+
+thread_local! {
+    static TRACE: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+/// Name of a module in the summary : the `.` separated path of its variables
+pub fn module_name(vs: &Path) -> String {
+    vs.components().collect::<Vec<_>>().join(".")
+}
+
+/// Records the output of a module while a [`Summary`] runs its sample forward, does nothing otherwise.
+/// The output is expected to be `[N, C, ...]` shaped (or `[N, C]`), see [`trace_tokens`] for `[N, T, D]` ones.
+pub fn trace(name: &str, xs: &Tensor) {
+    record(name, xs, 1)
+}
+
+/// Same as [`trace`] for the `[N, T, D]` tokens of the transformers, the features being on the last axis
+pub fn trace_tokens(name: &str, xs: &Tensor) {
+    record(name, xs, xs.dim().saturating_sub(1))
+}
+
+fn record(name: &str, xs: &Tensor, feature_axis: usize) {
+    TRACE.with(|trace| {
+        if let Some(trace) = trace.borrow_mut().as_mut() {
+            trace.push((name.to_string(), xs.size(), xs.kind(), feature_axis));
+        }
+    })
+}
+
+fn has_prefix(name: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || name == prefix
+        || (name.starts_with(prefix) && name[prefix.len()..].starts_with('.'))
+}
+
+/// Row of a [`Summary`] : a traced module or the variables of an untraced layer
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerSummary {
+    pub name: String,
+    /// Output shape of the module, `None` if it was not traced
+    pub output: Option<Vec<i64>>,
+    pub params: i64,
+    pub trainable_params: i64,
+    /// Estimated multiply-adds (x2) of the convolutions and linear layers for the sample input
+    pub flops: i64,
+    /// Size in bytes of the output
+    pub memory: i64,
+}
+
+/// Table of the layers of a model with their parameters, output shapes and estimated cost.
+///
+/// The output shapes come from the modules calling [`trace`] during a sample forward,
+/// the variables of the store are attributed to the traced module with the longest matching path.
+/// ```ignore
+/// let summary = Summary::new(&vs, &unet, &Tensor::zeros(&[1, 3, 256, 256], FLOAT_CPU));
+/// print!("{summary}");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub layers: Vec<LayerSummary>,
+    pub output: Option<Vec<i64>>,
+    /// Size in bytes of the parameters
+    pub params_memory: i64,
+}
+
+impl Summary {
+    /// Summary of the model with the outputs of a forward of `input` (in evaluation mode)
+    pub fn new<M: ModuleT>(vs: &VarStore, model: &M, input: &Tensor) -> Self {
+        TRACE.with(|trace| *trace.borrow_mut() = Some(vec![]));
+        let output = tch::no_grad(|| model.forward_t(input, false));
+        let traces = TRACE.with(|trace| trace.borrow_mut().take().unwrap_or_default());
+        let mut summary = Self::build(&named_variables(vs), traces);
+        summary.output = Some(output.size());
+        summary
+    }
+
+    /// Summary of the variables of a store without output shapes nor cost
+    pub fn of_var_store(vs: &VarStore) -> Self {
+        Self::build(&named_variables(vs), vec![])
+    }
+
+    /// Summary of the tensors of a checkpoint (e.g. loaded with [`Tensor::load_multi`]),
+    /// the tensors requiring a gradient are counted as trainable
+    pub fn of_tensors(tensors: &[(String, Tensor)]) -> Self {
+        let mut tensors: Vec<_> = tensors
+            .iter()
+            .map(|(name, tensor)| (name.clone(), tensor.shallow_clone()))
+            .collect();
+        tensors.sort_by(|a, b| a.0.cmp(&b.0));
+        Self::build(&tensors, vec![])
+    }
+
+    fn build(variables: &[(String, Tensor)], traces: Trace) -> Self {
+        // A module called several times is reported once, `feature_axes[i]` is the feature axis of the traced layer `i`
+        let mut layers: Vec<LayerSummary> = vec![];
+        let mut feature_axes = vec![];
+        for (name, output, kind, feature_axis) in traces {
+            if layers.iter().any(|layer| layer.name == name) {
+                continue;
+            }
+            feature_axes.push(feature_axis);
+            let memory = output.iter().product::<i64>() * kind.elt_size_in_bytes() as i64;
+            layers.push(LayerSummary {
+                name,
+                output: Some(output),
+                params: 0,
+                trainable_params: 0,
+                flops: 0,
+                memory,
+            });
+        }
+
+        let mut params_memory = 0;
+        for (name, var) in variables {
+            let numel = var.numel() as i64;
+            params_memory += numel * var.kind().elt_size_in_bytes() as i64;
+            let owner = layers
+                .iter()
+                .enumerate()
+                .filter(|(_, layer)| layer.output.is_some() && has_prefix(name, &layer.name))
+                .max_by_key(|(_, layer)| layer.name.len())
+                .map(|(i, _)| i);
+            let i = match owner {
+                Some(i) => i,
+                None => {
+                    // Untraced variables are grouped by layer
+                    let layer = name.rsplit_once('.').map_or("", |(layer, _)| layer);
+                    match layers
+                        .iter()
+                        .position(|l| l.output.is_none() && l.name == layer)
+                    {
+                        Some(i) => i,
+                        None => {
+                            layers.push(LayerSummary {
+                                name: layer.to_string(),
+                                output: None,
+                                params: 0,
+                                trainable_params: 0,
+                                flops: 0,
+                                memory: 0,
+                            });
+                            layers.len() - 1
+                        }
+                    }
+                }
+            };
+            let layer = &mut layers[i];
+            layer.params += numel;
+            if var.requires_grad() {
+                layer.trainable_params += numel;
+            }
+            // Each weight of a convolution or of a linear layer is used once per output position (and sample)
+            if let Some(output) = &layer.output {
+                if name.ends_with("weight") && var.dim() >= 2 {
+                    let positions: i64 = output
+                        .iter()
+                        .enumerate()
+                        .filter(|(axis, _)| *axis != feature_axes[i])
+                        .map(|(_, size)| size)
+                        .product();
+                    layer.flops += 2 * numel * positions;
+                }
+            }
+        }
+
+        Self {
+            layers,
+            output: None,
+            params_memory,
+        }
+    }
+
+    pub fn params(&self) -> i64 {
+        self.layers.iter().map(|layer| layer.params).sum()
+    }
+
+    pub fn trainable_params(&self) -> i64 {
+        self.layers.iter().map(|layer| layer.trainable_params).sum()
+    }
+
+    pub fn flops(&self) -> i64 {
+        self.layers.iter().map(|layer| layer.flops).sum()
+    }
+
+    /// Size in bytes of the outputs of the traced modules
+    pub fn activations_memory(&self) -> i64 {
+        self.layers.iter().map(|layer| layer.memory).sum()
+    }
+}
+
+fn named_variables(vs: &VarStore) -> Vec<(String, Tensor)> {
+    let mut variables: Vec<_> = vs.variables().into_iter().collect();
+    variables.sort_by(|a, b| a.0.cmp(&b.0));
+    variables
+}
+
+/// Human readable count (e.g. `1.25M`)
+fn human(value: i64) -> String {
+    let value = value as f64;
+    match value {
+        v if v >= 1e9 => format!("{:.2}G", v / 1e9),
+        v if v >= 1e6 => format!("{:.2}M", v / 1e6),
+        v if v >= 1e3 => format!("{:.2}K", v / 1e3),
+        v => format!("{v}"),
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = self
+            .layers
+            .iter()
+            .map(|layer| layer.name.len())
+            .max()
+            .unwrap_or(0)
+            .max(6);
+        writeln!(
+            f,
+            "{:width$}  {:>20}  {:>10}  {:>9}  {:>10}  {:>10}",
+            "module", "output", "params", "trainable", "flops", "memory"
+        )?;
+        for layer in self.layers.iter() {
+            let output = match &layer.output {
+                Some(output) => format!("{output:?}"),
+                None => "-".to_string(),
+            };
+            let trainable = match (layer.params, layer.trainable_params) {
+                (0, _) => "-",
+                (params, trainable) if params == trainable => "yes",
+                (_, 0) => "no",
+                _ => "partial",
+            };
+            writeln!(
+                f,
+                "{:width$}  {:>20}  {:>10}  {:>9}  {:>10}  {:>10}",
+                layer.name,
+                output,
+                human(layer.params),
+                trainable,
+                human(layer.flops),
+                human(layer.memory) + "B"
+            )?;
+        }
+        if let Some(output) = &self.output {
+            writeln!(f, "output shape         : {output:?}")?;
+        }
+        writeln!(
+            f,
+            "parameters           : {} ({} trainable)",
+            human(self.params()),
+            human(self.trainable_params())
+        )?;
+        writeln!(f, "parameters memory    : {}B", human(self.params_memory))?;
+        writeln!(
+            f,
+            "activations memory   : {}B",
+            human(self.activations_memory())
+        )?;
+        writeln!(f, "estimated flops      : {}", human(self.flops()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{self, Module, ModuleT, VarStore},
+        Device, Kind, Tensor,
+    };
+
+    use super::{module_name, trace, trace_tokens, Summary};
+
+    #[derive(Debug)]
+    struct Model {
+        hidden: nn::Linear,
+        output: nn::Linear,
+        names: [String; 2],
+    }
+
+    impl ModuleT for Model {
+        fn forward_t(&self, xs: &Tensor, _train: bool) -> Tensor {
+            let xs = self.hidden.forward(xs).relu();
+            trace(&self.names[0], &xs);
+            let ys = self.output.forward(&xs);
+            trace(&self.names[1], &ys);
+            ys
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let vs = VarStore::new(Device::Cpu);
+        let root = vs.root();
+        let model = &root / "model";
+        let hidden = &model / "hidden";
+        let output = &model / "output";
+        let model = Model {
+            hidden: nn::linear(&hidden, 8, 4, Default::default()),
+            output: nn::linear(&output, 4, 2, Default::default()),
+            names: [module_name(&hidden), module_name(&output)],
+        };
+        let _ = nn::linear(&root / "unused", 3, 3, Default::default());
+
+        let x = Tensor::zeros(&[5, 8], (Kind::Float, Device::Cpu));
+        let summary = Summary::new(&vs, &model, &x);
+        assert_eq!(summary.layers.len(), 3);
+        assert_eq!(summary.layers[0].name, "model.hidden");
+        assert_eq!(summary.layers[0].output, Some(vec![5, 4]));
+        assert_eq!(summary.layers[0].params, 8 * 4 + 4);
+        assert_eq!(summary.layers[0].flops, 2 * 8 * 4 * 5);
+        assert_eq!(summary.layers[2].name, "unused");
+        assert_eq!(summary.layers[2].output, None);
+        assert_eq!(summary.params(), 36 + 10 + 12);
+        assert_eq!(summary.output, Some(vec![5, 2]));
+        assert!(summary.to_string().contains("model.output"));
+
+        // Tracing is only enabled during the summary
+        trace("outside", &x);
+        assert_eq!(Summary::of_var_store(&vs).layers.len(), 3);
+    }
+
+    #[derive(Debug)]
+    struct Tokens {
+        fc: nn::Linear,
+        name: String,
+    }
+
+    impl ModuleT for Tokens {
+        fn forward_t(&self, xs: &Tensor, _train: bool) -> Tensor {
+            let ys = self.fc.forward(xs);
+            trace_tokens(&self.name, &ys);
+            ys
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        let vs = VarStore::new(Device::Cpu);
+        let fc = &vs.root() / "fc";
+        let model = Tokens {
+            fc: nn::linear(&fc, 8, 6, Default::default()),
+            name: module_name(&fc),
+        };
+
+        // The weights are used once per token : 2 samples of 5 tokens
+        let x = Tensor::zeros(&[2, 5, 8], (Kind::Float, Device::Cpu));
+        let summary = Summary::new(&vs, &model, &x);
+        assert_eq!(summary.layers[0].output, Some(vec![2, 5, 6]));
+        assert_eq!(summary.flops(), 2 * 8 * 6 * 2 * 5);
+    }
+}
@@ -0,0 +1,190 @@
+use tch::Tensor;
+
+/// Crops the last `size.len()` dimensions of `xs` around their center
+pub fn center_crop(xs: &Tensor, size: &[i64]) -> Tensor {
+    let xs_size = xs.size();
+    assert!(
+        xs_size.len() >= size.len(),
+        "Cannot crop {} dimentions of a {:?} shaped tensor",
+        size.len(),
+        xs_size
+    );
+    let offset = xs_size.len() - size.len();
+    size.iter()
+        .enumerate()
+        .fold(xs.shallow_clone(), |xs, (i, target)| {
+            let dim = offset + i;
+            let delta = xs_size[dim] - target;
+            assert!(
+                delta >= 0,
+                "Cannot crop a {:?} shaped tensor to {:?}",
+                xs_size,
+                size
+            );
+            xs.narrow(dim as i64, delta / 2, *target)
+        })
+}
+
+/// Crops the last `nd` dimensions of `xs` to the ones of `like`
+pub fn center_crop_like(xs: &Tensor, like: &Tensor, nd: usize) -> Tensor {
+    let like_size = like.size();
+    center_crop(xs, &like_size[like_size.len() - nd..])
+}
+
+/// Zero-pads the last `nd` dimensions of `xs` so that they are divisible by `multiple`.
+/// The padding is split on both sides so that [`center_crop`] restores the original tensor.
+pub fn pad_to_multiple(xs: &Tensor, multiple: i64, nd: usize) -> Tensor {
+    let xs_size = xs.size();
+    // constant_pad_nd expects the paddings starting from the last dimention
+    let padding: Vec<i64> = xs_size[xs_size.len() - nd..]
+        .iter()
+        .rev()
+        .flat_map(|size| {
+            let delta = (multiple - size % multiple) % multiple;
+            [delta / 2, delta - delta / 2]
+        })
+        .collect();
+    if padding.iter().all(|p| *p == 0) {
+        xs.shallow_clone()
+    } else {
+        xs.constant_pad_nd(&padding)
+    }
+}
+
+/// Extracts the patch of size `size` starting at `start` from the last `size.len()` dimensions of `xs`
+pub fn extract_patch(xs: &Tensor, start: &[i64], size: &[i64]) -> Tensor {
+    assert!(
+        start.len() == size.len(),
+        "Expected as many starts as sizes got {:?} and {:?}",
+        start,
+        size
+    );
+    let offset = xs.dim() - size.len();
+    start
+        .iter()
+        .zip(size.iter())
+        .enumerate()
+        .fold(xs.shallow_clone(), |xs, (i, (start, size))| {
+            xs.narrow((offset + i) as i64, *start, *size)
+        })
+}
+
+/// Starts of the patches of size `patch` every `stride` along a dimension of size `size`.
+/// The last patch is moved back so that the whole dimension is covered.
+pub fn patch_starts(size: i64, patch: i64, stride: i64) -> Vec<i64> {
+    assert!(
+        patch <= size,
+        "Cannot extract patches of {patch} from a dimention of {size}"
+    );
+    assert!(stride > 0, "The stride should be above 0");
+    let mut starts: Vec<_> = (0..=size - patch).step_by(stride as usize).collect();
+    if starts.last() != Some(&(size - patch)) {
+        starts.push(size - patch);
+    }
+    starts
+}
+
+/// Resizes the last two dimensions of a `[C,H,W]` or `[B,C,H,W]` tensor to `size` with a bilinear (or nearest) interpolation
+pub fn interpolate2d(xs: &Tensor, size: &[i64], bilinear: bool) -> Tensor {
+    assert!(
+        xs.dim() == 3 || xs.dim() == 4,
+        "Expected [C,H,W]/[B,C,H,W] shaped tensor got {:?} instead",
+        xs.size()
+    );
+    let batched = if xs.dim() == 3 {
+        xs.unsqueeze(0)
+    } else {
+        xs.shallow_clone()
+    };
+    let ys = if bilinear {
+        batched.upsample_bilinear2d(size, false, None, None)
+    } else {
+        batched.upsample_nearest2d(size, None, None)
+    };
+    if xs.dim() == 3 {
+        ys.squeeze_dim(0)
+    } else {
+        ys
+    }
+}
+
+/// Unflattens `[N, C*H*W]` (or `[N, H, W]`) inputs, e.g. the MNIST images of tch, into `[N, C, H, W]` images
+pub fn images(xs: &Tensor, channels: i64, size: i64) -> Tensor {
+    match xs.dim() {
+        2 | 3 => xs.reshape(&[-1, channels, size, size]),
+        _ => xs.shallow_clone(),
+    }
+}
+
+/// Tiles `[N,C,H,W]` images into a `[C,H',W']` grid of `columns` columns separated by `padding` zero pixels
+pub fn image_grid(images: &Tensor, columns: i64, padding: i64) -> Tensor {
+    let size = images.size();
+    assert!(
+        size.len() == 4,
+        "Expected [N,C,H,W] shaped tensor got {:?} instead",
+        size
+    );
+    let (n, c, h, w) = (size[0], size[1], size[2], size[3]);
+    let columns = columns.min(n).max(1);
+    let rows = (n + columns - 1) / columns;
+
+    // Filling the last row with blank images and padding each image on the bottom and the right
+    let blank = Tensor::zeros(
+        &[rows * columns - n, c, h, w],
+        (images.kind(), images.device()),
+    );
+    let tiles =
+        Tensor::cat(&[images.shallow_clone(), blank], 0).constant_pad_nd(&[0, padding, 0, padding]);
+    let (h, w) = (h + padding, w + padding);
+    tiles
+        .view([rows, columns, c, h, w])
+        .permute(&[2, 0, 3, 1, 4])
+        .contiguous()
+        .view([c, rows * h, columns * w])
+        .constant_pad_nd(&[padding, 0, padding, 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{Device, Kind, Tensor};
+
+    use super::{center_crop, extract_patch, image_grid, images, pad_to_multiple, patch_starts};
+
+    #[test]
+    fn pad_and_crop() {
+        let x = Tensor::rand(&[2, 3, 37, 64], (Kind::Float, Device::Cpu));
+        let padded = pad_to_multiple(&x, 16, 2);
+        assert_eq!(padded.size(), vec![2, 3, 48, 64]);
+
+        let cropped = center_crop(&padded, &[37, 64]);
+        assert_eq!(cropped.size(), x.size());
+        assert!(cropped.equal(&x));
+    }
+
+    #[test]
+    fn volumes() {
+        let x = Tensor::rand(&[1, 2, 11, 20, 9], (Kind::Float, Device::Cpu));
+        let padded = pad_to_multiple(&x, 8, 3);
+        assert_eq!(padded.size(), vec![1, 2, 16, 24, 16]);
+        assert!(center_crop(&padded, &[11, 20, 9]).equal(&x));
+
+        assert_eq!(patch_starts(20, 8, 6), vec![0, 6, 12]);
+        assert_eq!(patch_starts(8, 8, 4), vec![0]);
+        let patch = extract_patch(&x, &[3, 12, 1], &[8, 8, 8]);
+        assert_eq!(patch.size(), vec![1, 2, 8, 8, 8]);
+        assert!(patch.equal(&x.narrow(2, 3, 8).narrow(3, 12, 8).narrow(4, 1, 8)));
+    }
+
+    #[test]
+    fn grid() {
+        let x = Tensor::ones(&[5, 3, 4, 6], (Kind::Float, Device::Cpu));
+        let grid = image_grid(&x, 2, 1);
+        assert_eq!(grid.size(), vec![3, 3 * 5 + 1, 2 * 7 + 1]);
+        // 5 images of 4x6 pixels, the sixth tile is blank
+        assert_eq!(f64::from(grid.sum(Kind::Float)), (5 * 3 * 4 * 6) as f64);
+
+        let flat = Tensor::rand(&[2, 784], (Kind::Float, Device::Cpu));
+        assert_eq!(images(&flat, 1, 28).size(), vec![2, 1, 28, 28]);
+        assert!(images(&x, 3, 4).equal(&x));
+    }
+}
@@ -0,0 +1,202 @@
+use clap::ArgEnum;
+use tch::{nn::ModuleT, Kind, Tensor};
+
+/// Transformation of the last two dimensions of the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    Identity,
+    HorizontalFlip,
+    VerticalFlip,
+    Rot90,
+    Rot180,
+    Rot270,
+}
+
+impl Transform {
+    /// Identity and the two flips
+    pub fn flips() -> Vec<Transform> {
+        vec![
+            Transform::Identity,
+            Transform::HorizontalFlip,
+            Transform::VerticalFlip,
+        ]
+    }
+
+    /// Flips and rotations
+    pub fn all() -> Vec<Transform> {
+        vec![
+            Transform::Identity,
+            Transform::HorizontalFlip,
+            Transform::VerticalFlip,
+            Transform::Rot90,
+            Transform::Rot180,
+            Transform::Rot270,
+        ]
+    }
+
+    pub fn apply(&self, xs: &Tensor) -> Tensor {
+        match self {
+            Transform::Identity => xs.shallow_clone(),
+            Transform::HorizontalFlip => xs.flip(&[-1]),
+            Transform::VerticalFlip => xs.flip(&[-2]),
+            Transform::Rot90 => xs.rot90(1, &[-2, -1]),
+            Transform::Rot180 => xs.rot90(2, &[-2, -1]),
+            Transform::Rot270 => xs.rot90(3, &[-2, -1]),
+        }
+    }
+
+    /// Reverts [`Transform::apply`]
+    pub fn invert(&self, xs: &Tensor) -> Tensor {
+        match self {
+            Transform::Rot90 => xs.rot90(-1, &[-2, -1]),
+            Transform::Rot270 => xs.rot90(-3, &[-2, -1]),
+            // The flips and the half turn are their own inverse
+            _ => self.apply(xs),
+        }
+    }
+}
+
+/// Sets of transforms selectable from the command line of the binaries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum TtaParam {
+    None,
+    /// Horizontal and vertical flips
+    Flips,
+    /// Flips and rotations
+    All,
+}
+
+impl TtaParam {
+    pub fn transforms(&self) -> Vec<Transform> {
+        match self {
+            TtaParam::None => vec![Transform::Identity],
+            TtaParam::Flips => Transform::flips(),
+            TtaParam::All => Transform::all(),
+        }
+    }
+}
+
+/// Reduction of the outputs of the transformed inputs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Merge {
+    #[default]
+    Mean,
+    Max,
+}
+
+/// Test-time augmentation : runs the model over transformed copies of the input and merges the outputs.
+///
+/// For segmentation models the transforms are reverted on the outputs before merging them,
+/// for classification models the outputs (e.g. logits) are merged as is.
+/// ```ignore
+/// let tta = TestTimeAugmentation::segmentation(unet).transforms(Transform::all());
+/// let mask = tta.forward_t(&image, false);
+/// ```
+#[derive(Debug)]
+pub struct TestTimeAugmentation<M> {
+    model: M,
+    transforms: Vec<Transform>,
+    merge: Merge,
+    spatial: bool,
+}
+
+impl<M: ModuleT> TestTimeAugmentation<M> {
+    /// Wraps a model whose output is aligned with its input (e.g. masks)
+    pub fn segmentation(model: M) -> Self {
+        Self {
+            model,
+            transforms: Transform::flips(),
+            merge: Merge::Mean,
+            spatial: true,
+        }
+    }
+
+    /// Wraps a model whose output doesn't depend on the orientation of the input (e.g. logits)
+    pub fn classification(model: M) -> Self {
+        Self {
+            spatial: false,
+            ..Self::segmentation(model)
+        }
+    }
+
+    pub fn transforms(mut self, transforms: Vec<Transform>) -> Self {
+        assert!(!transforms.is_empty(), "Expected at least one transform");
+        self.transforms = transforms;
+        self
+    }
+
+    pub fn merge(mut self, merge: Merge) -> Self {
+        self.merge = merge;
+        self
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+}
+
+impl<M: ModuleT> ModuleT for TestTimeAugmentation<M> {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let outputs: Vec<_> = self
+            .transforms
+            .iter()
+            .map(|transform| {
+                let ys = self.model.forward_t(&transform.apply(xs), train);
+                if self.spatial {
+                    transform.invert(&ys)
+                } else {
+                    ys
+                }
+            })
+            .collect();
+        let outputs = Tensor::stack(&outputs, 0);
+        match self.merge {
+            Merge::Mean => outputs.mean_dim(&[0], false, Kind::Float),
+            Merge::Max => outputs.amax(&[0], false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{self, ModuleT},
+        Device, Kind, Tensor,
+    };
+
+    use super::{Merge, TestTimeAugmentation, Transform};
+
+    #[test]
+    fn transforms() {
+        let x = Tensor::rand(&[2, 3, 5, 7], (Kind::Float, Device::Cpu));
+        for transform in Transform::all() {
+            assert!(transform.invert(&transform.apply(&x)).equal(&x));
+        }
+    }
+
+    #[test]
+    fn merging() {
+        let x = Tensor::rand(&[2, 1, 6, 6], (Kind::Float, Device::Cpu));
+
+        // A pixel-wise model is equivariant so the merged output is the output of the model
+        let segmentation = TestTimeAugmentation::segmentation(nn::func_t(|xs, _| xs * 2.0))
+            .transforms(Transform::all())
+            .merge(Merge::Max);
+        assert!(segmentation
+            .forward_t(&x, false)
+            .allclose(&(&x * 2.0), 1e-6, 1e-6, false));
+
+        // The top-left pixel changes with the transforms
+        let classification = TestTimeAugmentation::classification(nn::func_t(|xs, _| {
+            xs.flatten(1, -1).narrow(1, 0, 1)
+        }));
+        let y = classification.forward_t(&x, false);
+        assert_eq!(y.size(), vec![2, 1]);
+        let expected = (x.narrow(2, 0, 1).narrow(3, 0, 1)
+            + x.narrow(2, 0, 1).narrow(3, 5, 1)
+            + x.narrow(2, 5, 1).narrow(3, 0, 1))
+        .flatten(1, -1)
+            / 3.0;
+        assert!(y.allclose(&expected, 1e-6, 1e-6, false));
+    }
+}
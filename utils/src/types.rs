@@ -1,4 +1,48 @@
-pub trait FeatureExtractor<const L: usize>: tch::nn::Module {
-    const CHANELS_COUNT: [i64; L];
-    fn forward_extracts(&self, xs: &tch::Tensor) -> ([tch::Tensor; L], tch::Tensor);
+use anyhow::bail;
+use std::collections::HashMap;
+use thiserror::Error;
+
+pub trait FeatureExtractor<const L: usize>: tch::nn::ModuleT {
+    /// Number of channels of each of the extracted feature maps
+    fn chanels_count(&self) -> [i64; L];
+
+    /// Returns the feature maps of each level and the output of the last level
+    fn forward_extracts_t(&self, xs: &tch::Tensor, train: bool) -> ([tch::Tensor; L], tch::Tensor);
+
+    fn forward_extracts(&self, xs: &tch::Tensor) -> ([tch::Tensor; L], tch::Tensor) {
+        self.forward_extracts_t(xs, false)
+    }
+}
+
+/// Tensors given to or returned by a [`MultiModuleT`], indexed by their names
+pub type NamedTensors = HashMap<String, tch::Tensor>;
+
+#[derive(Debug, Error)]
+enum TypesError {
+    #[error("Missing input `{0}`")]
+    MissingInput(String),
+}
+
+/// Gets the input `name` of a [`MultiModuleT`]
+pub fn input<'a>(inputs: &'a NamedTensors, name: &str) -> anyhow::Result<&'a tch::Tensor> {
+    match inputs.get(name) {
+        Some(xs) => Ok(xs),
+        None => bail!(TypesError::MissingInput(name.to_string())),
+    }
+}
+
+/// Model with several named inputs and outputs so that the tooling (training loops, workers...)
+/// can be written once for every model
+pub trait MultiModuleT {
+    /// Names of the inputs expected by [`MultiModuleT::forward_multi_t`]
+    fn input_names(&self) -> Vec<&str>;
+
+    /// Names of the outputs returned by [`MultiModuleT::forward_multi_t`]
+    fn output_names(&self) -> Vec<&str>;
+
+    fn forward_multi_t(&self, inputs: &NamedTensors, train: bool) -> anyhow::Result<NamedTensors>;
+
+    fn forward_multi(&self, inputs: &NamedTensors) -> anyhow::Result<NamedTensors> {
+        self.forward_multi_t(inputs, false)
+    }
 }